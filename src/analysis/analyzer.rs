@@ -0,0 +1,306 @@
+use std::collections::VecDeque;
+use std::fmt;
+use serde::{Deserialize, Serialize};
+use crate::constants::common;
+use super::levels;
+
+/// Frames in the window the DC offset is averaged over, one second
+const DC_WINDOW_FRAMES: usize = 24;
+
+/// Thresholds of the audio analyzer, the `[analysis]` table of the config file
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct AnalysisSettings {
+    /// Frames with an RMS level below this are silent
+    pub silence_dbfs: f64,
+    /// Silence is reported once it lasts this long
+    pub silence_seconds: f64,
+    /// Largest DC offset, in sample values, before it is reported as a calibration problem
+    pub dc_offset_limit: f64,
+    /// Render a spectrogram of the session
+    pub spectrogram: bool,
+}
+
+impl Default for AnalysisSettings {
+    fn default() -> Self {
+        Self { silence_dbfs: -70.0, silence_seconds: 1.0, dc_offset_limit: 100.0, spectrogram: false }
+    }
+}
+
+/// Levels of one audio frame, all channels together
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct FrameAnalysis {
+    pub frame_number: u64,
+    pub peak_dbfs: f64,
+    pub rms_dbfs: f64,
+    /// Mean sample value
+    pub dc_offset: f64,
+    /// Samples at full scale
+    pub clipped_samples: usize,
+    pub silent: bool,
+}
+
+/// Changes in the audio worth a log entry
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AnalysisEvent {
+    ClippingStarted { frame_number: u64 },
+    ClippingEnded { frame_number: u64, clipped_samples: usize },
+    SilenceStarted { frame_number: u64 },
+    SilenceEnded { frame_number: u64, seconds: f64 },
+    /// The DC offset averaged over a second left the limit
+    DcOffsetHigh { frame_number: u64, dc_offset: f64 },
+    DcOffsetNormal { frame_number: u64, dc_offset: f64 },
+}
+
+impl fmt::Display for AnalysisEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::ClippingStarted { frame_number } => write!(f, "CLIPPING at frame {}", frame_number),
+            Self::ClippingEnded { frame_number, clipped_samples } =>
+                write!(f, "CLIPPING ended at frame {}, {} samples at full scale", frame_number, clipped_samples),
+            Self::SilenceStarted { frame_number } => write!(f, "SILENCE at frame {}", frame_number),
+            Self::SilenceEnded { frame_number, seconds } => write!(f, "SILENCE ended at frame {} after {:.1} s", frame_number, seconds),
+            Self::DcOffsetHigh { frame_number, dc_offset } =>
+                write!(f, "DC OFFSET of {:.0} at frame {} is over the limit, check the calibration", dc_offset, frame_number),
+            Self::DcOffsetNormal { frame_number, dc_offset } => write!(f, "DC OFFSET back to {:.0} at frame {}", dc_offset, frame_number),
+        }
+    }
+}
+
+/// Totals of a session, written next to its recordings
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct AnalysisSummary {
+    pub frames: u64,
+    pub peak_dbfs: f64,
+    pub rms_dbfs: f64,
+    pub dc_offset: f64,
+    pub clipped_samples: u64,
+    pub clipped_frames: u64,
+    pub silent_seconds: f64,
+    pub silences: u64,
+    pub dc_offset_warnings: u64,
+}
+
+/// Measures every audio frame and reports clipping, silence and DC offset
+pub struct AudioAnalyzer {
+    settings: AnalysisSettings,
+    frame_seconds: f64,
+    silence_frames: usize,
+    /// Clipped samples of the current run of clipping frames
+    clipping_run: Option<usize>,
+    dc_window: VecDeque<f64>,
+    dc_offset_high: bool,
+    summary: AnalysisSummary,
+    peak: f64,
+    sum_of_squares: f64,
+    sum: f64,
+    samples: u64,
+}
+
+impl AudioAnalyzer {
+    pub fn new(settings: &AnalysisSettings, sample_rate: u32) -> Self {
+        Self {
+            settings: settings.clone(),
+            frame_seconds: common::frame_seconds(sample_rate),
+            silence_frames: 0,
+            clipping_run: None,
+            dc_window: VecDeque::new(),
+            dc_offset_high: false,
+            summary: AnalysisSummary::default(),
+            peak: 0.0,
+            sum_of_squares: 0.0,
+            sum: 0.0,
+            samples: 0,
+        }
+    }
+
+    /// Take the frames as audio at `sample_rate` from now on, e.g. once the rate is measured
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.frame_seconds = common::frame_seconds(sample_rate);
+    }
+
+    fn silence_frames_needed(&self) -> usize {
+        ((self.settings.silence_seconds / self.frame_seconds).ceil() as usize).max(1)
+    }
+
+    /// Measure the 16 bit PCM payload of a frame
+    pub fn analyze(&mut self, frame_number: u64, payload: &[u8]) -> (FrameAnalysis, Vec<AnalysisEvent>) {
+        let mut peak = 0i32;
+        let (mut sum, mut sum_of_squares, mut count) = (0f64, 0f64, 0usize);
+        let mut clipped_samples = 0;
+        for sample in levels::samples(payload) {
+            peak = peak.max((sample as i32).abs());
+            sum += sample as f64;
+            sum_of_squares += (sample as f64).powi(2);
+            count += 1;
+            clipped_samples += (sample == i16::MAX || sample == i16::MIN) as usize;
+        }
+        let rms = (sum_of_squares / count.max(1) as f64).sqrt() / 32768.0;
+        let analysis = FrameAnalysis {
+            frame_number,
+            peak_dbfs: levels::dbfs(peak as f64 / 32768.0),
+            rms_dbfs: levels::dbfs(rms),
+            dc_offset: sum / count.max(1) as f64,
+            clipped_samples,
+            silent: levels::dbfs(rms) < self.settings.silence_dbfs,
+        };
+
+        self.peak = self.peak.max(peak as f64 / 32768.0);
+        self.sum += sum;
+        self.sum_of_squares += sum_of_squares;
+        self.samples += count as u64;
+        self.summary.frames += 1;
+        self.summary.clipped_samples += clipped_samples as u64;
+        self.summary.clipped_frames += (clipped_samples > 0) as u64;
+
+        let mut events = Vec::new();
+        self.check_clipping(&analysis, &mut events);
+        self.check_silence(&analysis, &mut events);
+        self.check_dc_offset(&analysis, &mut events);
+        (analysis, events)
+    }
+
+    fn check_clipping(&mut self, analysis: &FrameAnalysis, events: &mut Vec<AnalysisEvent>) {
+        let frame_number = analysis.frame_number;
+        match (self.clipping_run, analysis.clipped_samples) {
+            (None, 0) => {},
+            (None, clipped) => {
+                self.clipping_run = Some(clipped);
+                events.push(AnalysisEvent::ClippingStarted { frame_number });
+            },
+            (Some(clipped_samples), 0) => {
+                self.clipping_run = None;
+                events.push(AnalysisEvent::ClippingEnded { frame_number, clipped_samples });
+            },
+            (Some(run), clipped) => self.clipping_run = Some(run + clipped),
+        }
+    }
+
+    fn check_silence(&mut self, analysis: &FrameAnalysis, events: &mut Vec<AnalysisEvent>) {
+        let frame_number = analysis.frame_number;
+        let needed = self.silence_frames_needed();
+        if analysis.silent {
+            self.silence_frames += 1;
+            if self.silence_frames == needed {
+                self.summary.silences += 1;
+                events.push(AnalysisEvent::SilenceStarted { frame_number });
+            }
+        } else {
+            if self.silence_frames >= needed {
+                let seconds = self.silence_frames as f64 * self.frame_seconds;
+                self.summary.silent_seconds += seconds;
+                events.push(AnalysisEvent::SilenceEnded { frame_number, seconds });
+            }
+            self.silence_frames = 0;
+        }
+    }
+
+    fn check_dc_offset(&mut self, analysis: &FrameAnalysis, events: &mut Vec<AnalysisEvent>) {
+        self.dc_window.push_back(analysis.dc_offset);
+        if self.dc_window.len() > DC_WINDOW_FRAMES {
+            self.dc_window.pop_front();
+        }
+        if self.dc_window.len() < DC_WINDOW_FRAMES {
+            return;
+        }
+        let frame_number = analysis.frame_number;
+        let dc_offset = self.dc_window.iter().sum::<f64>() / DC_WINDOW_FRAMES as f64;
+        let high = dc_offset.abs() > self.settings.dc_offset_limit;
+        if high != self.dc_offset_high {
+            self.dc_offset_high = high;
+            if high {
+                self.summary.dc_offset_warnings += 1;
+                events.push(AnalysisEvent::DcOffsetHigh { frame_number, dc_offset });
+            } else {
+                events.push(AnalysisEvent::DcOffsetNormal { frame_number, dc_offset });
+            }
+        }
+    }
+
+    /// Forget the running state when the frame counter starts over after a device reset; the totals carry on
+    pub fn reset(&mut self) {
+        if self.silence_frames >= self.silence_frames_needed() {
+            self.summary.silent_seconds += self.silence_frames as f64 * self.frame_seconds;
+        }
+        self.silence_frames = 0;
+        self.clipping_run = None;
+        self.dc_window.clear();
+        self.dc_offset_high = false;
+    }
+
+    /// Totals so far, including a silence that is still going on
+    pub fn summary(&self) -> AnalysisSummary {
+        let mut summary = self.summary.clone();
+        let samples = self.samples.max(1) as f64;
+        summary.peak_dbfs = levels::dbfs(self.peak);
+        summary.rms_dbfs = levels::dbfs((self.sum_of_squares / samples).sqrt() / 32768.0);
+        summary.dc_offset = self.sum / samples;
+        if self.silence_frames >= self.silence_frames_needed() {
+            summary.silent_seconds += self.silence_frames as f64 * self.frame_seconds;
+        }
+        summary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_utils;
+
+    fn frame(samples: impl Fn(usize) -> i16) -> Vec<u8> {
+        (0..2000).flat_map(|i| samples(i).to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn test_audio_analyzer() {
+        let settings = AnalysisSettings { silence_seconds: 0.1, dc_offset_limit: 500.0, ..Default::default() };
+        let mut analyzer = AudioAnalyzer::new(&settings, common::SAMPLE_RATE);
+        let tone = |i: usize| ((i as f64 * 0.1).sin() * 10000.0) as i16;
+        let mut frame_number = 0;
+        let mut events = Vec::new();
+        let mut feed = |analyzer: &mut AudioAnalyzer, payload: &[u8], frames: usize| {
+            for _ in 0..frames {
+                let (analysis, new_events) = analyzer.analyze(frame_number, payload);
+                events.extend(new_events);
+                frame_number += 1;
+                assert_eq!(analysis.silent, payload.iter().all(|&byte| byte == 0));
+            }
+        };
+
+        feed(&mut analyzer, &frame(tone), 2);
+        feed(&mut analyzer, &frame(|i| if i % 100 == 0 { i16::MAX } else { tone(i) }), 2);
+        feed(&mut analyzer, &frame(|_| 0), 4);
+        feed(&mut analyzer, &frame(|i| tone(i) / 10 + 1000), 24);
+        assert_eq!(events[..4], [
+            AnalysisEvent::ClippingStarted { frame_number: 2 },
+            AnalysisEvent::ClippingEnded { frame_number: 4, clipped_samples: 40 },
+            AnalysisEvent::SilenceStarted { frame_number: 6 },
+            AnalysisEvent::SilenceEnded { frame_number: 8, seconds: 4.0 * common::frame_seconds(common::SAMPLE_RATE) },
+        ]);
+        match events[4] {
+            AnalysisEvent::DcOffsetHigh { frame_number, dc_offset } => {
+                assert!(frame_number < 31, "Reported before the window is all offset frames");
+                assert!(dc_offset > 500.0);
+            },
+            _ => panic!("Expected a DC offset event"),
+        }
+
+        let summary = analyzer.summary();
+        assert_eq!(summary.frames, 32);
+        assert_eq!(summary.clipped_frames, 2);
+        assert_eq!(summary.silences, 1);
+        assert_eq!(summary.peak_dbfs, levels::dbfs(32767.0 / 32768.0));
+
+        // The capture starts while the mic settles: a full scale spike and a decaying DC offset
+        let path = "tests/data/CoolTerm Capture (Untitled_1) 2024-12-21 12-24-34-830.txt";
+        let data = test_utils::read_file_as_bytes(path).expect("Failed to read the file");
+        let mut analyzer = AudioAnalyzer::new(&AnalysisSettings::default(), common::SAMPLE_RATE);
+        let (first, events) = analyzer.analyze(0, &data[3357..3357 + 4000]);
+        assert_eq!(first.clipped_samples, 40);
+        assert_eq!(events, [AnalysisEvent::ClippingStarted { frame_number: 0 }]);
+        assert!(first.dc_offset < -1900.0);
+        let (second, _) = analyzer.analyze(1, &data[7369..7369 + 4000]);
+        assert!(second.dc_offset > first.dc_offset && second.peak_dbfs < first.peak_dbfs);
+    }
+}
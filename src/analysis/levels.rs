@@ -0,0 +1,77 @@
+/// Level of silence in dBFS, used for an all-zero signal
+pub const SILENCE_DBFS: f64 = -120.0;
+
+/// Peak and RMS level of one channel, relative to full scale (1.0)
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ChannelLevels {
+    pub peak: f64,
+    pub rms: f64,
+}
+
+impl ChannelLevels {
+    pub fn peak_dbfs(&self) -> f64 {
+        dbfs(self.peak)
+    }
+
+    pub fn rms_dbfs(&self) -> f64 {
+        dbfs(self.rms)
+    }
+}
+
+/// Level relative to full scale in dB
+pub fn dbfs(level: f64) -> f64 {
+    if level > 0.0 {
+        20.0 * level.log10()
+    } else {
+        SILENCE_DBFS
+    }
+}
+
+/// Mean square level relative to full scale in dB
+pub fn power_dbfs(power: f64) -> f64 {
+    if power > 0.0 {
+        10.0 * power.log10()
+    } else {
+        SILENCE_DBFS
+    }
+}
+
+/// 16 bit little-endian PCM as samples
+pub fn samples(payload: &[u8]) -> impl Iterator<Item = i16> + '_ {
+    payload.chunks_exact(2).map(|sample| i16::from_le_bytes([sample[0], sample[1]]))
+}
+
+/// Peak and RMS of each channel of interleaved 16 bit PCM
+pub fn channel_levels(payload: &[u8], channels: u16) -> Vec<ChannelLevels> {
+    let channels = channels as usize;
+    let mut peaks = vec![0i32; channels];
+    let mut sums_of_squares = vec![0f64; channels];
+    let mut count = 0;
+    for (i, sample) in samples(payload).enumerate() {
+        let channel = i % channels;
+        peaks[channel] = peaks[channel].max((sample as i32).abs());
+        sums_of_squares[channel] += (sample as f64).powi(2);
+        count += (channel == 0) as usize;
+    }
+    peaks.iter().zip(sums_of_squares).map(|(&peak, sum_of_squares)| ChannelLevels {
+        peak: peak as f64 / 32768.0,
+        rms: (sum_of_squares / count.max(1) as f64).sqrt() / 32768.0,
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_channel_levels() {
+        // Left is a full scale square wave, right is silent
+        let payload: Vec<u8> = [i16::MIN, 0, i16::MAX, 0].repeat(100).iter().flat_map(|sample| sample.to_le_bytes()).collect();
+        let levels = channel_levels(&payload, 2);
+        assert_eq!(levels.len(), 2);
+        assert_eq!(levels[0].peak_dbfs(), 0.0);
+        assert!(levels[0].rms_dbfs().abs() < 0.01, "Square wave RMS equals its peak");
+        assert_eq!(levels[1], ChannelLevels::default());
+        assert_eq!(levels[1].peak_dbfs(), SILENCE_DBFS);
+    }
+}
@@ -0,0 +1,5 @@
+pub mod analyzer;
+pub mod levels;
+pub mod spectrogram;
+pub mod spectrum;
+pub mod tone_test;
@@ -0,0 +1,129 @@
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+use super::levels;
+use super::spectrum::Spectrum;
+
+/// Image rows, from 0 Hz at the bottom to half the sample rate at the top
+pub const HEIGHT: usize = 512;
+/// Widest image; beyond it neighbouring columns are merged, so a session of any length fits
+const MAX_WIDTH: usize = 4096;
+/// Levels mapped to the color scale, per bin
+const FLOOR_DBFS: f64 = -130.0;
+const CEILING_DBFS: f64 = -10.0;
+/// Color scale from quiet to loud
+const COLORS: [[u8; 3]; 5] = [[0, 0, 0], [60, 10, 110], [190, 40, 80], [250, 160, 20], [255, 255, 220]];
+
+/// Spectra of a whole session, rendered as a PNG image
+pub struct Spectrogram {
+    /// Level of each row in dBFS
+    columns: Vec<Vec<f32>>,
+    /// Spectra merged into one column
+    spectra_per_column: usize,
+    /// Column being merged and the spectra in it
+    pending: Option<(Vec<f32>, usize)>,
+    sample_rate: u32,
+}
+
+/// Loudest of two columns, so short events stay visible when columns are merged
+fn merge(column: &mut [f32], other: &[f32]) {
+    for (level, other) in column.iter_mut().zip(other) {
+        *level = level.max(*other);
+    }
+}
+
+impl Spectrogram {
+    pub fn new() -> Self {
+        Self { columns: Vec::new(), spectra_per_column: 1, pending: None, sample_rate: 0 }
+    }
+
+    pub fn push(&mut self, spectrum: &Spectrum) {
+        self.sample_rate = spectrum.sample_rate;
+        let bins_per_row = ((spectrum.power.len() - 1) / HEIGHT).max(1);
+        let column: Vec<f32> = spectrum.power.chunks(bins_per_row).take(HEIGHT)
+            .map(|bins| levels::power_dbfs(bins.iter().copied().fold(0.0, f64::max)) as f32)
+            .collect();
+        let (pending, count) = self.pending.get_or_insert_with(|| (column.clone(), 0));
+        merge(pending, &column);
+        *count += 1;
+        if *count < self.spectra_per_column {
+            return;
+        }
+        let (column, _) = self.pending.take().expect("Pending column was just filled");
+        self.columns.push(column);
+        if self.columns.len() == MAX_WIDTH {
+            self.columns = self.columns.chunks(2).map(|pair| {
+                let mut column = pair[0].clone();
+                merge(&mut column, &pair[1]);
+                column
+            }).collect();
+            self.spectra_per_column *= 2;
+        }
+    }
+
+    /// Whether no spectrum made it into a column yet
+    pub fn is_empty(&self) -> bool {
+        self.columns.is_empty()
+    }
+
+    fn color(level: f32) -> [u8; 3] {
+        let position = ((level as f64 - FLOOR_DBFS) / (CEILING_DBFS - FLOOR_DBFS)).clamp(0.0, 1.0) * (COLORS.len() - 1) as f64;
+        let index = (position as usize).min(COLORS.len() - 2);
+        let fraction = position - index as f64;
+        let (low, high) = (COLORS[index], COLORS[index + 1]);
+        [0, 1, 2].map(|i| (low[i] as f64 + (high[i] as f64 - low[i] as f64) * fraction).round() as u8)
+    }
+
+    /// Write the image; `seconds_per_spectrum`, the hop between spectra, goes into its description
+    pub fn write_png<P: AsRef<Path>>(&self, path: P, seconds_per_spectrum: f64) -> io::Result<()> {
+        if self.columns.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "No audio for a spectrogram"));
+        }
+        let mut encoder = png::Encoder::new(BufWriter::new(File::create(path)?), self.columns.len() as u32, HEIGHT as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.add_text_chunk("Description".to_string(), format!(
+            "Spectrogram, 0 to {} Hz bottom to top, {:.3} s per column, {} to {} dBFS per bin",
+            self.sample_rate / 2, seconds_per_spectrum * self.spectra_per_column as f64, FLOOR_DBFS, CEILING_DBFS))
+            .map_err(io::Error::other)?;
+        let mut image = Vec::with_capacity(self.columns.len() * HEIGHT * 3);
+        for row in (0..HEIGHT).rev() {
+            for column in &self.columns {
+                image.extend(Self::color(column.get(row).copied().unwrap_or(FLOOR_DBFS as f32)));
+            }
+        }
+        let mut writer = encoder.write_header().map_err(io::Error::other)?;
+        writer.write_image_data(&image).map_err(io::Error::other)?;
+        writer.finish().map_err(io::Error::other)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufReader;
+    use crate::analysis::spectrum::FFT_SIZE;
+
+    #[test]
+    fn test_spectrogram() {
+        let mut spectrogram = Spectrogram::new();
+        // A tone in the lower quarter of the band
+        let mut power = vec![1e-12; FFT_SIZE / 2 + 1];
+        power[FFT_SIZE / 8] = 0.1;
+        let spectrum = Spectrum { sample_rate: 48_000, power };
+        for _ in 0..MAX_WIDTH + 2 {
+            spectrogram.push(&spectrum);
+        }
+        assert_eq!(spectrogram.columns.len(), MAX_WIDTH / 2 + 1, "Columns merge in pairs once the image is full");
+
+        let path = std::env::temp_dir().join("serial2wave_test_spectrogram.png");
+        spectrogram.write_png(&path, 2048.0 / 48_000.0).expect("Failed to write the spectrogram");
+        let mut reader = png::Decoder::new(BufReader::new(File::open(&path).unwrap())).read_info().unwrap();
+        let mut image = vec![0; reader.output_buffer_size().unwrap()];
+        let info = reader.next_frame(&mut image).unwrap();
+        assert_eq!((info.width as usize, info.height as usize), (MAX_WIDTH / 2 + 1, HEIGHT));
+        let pixel = |x: usize, y: usize| &image[(y * info.width as usize + x) * 3..][..3];
+        assert_eq!(pixel(0, HEIGHT - 1 - HEIGHT / 4), Spectrogram::color(levels::power_dbfs(0.1) as f32), "Tone row is bright");
+        assert_eq!(pixel(0, 0), Spectrogram::color(levels::power_dbfs(1e-12) as f32), "Noise is dark");
+    }
+}
@@ -0,0 +1,197 @@
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+use std::sync::Arc;
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+use serde::Serialize;
+use super::levels;
+
+/// Samples per spectrum, 85 ms at 48 kHz and 11.7 Hz bins
+pub const FFT_SIZE: usize = 4096;
+/// Samples between spectra; the windows overlap by half
+pub const HOP: usize = FFT_SIZE / 2;
+/// Bins on each side of a peak that belong to it; the window spreads a tone over about eight bins
+const PEAK_HALF_WIDTH: usize = 5;
+/// 4-term Blackman-Harris window, its -92 dB sidelobes keep a tone out of the noise it is measured against
+const WINDOW_COEFFICIENTS: [f64; 4] = [0.35875, 0.48829, 0.14128, 0.01168];
+/// Harmonics counted in the THD, from the 2nd up to this one
+const HIGHEST_HARMONIC: usize = 5;
+/// Noise power below this is taken as this, so a perfect tone still has a finite SNR
+const NOISE_FLOOR: f64 = 1e-20;
+
+/// Power spectrum of a window of audio, one-sided
+#[derive(Clone, Debug)]
+pub struct Spectrum {
+    pub sample_rate: u32,
+    /// Mean square level of each bin, 1.0 for full scale; the bins of a tone add up to its mean square
+    pub power: Vec<f64>,
+}
+
+/// Measurements of a test tone
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct ToneAnalysis {
+    pub frequency: f64,
+    /// RMS level of the tone
+    pub level_dbfs: f64,
+    /// Power of the 2nd to 5th harmonic relative to the tone
+    pub thd_percent: f64,
+    /// Tone power over the power of everything but the tone, its harmonics and DC
+    pub snr_db: f64,
+}
+
+impl Spectrum {
+    pub fn bin_width(&self) -> f64 {
+        self.sample_rate as f64 / ((self.power.len() - 1) * 2) as f64
+    }
+
+    fn strongest_bin(&self, bins: std::ops::Range<usize>) -> Option<usize> {
+        bins.filter(|&bin| bin < self.power.len())
+            .max_by(|&a, &b| self.power[a].total_cmp(&self.power[b]))
+            .filter(|&bin| self.power[bin] > 0.0)
+    }
+
+    /// Frequency of the strongest bin, refined by parabolic interpolation; None for silence
+    pub fn dominant_frequency(&self) -> Option<f64> {
+        let bin = self.strongest_bin(PEAK_HALF_WIDTH + 1..self.power.len() - 1)?;
+        let db = |bin: usize| levels::power_dbfs(self.power[bin]);
+        let (left, center, right) = (db(bin - 1), db(bin), db(bin + 1));
+        let denominator = left - 2.0 * center + right;
+        let offset = if denominator.abs() > f64::EPSILON { 0.5 * (left - right) / denominator } else { 0.0 };
+        Some((bin as f64 + offset.clamp(-0.5, 0.5)) * self.bin_width())
+    }
+
+    /// Power of the peak around `bin`, and the bins it covers
+    fn peak_power(&self, bin: usize) -> (f64, std::ops::Range<usize>) {
+        let bins = bin.saturating_sub(PEAK_HALF_WIDTH)..(bin + PEAK_HALF_WIDTH + 1).min(self.power.len());
+        (self.power[bins.clone()].iter().sum(), bins)
+    }
+
+    /// Frequency, level, THD and SNR of the strongest tone; None for silence
+    pub fn tone(&self) -> Option<ToneAnalysis> {
+        let frequency = self.dominant_frequency()?;
+        let fundamental_bin = (frequency / self.bin_width()).round() as usize;
+        let (fundamental, fundamental_bins) = self.peak_power(fundamental_bin);
+        let mut used = vec![false; self.power.len()];
+        used[..=PEAK_HALF_WIDTH].fill(true);
+        used[fundamental_bins].fill(true);
+
+        let mut harmonics = 0.0;
+        for harmonic in 2..=HIGHEST_HARMONIC {
+            let expected = (frequency * harmonic as f64 / self.bin_width()).round() as usize;
+            if expected + PEAK_HALF_WIDTH >= self.power.len() {
+                break;
+            }
+            // The harmonic is searched near where it should be, small frequency errors add up
+            let Some(bin) = self.strongest_bin(expected.saturating_sub(2)..expected + 3) else { continue };
+            let (power, bins) = self.peak_power(bin);
+            harmonics += power;
+            used[bins].fill(true);
+        }
+        let noise: f64 = self.power.iter().zip(&used).filter(|(_, &used)| !used).map(|(power, _)| power).sum();
+        Some(ToneAnalysis {
+            frequency,
+            level_dbfs: levels::power_dbfs(fundamental),
+            thd_percent: (harmonics / fundamental).sqrt() * 100.0,
+            snr_db: 10.0 * (fundamental / noise.max(NOISE_FLOOR)).log10(),
+        })
+    }
+
+    /// Mean of several spectra, which smooths the noise for steady signals
+    pub fn average(spectra: &[Spectrum]) -> Option<Spectrum> {
+        let first = spectra.first()?;
+        let mut power = vec![0.0; first.power.len()];
+        for spectrum in spectra {
+            for (sum, bin) in power.iter_mut().zip(&spectrum.power) {
+                *sum += bin / spectra.len() as f64;
+            }
+        }
+        Some(Spectrum { sample_rate: first.sample_rate, power })
+    }
+}
+
+/// Computes running spectra of the audio, the channels mixed down
+pub struct SpectrumAnalyzer {
+    fft: Arc<dyn Fft<f64>>,
+    window: Vec<f64>,
+    /// Scales the squared FFT output to the mean square per bin
+    power_scale: f64,
+    samples: VecDeque<f64>,
+    sample_rate: u32,
+    channels: u16,
+}
+
+impl SpectrumAnalyzer {
+    pub fn new(sample_rate: u32, channels: u16) -> Self {
+        let window: Vec<f64> = (0..FFT_SIZE).map(|i| {
+            let phase = 2.0 * PI * i as f64 / FFT_SIZE as f64;
+            let [a0, a1, a2, a3] = WINDOW_COEFFICIENTS;
+            a0 - a1 * phase.cos() + a2 * (2.0 * phase).cos() - a3 * (3.0 * phase).cos()
+        }).collect();
+        let window_energy: f64 = window.iter().map(|w| w * w).sum();
+        Self {
+            fft: FftPlanner::new().plan_fft_forward(FFT_SIZE),
+            power_scale: 2.0 / (FFT_SIZE as f64 * window_energy),
+            window,
+            samples: VecDeque::with_capacity(FFT_SIZE + HOP),
+            sample_rate,
+            channels,
+        }
+    }
+
+    /// Add the 16 bit PCM payload of a frame and return the spectra completed by it
+    pub fn push(&mut self, payload: &[u8]) -> Vec<Spectrum> {
+        let samples: Vec<i16> = levels::samples(payload).collect();
+        for frame in samples.chunks_exact(self.channels as usize) {
+            let mixed = frame.iter().map(|&sample| sample as f64).sum::<f64>() / frame.len() as f64;
+            self.samples.push_back(mixed / 32768.0);
+        }
+        let mut spectra = Vec::new();
+        while self.samples.len() >= FFT_SIZE {
+            spectra.push(self.spectrum());
+            self.samples.drain(..HOP);
+        }
+        spectra
+    }
+
+    fn spectrum(&self) -> Spectrum {
+        let mut buffer: Vec<Complex<f64>> = self.samples.iter().zip(&self.window)
+            .map(|(sample, window)| Complex::new(sample * window, 0.0))
+            .collect();
+        self.fft.process(&mut buffer);
+        let mut power: Vec<f64> = buffer[..=FFT_SIZE / 2].iter().map(|bin| bin.norm_sqr() * self.power_scale).collect();
+        // DC and Nyquist have no mirror image in the discarded half
+        power[0] /= 2.0;
+        power[FFT_SIZE / 2] /= 2.0;
+        Spectrum { sample_rate: self.sample_rate, power }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tone_analysis() {
+        let mut analyzer = SpectrumAnalyzer::new(48_000, 1);
+        // 1 kHz at half scale with a 1% 3rd harmonic and a little noise
+        let mut noise: u32 = 1;
+        let payload: Vec<u8> = (0..FFT_SIZE * 2).flat_map(|i| {
+            let t = i as f64 / 48_000.0;
+            noise = noise.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            let dither = ((noise >> 16) as f64 / 65_536.0 - 0.5) * 4.0;
+            let sample = 16384.0 * ((2.0 * PI * 1000.0 * t).sin() + 0.01 * (2.0 * PI * 3000.0 * t).sin()) + dither;
+            (sample.round() as i16).to_le_bytes()
+        }).collect();
+
+        let spectra = analyzer.push(&payload);
+        assert_eq!(spectra.len(), 3, "Half-overlapping windows");
+        let tone = spectra[1].tone().unwrap();
+        assert!((tone.frequency - 1000.0).abs() < 2.0, "Frequency {}", tone.frequency);
+        assert!((tone.level_dbfs - (-9.03)).abs() < 0.1, "Half scale sine is -9 dBFS RMS, got {}", tone.level_dbfs);
+        assert!((tone.thd_percent - 1.0).abs() < 0.05, "THD {}", tone.thd_percent);
+        assert!(tone.snr_db > 70.0 && tone.snr_db < 100.0, "SNR {}", tone.snr_db);
+
+        let silence = analyzer.push(&vec![0u8; FFT_SIZE * 2 * 2]);
+        assert!(silence.last().unwrap().tone().is_none());
+    }
+}
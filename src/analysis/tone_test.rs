@@ -0,0 +1,204 @@
+use serde::{Deserialize, Serialize};
+use crate::constants::common;
+use super::spectrum::{Spectrum, SpectrumAnalyzer, ToneAnalysis};
+
+/// Expected tone and limits of `tone-test`, the `[tone_test]` table of the config file
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ToneTestSettings {
+    /// Audio measured, after the settle time
+    pub seconds: f64,
+    /// Audio skipped first, while the mic and codec settle
+    pub settle_seconds: f64,
+    pub frequency: f64,
+    pub frequency_tolerance: f64,
+    pub min_level_dbfs: f64,
+    pub max_level_dbfs: f64,
+    pub min_snr_db: f64,
+    pub max_thd_percent: f64,
+    pub max_lost_frames: u64,
+}
+
+impl Default for ToneTestSettings {
+    fn default() -> Self {
+        Self {
+            seconds: 3.0,
+            settle_seconds: 0.5,
+            frequency: 1000.0,
+            frequency_tolerance: 10.0,
+            min_level_dbfs: -40.0,
+            max_level_dbfs: -1.0,
+            min_snr_db: 40.0,
+            max_thd_percent: 1.0,
+            max_lost_frames: 0,
+        }
+    }
+}
+
+/// A measured value against its limits
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct ToneCheck {
+    pub name: &'static str,
+    pub value: f64,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub pass: bool,
+}
+
+impl ToneCheck {
+    fn new(name: &'static str, value: f64, min: Option<f64>, max: Option<f64>) -> Self {
+        let pass = min.is_none_or(|min| value >= min) && max.is_none_or(|max| value <= max);
+        Self { name, value, min, max, pass }
+    }
+}
+
+/// Result of a tone test, printed as JSON
+#[derive(Serialize, Clone, Debug)]
+pub struct ToneTestReport {
+    pub pass: bool,
+    /// Why the tone could not be measured
+    pub error: Option<String>,
+    pub frames: u64,
+    pub lost_frames: u64,
+    pub device_resets: u32,
+    pub tone: Option<ToneAnalysis>,
+    pub checks: Vec<ToneCheck>,
+    pub limits: ToneTestSettings,
+}
+
+/// Measures a test tone from received audio frames and checks it against the limits
+pub struct ToneTest {
+    settings: ToneTestSettings,
+    analyzer: SpectrumAnalyzer,
+    spectra: Vec<Spectrum>,
+    settle_frames: u64,
+    frames_needed: u64,
+    frames: u64,
+    lost_frames: u64,
+    device_resets: u32,
+    last_frame_number: Option<u64>,
+}
+
+fn seconds_to_frames(seconds: f64, sample_rate: u32) -> u64 {
+    (seconds / common::frame_seconds(sample_rate)).ceil().max(0.0) as u64
+}
+
+impl ToneTest {
+    pub fn new(settings: &ToneTestSettings, sample_rate: u32) -> Self {
+        Self {
+            settings: settings.clone(),
+            analyzer: SpectrumAnalyzer::new(sample_rate, common::CHANNELS),
+            spectra: Vec::new(),
+            settle_frames: seconds_to_frames(settings.settle_seconds, sample_rate),
+            frames_needed: seconds_to_frames(settings.seconds, sample_rate).max(1),
+            frames: 0,
+            lost_frames: 0,
+            device_resets: 0,
+            last_frame_number: None,
+        }
+    }
+
+    /// Add the payload of a received audio frame
+    pub fn push(&mut self, frame_number: u64, payload: &[u8]) {
+        if self.is_complete() {
+            return;
+        }
+        if let Some(last) = self.last_frame_number {
+            self.lost_frames += frame_number.saturating_sub(last + 1);
+        }
+        self.last_frame_number = Some(frame_number);
+        if self.settle_frames > 0 {
+            self.settle_frames -= 1;
+            return;
+        }
+        self.frames += 1;
+        self.spectra.extend(self.analyzer.push(payload));
+    }
+
+    /// The frame counter starts over, which is not a gap
+    pub fn on_device_reset(&mut self) {
+        self.device_resets += 1;
+        self.last_frame_number = None;
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.frames >= self.frames_needed
+    }
+
+    pub fn report(&self) -> ToneTestReport {
+        let settings = &self.settings;
+        let tone = Spectrum::average(&self.spectra).and_then(|spectrum| spectrum.tone());
+        let error = if !self.is_complete() {
+            Some(format!("Received {} of {} audio frames", self.frames, self.frames_needed))
+        } else {
+            tone.is_none().then(|| "No tone in the audio".to_string())
+        };
+        let mut checks = Vec::new();
+        if let Some(tone) = &tone {
+            checks.push(ToneCheck::new("frequency", tone.frequency,
+                Some(settings.frequency - settings.frequency_tolerance), Some(settings.frequency + settings.frequency_tolerance)));
+            checks.push(ToneCheck::new("level_dbfs", tone.level_dbfs, Some(settings.min_level_dbfs), Some(settings.max_level_dbfs)));
+            checks.push(ToneCheck::new("snr_db", tone.snr_db, Some(settings.min_snr_db), None));
+            checks.push(ToneCheck::new("thd_percent", tone.thd_percent, None, Some(settings.max_thd_percent)));
+        }
+        checks.push(ToneCheck::new("lost_frames", self.lost_frames as f64, None, Some(settings.max_lost_frames as f64)));
+        checks.push(ToneCheck::new("device_resets", self.device_resets as f64, None, Some(0.0)));
+        ToneTestReport {
+            pass: error.is_none() && checks.iter().all(|check| check.pass),
+            error,
+            frames: self.frames,
+            lost_frames: self.lost_frames,
+            device_resets: self.device_resets,
+            tone,
+            checks,
+            limits: settings.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+
+    fn tone_frame(frame_number: u64, frequency: f64, amplitude: f64) -> Vec<u8> {
+        let samples = common::AUDIO_PAYLOAD_LENGTH / 2;
+        (0..samples).flat_map(|i| {
+            let t = (frame_number as usize * samples + i) as f64 / common::SAMPLE_RATE as f64;
+            ((amplitude * (2.0 * PI * frequency * t).sin()).round() as i16).to_le_bytes()
+        }).collect()
+    }
+
+    #[test]
+    fn test_tone_test() {
+        let settings = ToneTestSettings { seconds: 1.0, ..Default::default() };
+        let mut test = ToneTest::new(&settings, common::SAMPLE_RATE);
+        // The settle time hides a loud click at the start
+        test.push(0, &[0xff, 0x7f].repeat(common::AUDIO_PAYLOAD_LENGTH / 2));
+        for frame_number in 1..40 {
+            test.push(frame_number, &tone_frame(frame_number, 1002.0, 8000.0));
+        }
+        assert!(test.is_complete());
+        let report = test.report();
+        assert!(report.pass, "{:?}", report);
+        assert_eq!(report.frames, 24, "One second after the settle time");
+        let tone = report.tone.unwrap();
+        assert!((tone.frequency - 1002.0).abs() < 1.0);
+        assert!((tone.level_dbfs - (-15.2)).abs() < 0.1, "{}", tone.level_dbfs);
+
+        let mut test = ToneTest::new(&ToneTestSettings { settle_seconds: 0.0, ..settings.clone() }, common::SAMPLE_RATE);
+        for frame_number in (0..60).filter(|&frame_number| frame_number != 30) {
+            test.push(frame_number, &tone_frame(frame_number, 1100.0, 8000.0));
+        }
+        let report = test.report();
+        assert!(!report.pass);
+        let failed: Vec<&str> = report.checks.iter().filter(|check| !check.pass).map(|check| check.name).collect();
+        assert_eq!(failed, ["frequency"], "Frame 30 was lost after the test ended");
+
+        let mut test = ToneTest::new(&settings, common::SAMPLE_RATE);
+        test.push(0, &tone_frame(0, 1000.0, 8000.0));
+        let report = test.report();
+        assert!(!report.pass);
+        assert_eq!(report.error.as_deref(), Some("Received 0 of 24 audio frames"));
+    }
+}
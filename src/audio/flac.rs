@@ -0,0 +1,453 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use md5::{Digest, Md5};
+use crate::receiver::console;
+
+/// Samples per channel in each FLAC frame
+const BLOCK_SIZE: usize = 4096;
+/// Room for the `VORBIS_COMMENT` and `PADDING` blocks, so comments can be rewritten in place
+const COMMENT_SPACE: usize = 8192;
+/// Offset of the `STREAMINFO` body, after "fLaC" and the block header
+const STREAMINFO_OFFSET: u64 = 8;
+const STREAMINFO_LENGTH: usize = 34;
+const COMMENT_OFFSET: u64 = STREAMINFO_OFFSET + STREAMINFO_LENGTH as u64;
+const MAX_FIXED_ORDER: usize = 4;
+const MAX_PARTITION_ORDER: u32 = 6;
+/// Largest parameter of the 5 bit Rice coding method; 31 is the escape code
+const MAX_RICE_PARAMETER: u32 = 30;
+
+/// Packs values MSB first, as FLAC frames are laid out
+struct BitWriter {
+    bytes: Vec<u8>,
+    accumulator: u64,
+    bits: u32,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        Self { bytes: Vec::new(), accumulator: 0, bits: 0 }
+    }
+
+    /// Write the low `bits` bits of `value`, at most 32
+    fn write(&mut self, value: u64, bits: u32) {
+        if bits == 0 {
+            return;
+        }
+        self.accumulator = (self.accumulator << bits) | (value & ((1u64 << bits) - 1));
+        self.bits += bits;
+        while self.bits >= 8 {
+            self.bits -= 8;
+            self.bytes.push((self.accumulator >> self.bits) as u8);
+        }
+    }
+
+    fn write_signed(&mut self, value: i64, bits: u32) {
+        self.write(value as u64, bits);
+    }
+
+    /// `zeros` zero bits followed by a one
+    fn write_unary(&mut self, mut zeros: u64) {
+        while zeros >= 32 {
+            self.write(0, 32);
+            zeros -= 32;
+        }
+        self.write(1, zeros as u32 + 1);
+    }
+
+    /// Pad with zero bits to the next byte
+    fn align(&mut self) {
+        if self.bits > 0 {
+            self.write(0, 8 - self.bits);
+        }
+    }
+}
+
+fn crc8(bytes: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for byte in bytes {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for byte in bytes {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x8005 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// Frame header code of the common sample rates, 0 means "see STREAMINFO"
+fn sample_rate_code(sample_rate: u32) -> u64 {
+    match sample_rate {
+        88_200 => 1,
+        176_400 => 2,
+        192_000 => 3,
+        8_000 => 4,
+        16_000 => 5,
+        22_050 => 6,
+        24_000 => 7,
+        32_000 => 8,
+        44_100 => 9,
+        48_000 => 10,
+        96_000 => 11,
+        _ => 0,
+    }
+}
+
+/// The frame number in the extended UTF-8 coding of FLAC frame headers
+fn write_utf8_number(writer: &mut BitWriter, value: u64) {
+    if value < 0x80 {
+        writer.write(value, 8);
+        return;
+    }
+    let continuation_bytes = match value {
+        0..=0x7ff => 1,
+        0x800..=0xffff => 2,
+        0x1_0000..=0x1f_ffff => 3,
+        0x20_0000..=0x3ff_ffff => 4,
+        _ => 5,
+    };
+    let prefix = (0xff00u64 >> (continuation_bytes + 1)) & 0xff;
+    writer.write(prefix | (value >> (6 * continuation_bytes)), 8);
+    for i in (0..continuation_bytes).rev() {
+        writer.write(0x80 | ((value >> (6 * i)) & 0x3f), 8);
+    }
+}
+
+/// Residual of the fixed polynomial predictor of `order`
+fn fixed_residual(samples: &[i64], order: usize) -> Vec<i64> {
+    (order..samples.len()).map(|i| {
+        let s = |back: usize| samples[i - back];
+        match order {
+            0 => s(0),
+            1 => s(0) - s(1),
+            2 => s(0) - 2 * s(1) + s(2),
+            3 => s(0) - 3 * s(1) + 3 * s(2) - s(3),
+            _ => s(0) - 4 * s(1) + 6 * s(2) - 4 * s(3) + s(4),
+        }
+    }).collect()
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+/// Best Rice parameter for a partition and its size in bits
+fn rice_parameter(residual: &[i64]) -> (u32, u64) {
+    let sum: u64 = residual.iter().map(|&r| zigzag(r)).sum();
+    let mean = sum / residual.len().max(1) as u64;
+    let estimate = 64 - mean.leading_zeros();
+    let cost = |k: u32| residual.iter().map(|&r| zigzag(r) >> k).sum::<u64>() + residual.len() as u64 * (k as u64 + 1);
+    (estimate.saturating_sub(1)..=(estimate + 1).min(MAX_RICE_PARAMETER))
+        .map(|k| (k, cost(k)))
+        .min_by_key(|&(_, bits)| bits)
+        .expect("Parameter range is not empty")
+}
+
+/// Rice partitions of a residual: the partition order and each partition's parameter, with the total size in bits
+fn plan_partitions(residual: &[i64], block_size: usize, predictor_order: usize) -> (u32, Vec<u32>, u64) {
+    let mut best: Option<(u32, Vec<u32>, u64)> = None;
+    for partition_order in 0..=MAX_PARTITION_ORDER {
+        let partition_size = block_size >> partition_order;
+        if !block_size.is_multiple_of(1 << partition_order) || partition_size <= predictor_order {
+            break;
+        }
+        let mut parameters = Vec::new();
+        let mut bits = 0;
+        let mut start = 0;
+        for partition in 0..1usize << partition_order {
+            let length = if partition == 0 { partition_size - predictor_order } else { partition_size };
+            let (parameter, partition_bits) = rice_parameter(&residual[start..start + length]);
+            parameters.push(parameter);
+            bits += 5 + partition_bits;
+            start += length;
+        }
+        if best.as_ref().is_none_or(|(_, _, best_bits)| bits < *best_bits) {
+            best = Some((partition_order, parameters, bits));
+        }
+    }
+    best.expect("Partition order 0 always fits")
+}
+
+/// Write one channel of a block as the smallest of a constant, fixed or verbatim subframe
+fn write_subframe(writer: &mut BitWriter, samples: &[i64], bits_per_sample: u32) {
+    if samples.iter().all(|&sample| sample == samples[0]) {
+        writer.write(0b0000_0000, 8);
+        writer.write_signed(samples[0], bits_per_sample);
+        return;
+    }
+
+    let verbatim_bits = samples.len() as u64 * bits_per_sample as u64;
+    let best = (0..=MAX_FIXED_ORDER.min(samples.len() - 1))
+        .map(|order| {
+            let residual = fixed_residual(samples, order);
+            let (partition_order, parameters, bits) = plan_partitions(&residual, samples.len(), order);
+            (order, residual, partition_order, parameters, bits + order as u64 * bits_per_sample as u64)
+        })
+        .min_by_key(|candidate| candidate.4)
+        .filter(|candidate| candidate.4 < verbatim_bits);
+
+    match best {
+        Some((order, residual, partition_order, parameters, _)) => {
+            writer.write(0b0001_0000 | (order as u64) << 1, 8);
+            for &sample in &samples[..order] {
+                writer.write_signed(sample, bits_per_sample);
+            }
+            writer.write(0b01, 2); // Rice coding with 5 bit parameters
+            writer.write(partition_order as u64, 4);
+            let partition_size = samples.len() >> partition_order;
+            let mut start = 0;
+            for (partition, parameter) in parameters.into_iter().enumerate() {
+                let length = if partition == 0 { partition_size - order } else { partition_size };
+                writer.write(parameter as u64, 5);
+                for &value in &residual[start..start + length] {
+                    let value = zigzag(value);
+                    writer.write_unary(value >> parameter);
+                    writer.write(value, parameter);
+                }
+                start += length;
+            }
+        }
+        None => {
+            writer.write(0b0000_0010, 8);
+            for &sample in samples {
+                writer.write_signed(sample, bits_per_sample);
+            }
+        }
+    }
+}
+
+/// Writes 16 bit PCM audio into a FLAC file, with metadata as Vorbis comments
+pub struct FlacWriter {
+    file: BufWriter<File>,
+    sample_rate: u32,
+    channels: u16,
+    bits_per_sample: u16,
+    comments: Vec<(String, String)>,
+    /// Interleaved samples not yet encoded into a full block
+    pending: Vec<i64>,
+    frame_number: u64,
+    total_samples: u64,
+    min_frame_length: u32,
+    max_frame_length: u32,
+    md5: Md5,
+}
+
+impl FlacWriter {
+    /// Create the file and write the metadata blocks for an empty stream
+    pub fn create<P: AsRef<Path>>(path: P, sample_rate: u32, channels: u16, bits_per_sample: u16) -> io::Result<Self> {
+        if bits_per_sample != 16 {
+            return Err(io::Error::other("Only 16 bit audio can be written as FLAC"));
+        }
+        let mut writer = Self {
+            file: BufWriter::new(File::create(path)?),
+            sample_rate,
+            channels,
+            bits_per_sample,
+            comments: Vec::new(),
+            pending: Vec::new(),
+            frame_number: 0,
+            total_samples: 0,
+            min_frame_length: 0,
+            max_frame_length: 0,
+            md5: Md5::new(),
+        };
+        writer.file.write_all(b"fLaC")?;
+        writer.file.write_all(&[0, 0, 0, STREAMINFO_LENGTH as u8])?;
+        writer.file.write_all(&writer.stream_info())?;
+        writer.file.write_all(&writer.comment_blocks()?)?;
+        writer.file.flush()?;
+        Ok(writer)
+    }
+
+    fn stream_info(&self) -> Vec<u8> {
+        let mut info = BitWriter::new();
+        info.write(BLOCK_SIZE as u64, 16); // Minimum block size
+        info.write(BLOCK_SIZE as u64, 16); // Maximum block size
+        info.write(self.min_frame_length as u64, 24);
+        info.write(self.max_frame_length as u64, 24);
+        info.write(self.sample_rate as u64, 20);
+        info.write(self.channels as u64 - 1, 3);
+        info.write(self.bits_per_sample as u64 - 1, 5);
+        info.write(self.total_samples >> 32, 4);
+        info.write(self.total_samples, 32);
+        info.bytes.extend(self.md5.clone().finalize());
+        info.bytes
+    }
+
+    /// `VORBIS_COMMENT` block followed by a `PADDING` block filling the reserved space
+    fn comment_blocks(&self) -> io::Result<Vec<u8>> {
+        let mut comment: Vec<u8> = Vec::new();
+        let vendor = concat!("Serial2Wave ", env!("CARGO_PKG_VERSION"));
+        comment.extend(&(vendor.len() as u32).to_le_bytes());
+        comment.extend(vendor.as_bytes());
+        comment.extend(&(self.comments.len() as u32).to_le_bytes());
+        for (key, value) in &self.comments {
+            let entry = format!("{}={}", key, value);
+            comment.extend(&(entry.len() as u32).to_le_bytes());
+            comment.extend(entry.as_bytes());
+        }
+        if comment.len() + 8 > COMMENT_SPACE {
+            return Err(io::Error::other("Vorbis comments do not fit the reserved space"));
+        }
+
+        let padding = COMMENT_SPACE - comment.len() - 8;
+        let mut blocks = Vec::with_capacity(COMMENT_SPACE);
+        blocks.push(4); // VORBIS_COMMENT
+        blocks.extend(&(comment.len() as u32).to_be_bytes()[1..]);
+        blocks.extend(comment);
+        blocks.push(0x80 | 1); // Last metadata block, PADDING
+        blocks.extend(&(padding as u32).to_be_bytes()[1..]);
+        blocks.resize(COMMENT_SPACE, 0);
+        Ok(blocks)
+    }
+
+    /// Set a Vorbis comment such as `DESCRIPTION`, replacing an earlier value
+    pub fn set_comment(&mut self, key: &str, value: &str) -> io::Result<()> {
+        match self.comments.iter_mut().find(|(existing, _)| existing.eq_ignore_ascii_case(key)) {
+            Some((_, existing)) => *existing = value.to_string(),
+            None => self.comments.push((key.to_string(), value.to_string())),
+        }
+        let blocks = self.comment_blocks()?;
+        self.file.seek(SeekFrom::Start(COMMENT_OFFSET))?;
+        self.file.write_all(&blocks)?;
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.flush()
+    }
+
+    /// Append little-endian PCM bytes, encoding every full block right away
+    pub fn write_pcm(&mut self, data: &[u8]) -> io::Result<()> {
+        self.pending.extend(data.chunks_exact(2).map(|sample| i16::from_le_bytes([sample[0], sample[1]]) as i64));
+        let block_samples = BLOCK_SIZE * self.channels as usize;
+        while self.pending.len() >= block_samples {
+            let block: Vec<i64> = self.pending.drain(..block_samples).collect();
+            self.write_frame(&block)?;
+        }
+        Ok(())
+    }
+
+    /// Encode the samples still pending as a shorter last block
+    pub fn finish(&mut self) -> io::Result<()> {
+        if !self.pending.is_empty() {
+            let block = std::mem::take(&mut self.pending);
+            self.write_frame(&block)?;
+        }
+        Ok(())
+    }
+
+    /// Encode one block of interleaved samples, then update STREAMINFO
+    /// so a capture that is killed mid-session is still decodable
+    fn write_frame(&mut self, interleaved: &[i64]) -> io::Result<()> {
+        let channels = self.channels as usize;
+        let block_size = interleaved.len() / channels;
+        let mut frame = BitWriter::new();
+        frame.write(0b1111_1111_1111_1000, 16); // Sync code, fixed block size
+        let block_size_code = match block_size {
+            BLOCK_SIZE => 0b1100,
+            1..=256 => 0b0110,
+            _ => 0b0111,
+        };
+        frame.write(block_size_code, 4);
+        frame.write(sample_rate_code(self.sample_rate), 4);
+        frame.write(channels as u64 - 1, 4); // Independent channels
+        frame.write(0b100, 3); // 16 bits per sample
+        frame.write(0, 1);
+        write_utf8_number(&mut frame, self.frame_number);
+        match block_size_code {
+            0b0110 => frame.write(block_size as u64 - 1, 8),
+            0b0111 => frame.write(block_size as u64 - 1, 16),
+            _ => {}
+        }
+        let header_crc = crc8(&frame.bytes);
+        frame.write(header_crc as u64, 8);
+
+        for channel in 0..channels {
+            let samples: Vec<i64> = interleaved.iter().skip(channel).step_by(channels).copied().collect();
+            write_subframe(&mut frame, &samples, self.bits_per_sample as u32);
+        }
+        frame.align();
+        let frame_crc = crc16(&frame.bytes);
+        frame.write(frame_crc as u64, 16);
+
+        self.file.write_all(&frame.bytes)?;
+        // The MD5 covers only encoded audio, so a file that is never finished still verifies
+        let pcm: Vec<u8> = interleaved.iter().flat_map(|&sample| (sample as i16).to_le_bytes()).collect();
+        self.md5.update(&pcm);
+        let frame_length = frame.bytes.len() as u32;
+        self.min_frame_length = if self.frame_number == 0 { frame_length } else { self.min_frame_length.min(frame_length) };
+        self.max_frame_length = self.max_frame_length.max(frame_length);
+        self.frame_number += 1;
+        self.total_samples += block_size as u64;
+
+        let stream_info = self.stream_info();
+        self.file.seek(SeekFrom::Start(STREAMINFO_OFFSET))?;
+        self.file.write_all(&stream_info)?;
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.flush()
+    }
+}
+
+impl Drop for FlacWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            console::report_error(format_args!("Error finishing FLAC file: {}", e));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_utils;
+
+    #[test]
+    fn test_flac_round_trip() {
+        let capture = test_utils::read_file_as_bytes("tests/data/CoolTerm Capture (Untitled_1) 2024-12-21 12-24-34-830.txt").unwrap();
+        let sync = [0xFF, 0x01, 0xFF, 0x02, 0xFF, 0x03, 0xFF, 0x04];
+        let mut pcm: Vec<u8> = capture.windows(sync.len()).enumerate()
+            .filter(|(position, window)| *window == sync && *position >= 4004)
+            .flat_map(|(position, _)| capture[position - 4004..position - 4].to_vec())
+            .collect();
+        pcm.extend([0u8; 2 * BLOCK_SIZE]); // Silence becomes a constant subframe
+        pcm.extend([1, 0, 255, 255, 0, 128]); // Short last block
+
+        let path = std::env::temp_dir().join("serial2wave_test_flac.flac");
+        let mut writer = FlacWriter::create(&path, 48_000, 1, 16).expect("Failed to create flac");
+        writer.set_comment("DESCRIPTION", "port=/dev/ttyACM0").unwrap();
+        for chunk in pcm.chunks(4000) {
+            writer.write_pcm(chunk).unwrap();
+        }
+        let encoded = pcm.len() - 2 * writer.pending.len();
+        let unfinished = test_utils::read_file_as_bytes(&path).unwrap();
+        let md5_offset = STREAMINFO_OFFSET as usize + STREAMINFO_LENGTH - 16;
+        assert_eq!(&unfinished[md5_offset..md5_offset + 16], &Md5::digest(&pcm[..encoded])[..],
+            "Until the file is finished, the MD5 covers the encoded blocks");
+        writer.set_comment("REV_INFO", "03fa2ba-dirty:open_source").unwrap();
+        writer.set_comment("description", "port=/dev/ttyACM0; REV_INFO=03fa2ba-dirty:open_source").unwrap();
+        drop(writer);
+
+        let flac_length = std::fs::metadata(&path).unwrap().len() as usize;
+        let mut reader = claxon::FlacReader::open(&path).expect("Failed to open flac");
+        let info = reader.streaminfo();
+        assert_eq!(info.sample_rate, 48_000);
+        assert_eq!(info.samples, Some(pcm.len() as u64 / 2));
+        assert_eq!(&info.md5sum[..], &Md5::digest(&pcm)[..]);
+        assert_eq!(reader.get_tag("REV_INFO").collect::<Vec<_>>(), ["03fa2ba-dirty:open_source"]);
+        assert_eq!(reader.get_tag("DESCRIPTION").collect::<Vec<_>>(), ["port=/dev/ttyACM0; REV_INFO=03fa2ba-dirty:open_source"],
+            "Comments are replaced, not repeated");
+
+        let decoded: Vec<u8> = reader.samples().flat_map(|sample| (sample.unwrap() as i16).to_le_bytes()).collect();
+        std::fs::remove_file(&path).unwrap();
+        assert!(decoded == pcm, "Decoded audio is bit-exact");
+        assert!(flac_length < pcm.len() * 3 / 4, "{} bytes of FLAC for {} bytes of PCM", flac_length, pcm.len());
+    }
+}
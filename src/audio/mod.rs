@@ -0,0 +1,4 @@
+pub mod flac;
+pub mod rtp;
+pub mod stream;
+pub mod wav;
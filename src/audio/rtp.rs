@@ -0,0 +1,186 @@
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Dynamic RTP payload type announced as L16 in the SDP
+const PAYLOAD_TYPE: u8 = 96;
+/// Samples per RTP packet; a frame of 2000 samples goes out as four packets that fit a 1500 byte MTU
+const SAMPLES_PER_PACKET: usize = 500;
+const RTP_HEADER_LENGTH: usize = 12;
+
+/// Sends live audio as L16 RTP packets over UDP (RFC 3551).
+/// Sequence numbers and timestamps follow the frame counter, so lost frames show up as gaps at the receiver.
+/// Frames may hold any number of samples, e.g. after a sample rate conversion.
+pub struct RtpSender {
+    socket: UdpSocket,
+    destination: SocketAddr,
+    sample_rate: u32,
+    channels: u16,
+    /// Nominal samples of a frame, the gap a lost frame leaves
+    samples_per_frame: f64,
+    ssrc: u32,
+    sequence_base: u16,
+    timestamp_base: u32,
+    /// Frame counter value of the first frame of the current boot
+    boot_first_frame: Option<u64>,
+    /// Stream position, in frames, where the current boot starts
+    boot_offset: u64,
+    next_index: u64,
+    /// Packets and samples sent so far, plus the gaps of lost frames
+    packets: u64,
+    position: u64,
+    /// Set the marker bit on the next packet, at the start of the stream and after a reset
+    marker: bool,
+}
+
+impl RtpSender {
+    /// Send to `destination`, e.g. `192.168.1.20:5004`; a frame lasts `frame_seconds`
+    pub fn connect(destination: &str, sample_rate: u32, channels: u16, frame_seconds: f64) -> io::Result<Self> {
+        let destination = destination.to_socket_addrs()?.next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("No address for {}", destination)))?;
+        let bind_address: SocketAddr = if destination.is_ipv4() { ([0, 0, 0, 0], 0).into() } else { ([0u16; 8], 0).into() };
+        let socket = UdpSocket::bind(bind_address)?;
+        socket.connect(destination)?;
+
+        // RFC 3550 asks for random initial values; the clock is random enough here
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
+        let seed = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        Ok(Self {
+            socket,
+            destination,
+            sample_rate,
+            channels,
+            samples_per_frame: frame_seconds * sample_rate as f64,
+            ssrc: (seed >> 32) as u32,
+            sequence_base: (seed >> 16) as u16,
+            timestamp_base: seed as u32,
+            boot_first_frame: None,
+            boot_offset: 0,
+            next_index: 0,
+            packets: 0,
+            position: 0,
+            marker: true,
+        })
+    }
+
+    /// Announce and time the audio as `sample_rate` from now on; receivers have to open the new SDP
+    pub fn set_sample_rate(&mut self, sample_rate: u32, frame_seconds: f64) {
+        self.sample_rate = sample_rate;
+        self.samples_per_frame = frame_seconds * sample_rate as f64;
+    }
+
+    /// Continue the stream after a device reset, when the frame counter starts over
+    pub fn reset(&mut self) {
+        self.boot_offset = self.next_index;
+        self.boot_first_frame = None;
+        self.marker = true;
+    }
+
+    /// Send the little-endian PCM payload of the frame with counter value `frame_number`
+    pub fn send_frame(&mut self, frame_number: u64, payload: &[u8]) -> io::Result<()> {
+        let first_frame = *self.boot_first_frame.get_or_insert(frame_number);
+        let index = self.boot_offset + frame_number.saturating_sub(first_frame);
+        let block_align = self.channels as usize * 2;
+        if index > self.next_index {
+            let lost = (index - self.next_index) as f64;
+            self.position += (lost * self.samples_per_frame).round() as u64;
+            self.packets += (lost * (self.samples_per_frame / SAMPLES_PER_PACKET as f64).ceil()) as u64;
+        }
+
+        for chunk in payload.chunks(SAMPLES_PER_PACKET * block_align) {
+            let sequence = self.sequence_base.wrapping_add(self.packets as u16);
+            let timestamp = self.timestamp_base.wrapping_add(self.position as u32);
+            self.packets += 1;
+            self.position += (chunk.len() / block_align) as u64;
+            let mut packet = Vec::with_capacity(RTP_HEADER_LENGTH + chunk.len());
+            packet.push(0x80); // Version 2, no padding, extension or CSRCs
+            packet.push(PAYLOAD_TYPE | if self.marker { 0x80 } else { 0 });
+            packet.extend(&sequence.to_be_bytes());
+            packet.extend(&timestamp.to_be_bytes());
+            packet.extend(&self.ssrc.to_be_bytes());
+            // L16 is big-endian
+            packet.extend(chunk.chunks_exact(2).flat_map(|sample| [sample[1], sample[0]]));
+            self.marker = false;
+
+            match self.socket.send(&packet) {
+                // Nobody listens yet; keep sending so a receiver can join any time
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {},
+                result => { result?; },
+            }
+        }
+        self.next_index = self.next_index.max(index + 1);
+        Ok(())
+    }
+
+    /// SDP description of the stream, for `ffplay`, VLC or GStreamer to open
+    pub fn sdp(&self, session_name: &str) -> io::Result<String> {
+        let local = self.socket.local_addr()?;
+        let address_type = |address: &SocketAddr| if address.is_ipv4() { "IP4" } else { "IP6" };
+        Ok(format!(
+            "v=0\r\n\
+             o=- {ssrc} 1 IN {} {}\r\n\
+             s={session_name}\r\n\
+             c=IN {} {}\r\n\
+             t=0 0\r\n\
+             m=audio {} RTP/AVP {PAYLOAD_TYPE}\r\n\
+             a=rtpmap:{PAYLOAD_TYPE} L16/{}/{}\r\n\
+             a=recvonly\r\n",
+            address_type(&local), local.ip(),
+            address_type(&self.destination), self.destination.ip(),
+            self.destination.port(),
+            self.sample_rate, self.channels,
+            ssrc = self.ssrc,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::constants::common;
+
+    #[test]
+    fn test_rtp_loopback() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let destination = receiver.local_addr().unwrap().to_string();
+        let mut sender = RtpSender::connect(&destination, 48_000, 1, common::frame_seconds(48_000)).expect("Failed to create RTP sender");
+
+        let payload: Vec<u8> = (0..2000u16).flat_map(|sample| sample.to_le_bytes()).collect();
+        sender.send_frame(5, &payload).unwrap();
+        sender.send_frame(7, &payload).unwrap(); // Frame 6 was lost
+        sender.reset();
+        sender.send_frame(0, &payload).unwrap();
+
+        let mut packets = Vec::new();
+        let mut buffer = [0u8; 2048];
+        for _ in 0..12 {
+            let length = receiver.recv(&mut buffer).expect("Missing RTP packet");
+            packets.push(buffer[..length].to_vec());
+        }
+        let sequence = |packet: &[u8]| u16::from_be_bytes([packet[2], packet[3]]);
+        let timestamp = |packet: &[u8]| u32::from_be_bytes(packet[4..8].try_into().unwrap());
+
+        assert_eq!(packets[0][0], 0x80, "RTP version 2");
+        assert_eq!(packets[0][1], 0x80 | PAYLOAD_TYPE, "Marker on the first packet");
+        assert_eq!(packets[1][1], PAYLOAD_TYPE);
+        assert_eq!(packets[0].len(), RTP_HEADER_LENGTH + 1000);
+        assert_eq!(&packets[0][RTP_HEADER_LENGTH..RTP_HEADER_LENGTH + 4], &[0, 0, 0, 1], "Samples are big-endian");
+        assert_eq!(&packets[1][RTP_HEADER_LENGTH..RTP_HEADER_LENGTH + 2], &[1, 244], "Second packet starts at sample 500");
+        assert_eq!(sequence(&packets[1]), sequence(&packets[0]).wrapping_add(1));
+        assert_eq!(timestamp(&packets[1]).wrapping_sub(timestamp(&packets[0])), 500);
+        assert_eq!(sequence(&packets[4]).wrapping_sub(sequence(&packets[3])), 5, "Lost frame leaves a sequence gap");
+        assert_eq!(timestamp(&packets[4]).wrapping_sub(timestamp(&packets[0])), 2 * 2000);
+        assert_eq!(packets[8][1], 0x80 | PAYLOAD_TYPE, "Marker after a device reset");
+        assert_eq!(sequence(&packets[8]), sequence(&packets[7]).wrapping_add(1), "Stream continues over a reset");
+        assert_eq!(timestamp(&packets[8]).wrapping_sub(timestamp(&packets[0])), 3 * 2000);
+        assert!(packets.iter().all(|packet| packet[8..12] == packets[0][8..12]), "One SSRC");
+
+        let sdp = sender.sdp("session").unwrap();
+        let port = receiver.local_addr().unwrap().port();
+        assert!(sdp.contains("c=IN IP4 127.0.0.1\r\n"));
+        assert!(sdp.contains(&format!("m=audio {} RTP/AVP 96\r\n", port)));
+        assert!(sdp.contains("a=rtpmap:96 L16/48000/1\r\n"));
+    }
+}
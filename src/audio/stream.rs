@@ -0,0 +1,80 @@
+use std::fs::File;
+use std::io::{self, Write};
+use serde::Deserialize;
+use super::wav;
+
+/// Target name that streams to standard output instead of a file or FIFO
+pub const STDOUT_TARGET: &str = "-";
+
+/// Framing of the live audio stream
+#[derive(Deserialize, clap::ValueEnum, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum StreamFormat {
+    /// Headerless 16 bit little-endian PCM
+    Raw,
+    /// A wav header of unknown length, then PCM
+    #[default]
+    Wav,
+}
+
+/// Writes live audio to stdout or a named pipe, frame by frame
+pub struct AudioStream {
+    output: Box<dyn Write + Send>,
+    format: StreamFormat,
+}
+
+impl AudioStream {
+    /// Open `target`, `-` for stdout or a path such as a FIFO made with `mkfifo`.
+    /// Opening a FIFO waits until a reader opens the other end.
+    pub fn open(target: &str, format: StreamFormat, sample_rate: u32, channels: u16, bits_per_sample: u16) -> io::Result<Self> {
+        let output: Box<dyn Write + Send> = match target {
+            STDOUT_TARGET => Box::new(io::stdout()),
+            path => Box::new(File::create(path)?),
+        };
+        let mut stream = Self { output, format };
+        if format == StreamFormat::Wav {
+            stream.output.write_all(&wav::streaming_header(sample_rate, channels, bits_per_sample))?;
+        }
+        Ok(stream)
+    }
+
+    /// Whether the stream started with a wav header, which names the sample rate
+    pub fn has_header(&self) -> bool {
+        self.format == StreamFormat::Wav
+    }
+
+    /// Write little-endian PCM bytes and flush them, so the reader gets them right away
+    pub fn write_pcm(&mut self, data: &[u8]) -> io::Result<()> {
+        self.output.write_all(data)?;
+        self.output.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_utils;
+
+    #[test]
+    fn test_audio_stream() {
+        let path = std::env::temp_dir().join("serial2wave_test_audio_stream.wav");
+        let target = path.to_str().unwrap();
+
+        let mut stream = AudioStream::open(target, StreamFormat::Wav, 48_000, 1, 16).expect("Failed to open stream");
+        stream.write_pcm(&[1, 0, 2, 0]).unwrap();
+        drop(stream);
+        let bytes = test_utils::read_file_as_bytes(&path).unwrap();
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(bytes[4..8].try_into().unwrap()), u32::MAX, "Length is unknown while streaming");
+        assert_eq!(u32::from_le_bytes(bytes[24..28].try_into().unwrap()), 48_000);
+        assert_eq!(&bytes[36..40], b"data");
+        assert_eq!(&bytes[44..], &[1, 0, 2, 0]);
+
+        let mut stream = AudioStream::open(target, StreamFormat::Raw, 48_000, 1, 16).expect("Failed to open stream");
+        stream.write_pcm(&[1, 0, 2, 0]).unwrap();
+        drop(stream);
+        let bytes = test_utils::read_file_as_bytes(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(bytes, [1, 0, 2, 0], "Raw streams carry only the samples");
+    }
+}
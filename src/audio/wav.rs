@@ -0,0 +1,560 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use chrono::{DateTime, Local};
+use crate::receiver::console;
+
+/// RIFF, fmt and data chunk headers
+const HEADER_LENGTH: u64 = 44;
+/// Body of a `ds64` chunk without a table, reserved as `JUNK` until the file outgrows RIFF
+const DS64_LENGTH: u32 = 28;
+/// Body of a version 2 `bext` chunk without coding history
+const BEXT_LENGTH: u32 = 602;
+const BEXT_DESCRIPTION_LENGTH: usize = 256;
+const BEXT_TIME_REFERENCE_OFFSET: u64 = 338;
+/// Bytes of audio read at a time while normalizing
+const NORMALIZE_CHUNK_LENGTH: usize = 1 << 16;
+
+/// Broadcast Wave Format metadata written into the `bext` chunk
+pub struct BroadcastExtension {
+    pub description: String,
+    pub originator: String,
+    pub origination: DateTime<Local>,
+    /// First sample of the file, counted in samples since midnight
+    pub time_reference: u64,
+}
+
+/// A labeled marker at a sample position
+struct Cue {
+    position: u32,
+    label: String,
+}
+
+/// Where the audio of a closed wav file lies, so it can be scaled afterwards
+pub struct WavAudio {
+    path: PathBuf,
+    data_offset: u64,
+    data_length: u64,
+    bits_per_sample: u16,
+    /// Highest absolute sample value of 16 bit audio
+    peak: u16,
+}
+
+/// Writes PCM audio into a RIFF/WAVE file
+pub struct WavWriter {
+    file: BufWriter<File>,
+    path: PathBuf,
+    block_align: u16,
+    bits_per_sample: u16,
+    /// Offset of the first PCM byte
+    data_offset: u64,
+    data_length: u64,
+    /// Offset of the `bext` chunk body, if there is one
+    bext_offset: Option<u64>,
+    /// Whether a `ds64` chunk is reserved, so the file can grow past 4 GiB as RF64
+    rf64: bool,
+    cues: Vec<Cue>,
+    info: Vec<([u8; 4], String)>,
+    /// Whether audio was written over the trailer since it was last written
+    trailer_stale: bool,
+    /// Highest absolute sample value written, tracked for 16 bit audio
+    peak: u16,
+}
+
+/// `value` as a fixed length, null padded field
+fn fixed_field(value: &str, length: usize) -> Vec<u8> {
+    let mut field = value.as_bytes().to_vec();
+    field.truncate(length);
+    field.resize(length, 0);
+    field
+}
+
+/// `fmt ` chunk of integer PCM audio
+fn fmt_chunk(sample_rate: u32, channels: u16, bits_per_sample: u16) -> Vec<u8> {
+    let block_align = channels * bits_per_sample / 8;
+    let byte_rate = sample_rate * block_align as u32;
+    let mut chunk = Vec::new();
+    chunk.extend(b"fmt ");
+    chunk.extend(&16u32.to_le_bytes());
+    chunk.extend(&1u16.to_le_bytes()); // PCM
+    chunk.extend(&channels.to_le_bytes());
+    chunk.extend(&sample_rate.to_le_bytes());
+    chunk.extend(&byte_rate.to_le_bytes());
+    chunk.extend(&block_align.to_le_bytes());
+    chunk.extend(&bits_per_sample.to_le_bytes());
+    chunk
+}
+
+/// Header of a wav stream of unknown length, as written to pipes.
+/// The sizes are left at the maximum, which sox and ffmpeg read as "until the end of the stream".
+pub fn streaming_header(sample_rate: u32, channels: u16, bits_per_sample: u16) -> Vec<u8> {
+    let mut header = Vec::new();
+    header.extend(b"RIFF");
+    header.extend(&u32::MAX.to_le_bytes());
+    header.extend(b"WAVE");
+    header.extend(fmt_chunk(sample_rate, channels, bits_per_sample));
+    header.extend(b"data");
+    header.extend(&u32::MAX.to_le_bytes());
+    header
+}
+
+impl WavAudio {
+    /// Scale the audio so its highest peak, tracked while it was written, is at `peak_dbfs`.
+    /// Only 16 bit PCM is supported.
+    pub fn normalize(&self, peak_dbfs: f64) -> io::Result<()> {
+        if self.bits_per_sample != 16 {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "Only 16 bit audio can be normalized"));
+        }
+        if self.peak == 0 {
+            return Ok(());
+        }
+        let gain = 10f64.powf(peak_dbfs / 20.0) * 32768.0 / self.peak as f64;
+        let mut file = OpenOptions::new().read(true).write(true).open(&self.path)?;
+        let mut chunk = vec![0u8; NORMALIZE_CHUNK_LENGTH];
+        let mut position = 0;
+        while position < self.data_length {
+            let length = (self.data_length - position).min(chunk.len() as u64) as usize;
+            let samples = &mut chunk[..length];
+            file.seek(SeekFrom::Start(self.data_offset + position))?;
+            file.read_exact(samples)?;
+            for sample in samples.chunks_exact_mut(2) {
+                let value = i16::from_le_bytes([sample[0], sample[1]]) as f64 * gain;
+                let value = value.round().clamp(i16::MIN as f64, i16::MAX as f64) as i16;
+                sample.copy_from_slice(&value.to_le_bytes());
+            }
+            file.seek(SeekFrom::Start(self.data_offset + position))?;
+            file.write_all(samples)?;
+            position += length as u64;
+        }
+        Ok(())
+    }
+}
+
+impl WavWriter {
+    /// Create the file and write a header for an empty data chunk; the recorder writes Broadcast Wave files
+    #[cfg(test)]
+    pub fn create<P: AsRef<Path>>(path: P, sample_rate: u32, channels: u16, bits_per_sample: u16) -> io::Result<Self> {
+        Self::create_with(path, sample_rate, channels, bits_per_sample, None, false)
+    }
+
+    /// Create a Broadcast Wave file, with a `bext` chunk in front of the data.
+    /// With `rf64` the file switches to RF64 once it no longer fits a RIFF file.
+    pub fn create_broadcast<P: AsRef<Path>>(path: P, sample_rate: u32, channels: u16, bits_per_sample: u16,
+            bext: &BroadcastExtension, rf64: bool) -> io::Result<Self> {
+        Self::create_with(path, sample_rate, channels, bits_per_sample, Some(bext), rf64)
+    }
+
+    fn create_with<P: AsRef<Path>>(path: P, sample_rate: u32, channels: u16, bits_per_sample: u16,
+            bext: Option<&BroadcastExtension>, rf64: bool) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = BufWriter::new(File::create(&path)?);
+        let block_align = channels * bits_per_sample / 8;
+        let junk_length = if rf64 { 8 + DS64_LENGTH as u64 } else { 0 };
+        let bext_offset = bext.map(|_| junk_length + HEADER_LENGTH);
+        let data_offset = match bext {
+            Some(_) => junk_length + HEADER_LENGTH + BEXT_LENGTH as u64 + 8,
+            None => junk_length + HEADER_LENGTH,
+        };
+
+        file.write_all(b"RIFF")?;
+        file.write_all(&(data_offset as u32 - 8).to_le_bytes())?;
+        file.write_all(b"WAVE")?;
+        if rf64 {
+            file.write_all(b"JUNK")?;
+            file.write_all(&DS64_LENGTH.to_le_bytes())?;
+            file.write_all(&[0u8; DS64_LENGTH as usize])?;
+        }
+        file.write_all(&fmt_chunk(sample_rate, channels, bits_per_sample))?;
+        if let Some(bext) = bext {
+            file.write_all(b"bext")?;
+            file.write_all(&BEXT_LENGTH.to_le_bytes())?;
+            file.write_all(&Self::bext_chunk(bext))?;
+        }
+        file.write_all(b"data")?;
+        file.write_all(&0u32.to_le_bytes())?;
+
+        Ok(Self {
+            file, path, block_align, bits_per_sample, data_offset, data_length: 0, bext_offset, rf64,
+            cues: Vec::new(), info: Vec::new(), trailer_stale: false, peak: 0,
+        })
+    }
+
+    fn bext_chunk(bext: &BroadcastExtension) -> Vec<u8> {
+        let mut chunk = Vec::new();
+        chunk.extend(fixed_field(&bext.description, BEXT_DESCRIPTION_LENGTH));
+        chunk.extend(fixed_field(&bext.originator, 32));
+        chunk.extend(fixed_field("", 32)); // Originator reference
+        chunk.extend(bext.origination.format("%Y-%m-%d").to_string().as_bytes());
+        chunk.extend(bext.origination.format("%H:%M:%S").to_string().as_bytes());
+        chunk.extend(&bext.time_reference.to_le_bytes()); // Low and high 32 bits
+        chunk.extend(&2u16.to_le_bytes()); // Version
+        chunk.extend([0u8; 64]); // UMID
+        chunk.extend([0u8; 10]); // Loudness values, not measured
+        chunk.extend([0u8; 180]); // Reserved
+        chunk
+    }
+
+    /// Replace the `bext` description, e.g. once the firmware version is known
+    pub fn set_description(&mut self, description: &str) -> io::Result<()> {
+        self.patch(0, &fixed_field(description, BEXT_DESCRIPTION_LENGTH))
+    }
+
+    /// Replace the `bext` time reference
+    pub fn set_time_reference(&mut self, time_reference: u64) -> io::Result<()> {
+        self.patch(BEXT_TIME_REFERENCE_OFFSET, &time_reference.to_le_bytes())
+    }
+
+    /// Replace the sample rate and byte rate in the `fmt` chunk, e.g. once the real rate is measured
+    pub fn set_sample_rate(&mut self, sample_rate: u32) -> io::Result<()> {
+        let fmt_offset = if self.rf64 { 12 + 8 + DS64_LENGTH as u64 } else { 12 };
+        self.file.seek(SeekFrom::Start(fmt_offset + 12))?;
+        self.file.write_all(&sample_rate.to_le_bytes())?;
+        self.file.write_all(&(sample_rate * self.block_align as u32).to_le_bytes())?;
+        self.file.flush()
+    }
+
+    /// Overwrite bytes at `offset` into the `bext` chunk body
+    fn patch(&mut self, offset: u64, bytes: &[u8]) -> io::Result<()> {
+        let bext_offset = self.bext_offset.ok_or_else(|| io::Error::other("Not a Broadcast Wave file"))?;
+        self.file.seek(SeekFrom::Start(bext_offset + offset))?;
+        self.file.write_all(bytes)?;
+        self.file.flush()
+    }
+
+    /// Set a `LIST INFO` tag such as `ICMT`
+    pub fn set_info(&mut self, tag: &[u8; 4], value: &str) -> io::Result<()> {
+        match self.info.iter_mut().find(|(existing, _)| existing == tag) {
+            Some((_, existing)) => *existing = value.to_string(),
+            None => self.info.push((*tag, value.to_string())),
+        }
+        self.file.seek(SeekFrom::Start(self.data_offset + self.data_length))?;
+        self.write_trailer()
+    }
+
+    /// Append little-endian PCM bytes and keep the header sizes up to date,
+    /// so a capture that is killed mid-session is still playable.
+    /// The markers and tags following the audio are written again by `finish`.
+    pub fn write_pcm(&mut self, data: &[u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(self.data_offset + self.data_length))?;
+        self.file.write_all(data)?;
+        if self.bits_per_sample == 16 {
+            for sample in data.chunks_exact(2) {
+                self.peak = self.peak.max(i16::from_le_bytes([sample[0], sample[1]]).unsigned_abs());
+            }
+        }
+        self.data_length += data.len() as u64;
+        self.trailer_stale = true;
+        self.write_sizes(self.data_length % 2)
+    }
+
+    /// Write the markers and tags behind the audio, once no more audio follows
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.trailer_stale {
+            self.file.seek(SeekFrom::Start(self.data_offset + self.data_length))?;
+            self.write_trailer()?;
+        }
+        Ok(())
+    }
+
+    /// Finish the file and return where its audio is, to normalize it
+    pub fn close(mut self) -> io::Result<WavAudio> {
+        self.finish()?;
+        Ok(WavAudio {
+            path: self.path.clone(),
+            data_offset: self.data_offset,
+            data_length: self.data_length,
+            bits_per_sample: self.bits_per_sample,
+            peak: self.peak,
+        })
+    }
+
+    /// Add a labeled cue marker at the current end of the audio
+    pub fn add_cue(&mut self, label: &str) -> io::Result<()> {
+        self.cues.push(Cue {
+            position: (self.data_length / self.block_align as u64).min(u32::MAX as u64) as u32,
+            label: label.to_string(),
+        });
+        self.file.seek(SeekFrom::Start(self.data_offset + self.data_length))?;
+        self.write_trailer()
+    }
+
+    /// Write the chunks following the audio data, then update the header sizes
+    fn write_trailer(&mut self) -> io::Result<()> {
+        let mut trailer = Vec::new();
+        if self.data_length % 2 == 1 {
+            trailer.push(0); // RIFF chunks are word aligned
+        }
+        if !self.info.is_empty() {
+            trailer.extend(self.info_chunk());
+        }
+        if !self.cues.is_empty() {
+            trailer.extend(self.cue_chunk());
+            trailer.extend(self.label_chunk());
+        }
+        self.file.write_all(&trailer)?;
+        self.file.flush()?;
+        self.file.get_ref().set_len(self.data_offset + self.data_length + trailer.len() as u64)?;
+        self.trailer_stale = false;
+        self.write_sizes(trailer.len() as u64)
+    }
+
+    /// Update the header sizes for the audio and `trailer_length` bytes following it
+    fn write_sizes(&mut self, trailer_length: u64) -> io::Result<()> {
+        let riff_length = self.data_offset - 8 + self.data_length + trailer_length;
+        self.file.seek(SeekFrom::Start(0))?;
+        if riff_length <= u32::MAX as u64 {
+            self.file.write_all(b"RIFF")?;
+            self.file.write_all(&(riff_length as u32).to_le_bytes())?;
+            self.file.seek(SeekFrom::Start(self.data_offset - 4))?;
+            self.file.write_all(&(self.data_length as u32).to_le_bytes())?;
+        } else if self.rf64 {
+            // EBU Tech 3306: the 32 bit sizes are -1 and the real ones live in ds64
+            self.file.write_all(b"RF64")?;
+            self.file.write_all(&u32::MAX.to_le_bytes())?;
+            self.file.seek(SeekFrom::Start(12))?;
+            self.file.write_all(b"ds64")?;
+            self.file.write_all(&DS64_LENGTH.to_le_bytes())?;
+            self.file.write_all(&riff_length.to_le_bytes())?;
+            self.file.write_all(&self.data_length.to_le_bytes())?;
+            self.file.write_all(&(self.data_length / self.block_align as u64).to_le_bytes())?;
+            self.file.write_all(&0u32.to_le_bytes())?; // No table entries
+            self.file.seek(SeekFrom::Start(self.data_offset - 4))?;
+            self.file.write_all(&u32::MAX.to_le_bytes())?;
+        } else {
+            return Err(io::Error::other("Audio does not fit a RIFF file, use RF64 or split the recording"));
+        }
+        self.file.flush()
+    }
+
+    /// `LIST INFO` chunk with the text tags
+    fn info_chunk(&self) -> Vec<u8> {
+        let mut tags = Vec::new();
+        for (tag, value) in &self.info {
+            let text_length = value.len() as u32 + 1; // Null terminated
+            tags.extend(tag);
+            tags.extend(&text_length.to_le_bytes());
+            tags.extend(value.as_bytes());
+            tags.push(0);
+            if text_length % 2 == 1 {
+                tags.push(0);
+            }
+        }
+        let mut chunk = Vec::new();
+        chunk.extend(b"LIST");
+        chunk.extend(&(4 + tags.len() as u32).to_le_bytes());
+        chunk.extend(b"INFO");
+        chunk.extend(tags);
+        chunk
+    }
+
+    /// `cue ` chunk with one cue point per marker
+    fn cue_chunk(&self) -> Vec<u8> {
+        let mut chunk = Vec::new();
+        chunk.extend(b"cue ");
+        chunk.extend(&(4 + 24 * self.cues.len() as u32).to_le_bytes());
+        chunk.extend(&(self.cues.len() as u32).to_le_bytes());
+        for (i, cue) in self.cues.iter().enumerate() {
+            chunk.extend(&(i as u32 + 1).to_le_bytes()); // cue point id
+            chunk.extend(&cue.position.to_le_bytes());
+            chunk.extend(b"data");
+            chunk.extend(&0u32.to_le_bytes()); // chunk start
+            chunk.extend(&0u32.to_le_bytes()); // block start
+            chunk.extend(&cue.position.to_le_bytes()); // sample offset
+        }
+        chunk
+    }
+
+    /// `LIST adtl` chunk with the label text of each cue point
+    fn label_chunk(&self) -> Vec<u8> {
+        let mut labels = Vec::new();
+        for (i, cue) in self.cues.iter().enumerate() {
+            let text_length = cue.label.len() as u32 + 1; // Null terminated
+            labels.extend(b"labl");
+            labels.extend(&(4 + text_length).to_le_bytes());
+            labels.extend(&(i as u32 + 1).to_le_bytes());
+            labels.extend(cue.label.as_bytes());
+            labels.push(0);
+            if text_length % 2 == 1 {
+                labels.push(0);
+            }
+        }
+        let mut chunk = Vec::new();
+        chunk.extend(b"LIST");
+        chunk.extend(&(4 + labels.len() as u32).to_le_bytes());
+        chunk.extend(b"adtl");
+        chunk.extend(labels);
+        chunk
+    }
+}
+
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            console::report_error(format_args!("Failed to finish the wav file: {}", e));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_utils;
+
+    #[test]
+    fn test_wav_header() {
+        let path = std::env::temp_dir().join("serial2wave_test_wav_header.wav");
+        let mut writer = WavWriter::create(&path, 48_000, 1, 16).expect("Failed to create wav");
+        writer.write_pcm(&[1, 0, 2, 0]).unwrap();
+        writer.write_pcm(&[3, 0]).unwrap();
+        drop(writer);
+
+        let bytes = test_utils::read_file_as_bytes(&path).unwrap();
+        let mut writer = WavWriter::create(&path, 48_000, 1, 16).expect("Failed to create wav");
+        writer.set_sample_rate(16_000).unwrap();
+        drop(writer);
+        let corrected = test_utils::read_file_as_bytes(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(u32::from_le_bytes(corrected[24..28].try_into().unwrap()), 16_000);
+        assert_eq!(u32::from_le_bytes(corrected[28..32].try_into().unwrap()), 32_000, "Byte rate follows the sample rate");
+
+        assert_eq!(bytes.len(), 50);
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(bytes[4..8].try_into().unwrap()), 42, "RIFF size should cover the data");
+        assert_eq!(u32::from_le_bytes(bytes[24..28].try_into().unwrap()), 48_000);
+        assert_eq!(u32::from_le_bytes(bytes[28..32].try_into().unwrap()), 96_000, "Byte rate of 16 bit mono");
+        assert_eq!(&bytes[36..40], b"data");
+        assert_eq!(u32::from_le_bytes(bytes[40..44].try_into().unwrap()), 6);
+        assert_eq!(&bytes[44..], &[1, 0, 2, 0, 3, 0]);
+    }
+
+    #[test]
+    fn test_wav_cues() {
+        let path = std::env::temp_dir().join("serial2wave_test_wav_cues.wav");
+        let mut writer = WavWriter::create(&path, 48_000, 1, 16).expect("Failed to create wav");
+        writer.write_pcm(&[1, 0, 2, 0]).unwrap();
+        writer.add_cue("app_init").unwrap();
+        writer.write_pcm(&[3, 0]).unwrap();
+        let unfinished = test_utils::read_file_as_bytes(&path).unwrap();
+        assert_eq!(u32::from_le_bytes(unfinished[4..8].try_into().unwrap()), 42, "Until the file is finished, RIFF size covers the audio only");
+        assert_eq!(u32::from_le_bytes(unfinished[40..44].try_into().unwrap()), 6);
+        drop(writer);
+
+        let bytes = test_utils::read_file_as_bytes(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize, bytes.len() - 8, "RIFF size should cover the markers");
+        assert_eq!(u32::from_le_bytes(bytes[40..44].try_into().unwrap()), 6);
+        assert_eq!(&bytes[44..50], &[1, 0, 2, 0, 3, 0], "Audio written after the cue stays in the data chunk");
+
+        let cue = &bytes[50..];
+        assert_eq!(&cue[0..4], b"cue ");
+        assert_eq!(u32::from_le_bytes(cue[4..8].try_into().unwrap()), 28);
+        assert_eq!(u32::from_le_bytes(cue[8..12].try_into().unwrap()), 1, "One cue point");
+        assert_eq!(u32::from_le_bytes(cue[16..20].try_into().unwrap()), 2, "Cue at the third sample");
+
+        let list = &cue[36..];
+        assert_eq!(&list[0..4], b"LIST");
+        assert_eq!(&list[8..12], b"adtl");
+        assert_eq!(&list[12..16], b"labl");
+        assert_eq!(&list[24..33], b"app_init\0");
+        assert_eq!(list.len(), 34, "Label is padded to an even length");
+    }
+
+    #[test]
+    fn test_broadcast_wave() {
+        let path = std::env::temp_dir().join("serial2wave_test_broadcast_wave.wav");
+        let bext = BroadcastExtension {
+            description: "port=/dev/ttyACM0".to_string(),
+            originator: "Serial2Wave".to_string(),
+            origination: Local::now(),
+            time_reference: 0,
+        };
+        let mut writer = WavWriter::create_broadcast(&path, 48_000, 1, 16, &bext, false).expect("Failed to create wav");
+        writer.write_pcm(&[1, 0]).unwrap();
+        writer.set_description("port=/dev/ttyACM0; REV_INFO=03fa2ba-dirty:open_source").unwrap();
+        writer.set_time_reference(48_000 * 3600).unwrap();
+        writer.set_info(b"ICMT", "first").unwrap();
+        writer.set_info(b"ICMT", "REV_INFO").unwrap();
+        writer.set_info(b"ISFT", "Serial2Wave").unwrap();
+        writer.write_pcm(&[2, 0]).unwrap();
+        drop(writer);
+
+        let bytes = test_utils::read_file_as_bytes(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize, bytes.len() - 8);
+        assert_eq!(&bytes[36..40], b"bext");
+        assert_eq!(u32::from_le_bytes(bytes[40..44].try_into().unwrap()), 602);
+        assert!(bytes[44..300].starts_with(b"port=/dev/ttyACM0; REV_INFO=03fa2ba-dirty:open_source\0"));
+        assert_eq!(&bytes[300..311], b"Serial2Wave");
+        assert_eq!(&bytes[364..374], bext.origination.format("%Y-%m-%d").to_string().as_bytes());
+        assert_eq!(u64::from_le_bytes(bytes[382..390].try_into().unwrap()), 48_000 * 3600, "One hour after midnight");
+        assert_eq!(u16::from_le_bytes(bytes[390..392].try_into().unwrap()), 2, "bext version");
+
+        assert_eq!(&bytes[646..650], b"data");
+        assert_eq!(u32::from_le_bytes(bytes[650..654].try_into().unwrap()), 4);
+        assert_eq!(&bytes[654..658], &[1, 0, 2, 0]);
+
+        let list = &bytes[658..];
+        assert_eq!(&list[0..4], b"LIST");
+        assert_eq!(&list[8..12], b"INFO");
+        assert_eq!(&list[12..16], b"ICMT");
+        assert_eq!(&list[20..29], b"REV_INFO\0", "Tags are replaced, not repeated");
+        assert_eq!(&list[30..34], b"ISFT");
+    }
+
+    #[test]
+    fn test_normalize() {
+        let path = std::env::temp_dir().join("serial2wave_test_normalize.wav");
+        let mut writer = WavWriter::create(&path, 48_000, 1, 16).expect("Failed to create wav");
+        let samples: Vec<u8> = [1000i16, -4000, 2000].iter().flat_map(|sample| sample.to_le_bytes()).collect();
+        writer.write_pcm(&samples.repeat(NORMALIZE_CHUNK_LENGTH / 4)).unwrap();
+        writer.add_cue("after").unwrap();
+        writer.close().unwrap().normalize(-6.0).unwrap();
+        let bytes = test_utils::read_file_as_bytes(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let sample = |index: usize| i16::from_le_bytes(bytes[44 + index * 2..][..2].try_into().unwrap());
+        assert_eq!([sample(0), sample(1), sample(2)], [4106, -16423, 8211], "Peak at -6 dBFS");
+        let last = NORMALIZE_CHUNK_LENGTH / 4 * 3 - 1;
+        assert_eq!(sample(last), 8211, "Every chunk is scaled");
+        assert_eq!(&bytes[44 + (last + 1) * 2..][..4], b"cue ", "Trailer is left alone");
+    }
+
+    #[test]
+    fn test_rf64() {
+        let path = std::env::temp_dir().join("serial2wave_test_rf64.wav");
+        let bext = BroadcastExtension {
+            description: String::new(),
+            originator: "Serial2Wave".to_string(),
+            origination: Local::now(),
+            time_reference: 0,
+        };
+        let mut writer = WavWriter::create_broadcast(&path, 48_000, 1, 16, &bext, true).expect("Failed to create wav");
+        writer.write_pcm(&[1, 0]).unwrap();
+        let bytes = test_utils::read_file_as_bytes(&path).unwrap();
+        assert_eq!(&bytes[0..4], b"RIFF", "Small files stay plain RIFF");
+        assert_eq!(&bytes[12..16], b"JUNK");
+        assert_eq!(&bytes[48..52], b"fmt ");
+        assert_eq!(&bytes[72..76], b"bext");
+        assert_eq!(&bytes[682..686], b"data");
+        assert_eq!(u32::from_le_bytes(bytes[686..690].try_into().unwrap()), 2);
+
+        // Pretend 4 GiB were written; the file is sparse, so this costs no disk space
+        writer.data_length = 1 << 32;
+        writer.write_pcm(&[2, 0]).unwrap();
+        drop(writer);
+        let mut header = [0u8; 690];
+        io::Read::read_exact(&mut File::open(&path).unwrap(), &mut header).unwrap();
+        let file_length = std::fs::metadata(&path).unwrap().len();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(&header[0..4], b"RF64");
+        assert_eq!(u32::from_le_bytes(header[4..8].try_into().unwrap()), u32::MAX);
+        assert_eq!(&header[12..16], b"ds64");
+        assert_eq!(u64::from_le_bytes(header[20..28].try_into().unwrap()), file_length - 8, "RIFF size");
+        assert_eq!(u64::from_le_bytes(header[28..36].try_into().unwrap()), (1 << 32) + 2, "data size");
+        assert_eq!(u64::from_le_bytes(header[36..44].try_into().unwrap()), ((1 << 32) + 2) / 2, "sample count");
+        assert_eq!(u32::from_le_bytes(header[686..690].try_into().unwrap()), u32::MAX);
+    }
+}
@@ -0,0 +1,342 @@
+use std::io;
+use std::path::PathBuf;
+use clap::{Parser, Subcommand};
+use crate::analysis::tone_test::ToneTestSettings;
+use crate::audio::stream::StreamFormat;
+use crate::config::settings::{AudioFormat, Settings};
+use crate::dsp::chain::{AudioSink, ProcessorSettings};
+use crate::logs::filter::{FilterAction, FilterRuleSettings};
+use crate::logs::line_parser::LogRuleSettings;
+use crate::recorder::trigger::{TriggerAction, TriggerSettings};
+
+/// Receive PineBuds serial data and write it to wav and log files
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// TOML config file; command line options override its values
+    #[arg(short, long, global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Serial port to read from
+    #[arg(short, long, global = true)]
+    pub port: Option<String>,
+
+    /// Serial port baud rate
+    #[arg(short, long, global = true)]
+    pub baudrate: Option<u32>,
+
+    /// Directory the recordings are written to
+    #[arg(short, long)]
+    pub output_dir: Option<PathBuf>,
+
+    /// Only print log lines matching PATTERN, a substring or /regex/
+    #[arg(long, value_name = "PATTERN")]
+    pub include: Vec<String>,
+
+    /// Do not print log lines matching PATTERN
+    #[arg(long, value_name = "PATTERN")]
+    pub exclude: Vec<String>,
+
+    /// Color log lines matching PATTERN, e.g. red:app_init
+    #[arg(long, value_name = "[COLOR:]PATTERN")]
+    pub highlight: Vec<String>,
+
+    /// Extract the named groups of REGEX into the .jsonl records; repeat for several
+    #[arg(long, value_name = "REGEX")]
+    pub log_rule: Vec<String>,
+
+    /// Only apply the --log-rule and config file rules, not the built-in ones
+    #[arg(long)]
+    pub no_default_log_rules: bool,
+
+    /// Record audio only from the log line matching PATTERN on
+    #[arg(long, value_name = "PATTERN")]
+    pub start_on: Vec<String>,
+
+    /// Stop recording audio at the log line matching PATTERN
+    #[arg(long, value_name = "PATTERN")]
+    pub stop_on: Vec<String>,
+
+    /// Mark the recording at log lines matching PATTERN
+    #[arg(long, value_name = "PATTERN")]
+    pub mark_on: Vec<String>,
+
+    /// Seconds of audio kept from before a start trigger
+    #[arg(long, value_name = "SECONDS")]
+    pub pre_roll: Option<f64>,
+
+    /// Seconds of audio still recorded after a stop trigger
+    #[arg(long, value_name = "SECONDS")]
+    pub post_roll: Option<f64>,
+
+    /// Audio file format; repeat to write several, e.g. --format wav --format flac
+    #[arg(long, value_enum)]
+    pub format: Vec<AudioFormat>,
+
+    /// Write RF64 files that can grow past 4 GiB, instead of splitting there
+    #[arg(long)]
+    pub rf64: bool,
+
+    /// Continue the audio in a new file every SECONDS
+    #[arg(long, value_name = "SECONDS")]
+    pub split_duration: Option<f64>,
+
+    /// Continue the audio in a new file before it exceeds MB megabytes
+    #[arg(long, value_name = "MB")]
+    pub split_size: Option<u64>,
+
+    /// Stream live audio to TARGET, - for stdout or a FIFO path
+    #[arg(long, value_name = "TARGET")]
+    pub stream: Option<String>,
+
+    /// Framing of the live audio stream
+    #[arg(long, value_enum)]
+    pub stream_format: Option<StreamFormat>,
+
+    /// Send live audio as L16 RTP packets to HOST:PORT, described by an .sdp file next to the recordings
+    #[arg(long, value_name = "HOST:PORT")]
+    pub rtp: Option<String>,
+
+    /// Serve /audio.wav, /log and /status on ADDRESS, e.g. 127.0.0.1:8080 or 0.0.0.0:8080 for the LAN
+    #[arg(long, value_name = "ADDRESS")]
+    pub http: Option<String>,
+
+    /// Print log lines and status messages to FILE instead of the terminal
+    #[arg(long, value_name = "FILE")]
+    pub console_log: Option<PathBuf>,
+
+    /// Print a line per received audio frame
+    #[arg(short, long)]
+    pub verbose: bool,
+
+    /// Remove the firmware's ANSI colors instead of rendering them in the terminal
+    #[arg(long)]
+    pub strip_ansi: bool,
+
+    /// Sample rate of the received audio in Hz
+    #[arg(long, value_name = "HZ", global = true)]
+    pub sample_rate: Option<u32>,
+
+    /// Measure the sample rate from the frame rate at startup
+    #[arg(long, global = true)]
+    pub infer_sample_rate: bool,
+
+    /// Correct the sample rate in the wav header when the frames arrive at another rate
+    #[arg(long)]
+    pub use_measured_rate: bool,
+
+    /// Convert the processed audio to HZ, e.g. 48000
+    #[arg(long, value_name = "HZ")]
+    pub resample: Option<u32>,
+
+    /// Remove DC offset from the audio
+    #[arg(long)]
+    pub dc_block: bool,
+
+    /// High-pass filter the audio at HZ
+    #[arg(long, value_name = "HZ")]
+    pub high_pass: Option<f64>,
+
+    /// Low-pass filter the audio at HZ
+    #[arg(long, value_name = "HZ")]
+    pub low_pass: Option<f64>,
+
+    /// Amplify the audio by DB, negative to attenuate
+    #[arg(long, value_name = "DB", allow_negative_numbers = true)]
+    pub gain: Option<f64>,
+
+    /// Scale each processed wav file to a peak of DBFS when it is closed
+    #[arg(long, value_name = "DBFS", allow_negative_numbers = true)]
+    pub normalize: Option<f64>,
+
+    /// Round processed audio to 16 bit without dither
+    #[arg(long)]
+    pub no_dither: bool,
+
+    /// Give SINK the audio as received instead of processed; repeat for several
+    #[arg(long, value_enum, value_name = "SINK")]
+    pub raw: Vec<AudioSink>,
+
+    /// Render a PNG spectrogram of the session
+    #[arg(long)]
+    pub spectrogram: bool,
+
+    /// Full-screen terminal UI with level meters, frame statistics and a searchable log
+    #[arg(long)]
+    pub tui: bool,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Record a test tone, check it against the limits and exit with 0 on pass, 1 on fail
+    ToneTest(ToneTestArgs),
+}
+
+/// Limits of `tone-test`; unset ones come from `[tone_test]` in the config file
+#[derive(clap::Args, Debug)]
+pub struct ToneTestArgs {
+    /// Seconds of audio measured
+    #[arg(long)]
+    pub seconds: Option<f64>,
+
+    /// Seconds of audio skipped first
+    #[arg(long, value_name = "SECONDS")]
+    pub settle: Option<f64>,
+
+    /// Expected tone frequency in Hz
+    #[arg(long, value_name = "HZ")]
+    pub frequency: Option<f64>,
+
+    /// Largest frequency error in Hz
+    #[arg(long, value_name = "HZ")]
+    pub frequency_tolerance: Option<f64>,
+
+    /// Lowest RMS level of the tone
+    #[arg(long, value_name = "DBFS", allow_negative_numbers = true)]
+    pub min_level: Option<f64>,
+
+    /// Highest RMS level of the tone
+    #[arg(long, value_name = "DBFS", allow_negative_numbers = true)]
+    pub max_level: Option<f64>,
+
+    /// Lowest signal to noise ratio
+    #[arg(long, value_name = "DB")]
+    pub min_snr: Option<f64>,
+
+    /// Highest total harmonic distortion, 2nd to 5th harmonic
+    #[arg(long, value_name = "PERCENT")]
+    pub max_thd: Option<f64>,
+
+    /// Most audio frames that may be lost
+    #[arg(long, value_name = "FRAMES")]
+    pub max_lost_frames: Option<u64>,
+
+    /// Also write the JSON report to FILE
+    #[arg(long, value_name = "FILE")]
+    pub report: Option<PathBuf>,
+}
+
+impl ToneTestArgs {
+    fn apply(&self, settings: &mut ToneTestSettings) {
+        let options = [
+            (self.seconds, &mut settings.seconds),
+            (self.settle, &mut settings.settle_seconds),
+            (self.frequency, &mut settings.frequency),
+            (self.frequency_tolerance, &mut settings.frequency_tolerance),
+            (self.min_level, &mut settings.min_level_dbfs),
+            (self.max_level, &mut settings.max_level_dbfs),
+            (self.min_snr, &mut settings.min_snr_db),
+            (self.max_thd, &mut settings.max_thd_percent),
+        ];
+        for (option, setting) in options {
+            if let Some(value) = option {
+                *setting = value;
+            }
+        }
+        if let Some(max_lost_frames) = self.max_lost_frames {
+            settings.max_lost_frames = max_lost_frames;
+        }
+    }
+}
+
+impl Args {
+    /// Load the config file, if any, and apply the command line options on top of it
+    pub fn settings(&self) -> io::Result<Settings> {
+        let mut settings = match &self.config {
+            Some(path) => Settings::load(path)?,
+            None => Settings::default(),
+        };
+
+        if let Some(port) = &self.port {
+            settings.port = port.clone();
+        }
+        if let Some(baudrate) = self.baudrate {
+            settings.baudrate = baudrate;
+        }
+        if let Some(output_dir) = &self.output_dir {
+            settings.output_dir = output_dir.clone();
+        }
+
+        let rule = |action, pattern: &String| FilterRuleSettings { action, pattern: pattern.clone(), color: None };
+        settings.log_filter.extend(self.include.iter().map(|pattern| rule(FilterAction::Include, pattern)));
+        settings.log_filter.extend(self.exclude.iter().map(|pattern| rule(FilterAction::Exclude, pattern)));
+        settings.log_filter.extend(self.highlight.iter().map(|value| FilterRuleSettings::highlight(value)));
+
+        settings.log_rule.extend(self.log_rule.iter().map(|pattern| LogRuleSettings { pattern: pattern.clone() }));
+        settings.default_log_rules &= !self.no_default_log_rules;
+
+        let trigger = |action, pattern: &String| TriggerSettings { action, pattern: pattern.clone() };
+        settings.trigger.extend(self.start_on.iter().map(|pattern| trigger(TriggerAction::Start, pattern)));
+        settings.trigger.extend(self.stop_on.iter().map(|pattern| trigger(TriggerAction::Stop, pattern)));
+        settings.trigger.extend(self.mark_on.iter().map(|pattern| trigger(TriggerAction::Mark, pattern)));
+        if let Some(pre_roll) = self.pre_roll {
+            settings.pre_roll_seconds = pre_roll;
+        }
+        if let Some(post_roll) = self.post_roll {
+            settings.post_roll_seconds = post_roll;
+        }
+        if !self.format.is_empty() {
+            settings.formats = self.format.clone();
+        }
+        settings.rf64 |= self.rf64;
+        if let Some(split_duration) = self.split_duration {
+            settings.split_seconds = Some(split_duration);
+        }
+        if let Some(split_size) = self.split_size {
+            settings.split_megabytes = Some(split_size);
+        }
+        if let Some(stream) = &self.stream {
+            settings.stream = Some(stream.clone());
+        }
+        if let Some(stream_format) = self.stream_format {
+            settings.stream_format = stream_format;
+        }
+        if let Some(rtp) = &self.rtp {
+            settings.rtp = Some(rtp.clone());
+        }
+        if let Some(http) = &self.http {
+            settings.http = Some(http.clone());
+        }
+        if let Some(console_log) = &self.console_log {
+            settings.console_log = Some(console_log.clone());
+        }
+        settings.verbose |= self.verbose;
+        settings.strip_ansi |= self.strip_ansi;
+        settings.tui |= self.tui;
+        if let Some(sample_rate) = self.sample_rate {
+            settings.sample_rate = sample_rate;
+        }
+        settings.infer_sample_rate |= self.infer_sample_rate;
+        settings.use_measured_sample_rate |= self.use_measured_rate;
+        if let Some(resample) = self.resample {
+            settings.resample = Some(resample);
+        }
+        if self.dc_block {
+            settings.dsp.push(ProcessorSettings::dc_blocker());
+        }
+        if let Some(frequency) = self.high_pass {
+            settings.dsp.push(ProcessorSettings::high_pass(frequency));
+        }
+        if let Some(frequency) = self.low_pass {
+            settings.dsp.push(ProcessorSettings::low_pass(frequency));
+        }
+        if let Some(db) = self.gain {
+            settings.dsp.push(ProcessorSettings::Gain { db });
+        }
+        if let Some(normalize) = self.normalize {
+            settings.normalize_dbfs = Some(normalize);
+        }
+        settings.dither &= !self.no_dither;
+        settings.raw.extend(&self.raw);
+        settings.analysis.spectrogram |= self.spectrogram;
+        if let Some(Command::ToneTest(tone_test)) = &self.command {
+            tone_test.apply(&mut settings.tone_test);
+        }
+
+        settings.validate()?;
+        Ok(settings)
+    }
+}
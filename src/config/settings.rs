@@ -0,0 +1,228 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use serde::Deserialize;
+use crate::analysis::analyzer::AnalysisSettings;
+use crate::analysis::tone_test::ToneTestSettings;
+use crate::audio::stream::{self, StreamFormat};
+use crate::constants::common;
+use crate::dsp::chain::{AudioSink, ProcessorSettings};
+use crate::logs::filter::FilterRuleSettings;
+use crate::logs::line_parser::LogRuleSettings;
+use crate::receiver::console::ConsoleTarget;
+use crate::recorder::session::AudioFileOptions;
+use crate::recorder::trigger::TriggerSettings;
+
+/// Sample rates the receiver takes, for the received audio and for resampling
+const SAMPLE_RATE_RANGE: std::ops::RangeInclusive<u32> = 1_000..=384_000;
+
+/// File format the audio is written in
+#[derive(Deserialize, clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioFormat {
+    Wav,
+    Flac,
+}
+
+/// Receiver settings, read from a TOML config file. Missing keys keep their defaults.
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub port: String,
+    pub baudrate: u32,
+    pub output_dir: PathBuf,
+    pub log_filter: Vec<FilterRuleSettings>,
+    /// Regexes whose named groups become fields of the `.jsonl` records, tried before the default rules
+    pub log_rule: Vec<LogRuleSettings>,
+    /// Also apply the built-in rules for the BES firmware log format
+    pub default_log_rules: bool,
+    pub trigger: Vec<TriggerSettings>,
+    pub pre_roll_seconds: f64,
+    pub post_roll_seconds: f64,
+    /// Audio files written for each boot
+    pub formats: Vec<AudioFormat>,
+    /// Write RF64 files instead of splitting the audio at 4 GiB
+    pub rf64: bool,
+    pub split_seconds: Option<f64>,
+    pub split_megabytes: Option<u64>,
+    /// Live audio stream target, `-` for stdout or a FIFO path
+    pub stream: Option<String>,
+    pub stream_format: StreamFormat,
+    /// RTP destination of the live audio, `host:port`
+    pub rtp: Option<String>,
+    /// Address of the HTTP server with the live audio, log and status, e.g. `127.0.0.1:8080`
+    pub http: Option<String>,
+    /// File the console output goes to instead of the terminal
+    pub console_log: Option<PathBuf>,
+    /// Print a line per received audio frame
+    pub verbose: bool,
+    /// Remove the firmware's ANSI colors instead of rendering them in the terminal
+    pub strip_ansi: bool,
+    /// Show the full-screen terminal UI instead of printing lines
+    pub tui: bool,
+    /// Sample rate of the received audio
+    pub sample_rate: u32,
+    /// Measure the sample rate from the frame rate at startup instead of using `sample_rate`
+    pub infer_sample_rate: bool,
+    /// Sample rate the processed audio is converted to
+    pub resample: Option<u32>,
+    /// Correct the wav header when the frames arrive at another rate than `sample_rate`
+    pub use_measured_sample_rate: bool,
+    /// Sample rate measured at startup, if it was
+    #[serde(skip)]
+    pub measured_sample_rate: Option<f64>,
+    /// Processors the audio runs through, in order
+    pub dsp: Vec<ProcessorSettings>,
+    /// Add dither when the processed audio is rounded back to 16 bit
+    pub dither: bool,
+    /// Peak level each processed wav file is scaled to when it is closed
+    pub normalize_dbfs: Option<f64>,
+    /// Sinks that get the audio as received, bit-exact
+    pub raw: Vec<AudioSink>,
+    pub analysis: AnalysisSettings,
+    pub tone_test: ToneTestSettings,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            port: common::SERIAL_PORT.to_string(),
+            baudrate: common::BAUDRATE,
+            output_dir: PathBuf::from(common::OUTPUT_DIR),
+            log_filter: Vec::new(),
+            log_rule: Vec::new(),
+            default_log_rules: true,
+            trigger: Vec::new(),
+            pre_roll_seconds: common::PRE_ROLL_SECONDS,
+            post_roll_seconds: common::POST_ROLL_SECONDS,
+            formats: vec![AudioFormat::Wav],
+            rf64: false,
+            split_seconds: None,
+            split_megabytes: None,
+            stream: None,
+            stream_format: StreamFormat::Wav,
+            rtp: None,
+            http: None,
+            console_log: None,
+            verbose: false,
+            strip_ansi: false,
+            tui: false,
+            sample_rate: common::SAMPLE_RATE,
+            infer_sample_rate: false,
+            use_measured_sample_rate: false,
+            resample: None,
+            measured_sample_rate: None,
+            dsp: Vec::new(),
+            dither: true,
+            normalize_dbfs: None,
+            raw: Vec::new(),
+            analysis: AnalysisSettings::default(),
+            tone_test: ToneTestSettings::default(),
+        }
+    }
+}
+
+impl Settings {
+    /// Reject values that make no sense together with others or on their own
+    pub fn validate(&self) -> io::Result<()> {
+        let valid = |rate: u32| SAMPLE_RATE_RANGE.contains(&rate);
+        if !valid(self.sample_rate) || self.resample.is_some_and(|rate| !valid(rate)) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Sample rates must be from {} to {} Hz",
+                SAMPLE_RATE_RANGE.start(), SAMPLE_RATE_RANGE.end())));
+        }
+        if self.use_measured_sample_rate && self.resample.is_some() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "--use-measured-rate cannot be combined with --resample"));
+        }
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let text = fs::read_to_string(&path)?;
+        toml::from_str(&text).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.as_ref().display(), e))
+        })
+    }
+
+    /// Where log lines and status messages are printed; never stdout while audio streams there
+    pub fn console_target(&self) -> ConsoleTarget {
+        match (&self.console_log, self.stream.as_deref()) {
+            (Some(path), _) => ConsoleTarget::File(path.clone()),
+            (None, Some(stream::STDOUT_TARGET)) => ConsoleTarget::Stderr,
+            (None, _) => ConsoleTarget::Stdout,
+        }
+    }
+
+    /// How the recorder writes and splits wav files
+    pub fn audio_file_options(&self) -> AudioFileOptions {
+        AudioFileOptions {
+            wav: self.formats.contains(&AudioFormat::Wav),
+            flac: self.formats.contains(&AudioFormat::Flac),
+            rf64: self.rf64,
+            split_seconds: self.split_seconds,
+            split_bytes: self.split_megabytes.map(|megabytes| megabytes * 1_000_000),
+            processed_wav: self.is_processed(AudioSink::Wav),
+            processed_flac: self.is_processed(AudioSink::Flac),
+            normalize_dbfs: self.normalize_dbfs,
+            sample_rate: self.sample_rate,
+            output_sample_rate: self.output_sample_rate(),
+        }
+    }
+
+    /// Sample rate of the processed audio
+    pub fn output_sample_rate(&self) -> u32 {
+        self.resample.unwrap_or(self.sample_rate)
+    }
+
+    /// Sample rate of the audio a sink gets
+    pub fn sink_sample_rate(&self, sink: AudioSink) -> u32 {
+        if self.is_processed(sink) { self.output_sample_rate() } else { self.sample_rate }
+    }
+
+    /// Whether a sink gets the processed audio
+    pub fn is_processed(&self, sink: AudioSink) -> bool {
+        !self.raw.contains(&sink)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logs::filter::FilterAction;
+    use crate::recorder::trigger::TriggerAction;
+
+    #[test]
+    fn test_settings() {
+        let settings: Settings = toml::from_str(r#"
+            port = "/dev/ttyACM0"
+
+            [[log_filter]]
+            action = "exclude"
+            pattern = "aaf_stream_store"
+
+            [[log_filter]]
+            action = "highlight"
+            pattern = '/^\[af_stream/'
+            color = "cyan"
+
+            [[trigger]]
+            action = "start"
+            pattern = "app_init"
+
+            [[log_rule]]
+            pattern = '(?P<battery>\d+)%'
+        "#).unwrap();
+
+        assert_eq!(settings.port, "/dev/ttyACM0");
+        assert_eq!(settings.baudrate, common::BAUDRATE, "Missing keys keep their defaults");
+        assert_eq!(settings.log_filter.len(), 2);
+        assert_eq!(settings.log_filter[0].action, FilterAction::Exclude);
+        assert_eq!(settings.log_filter[1].pattern, r"/^\[af_stream/");
+        assert_eq!(settings.log_filter[1].color.as_deref(), Some("cyan"));
+        assert_eq!(settings.trigger.len(), 1);
+        assert_eq!(settings.trigger[0].action, TriggerAction::Start);
+        assert_eq!(settings.log_rule[0].pattern, r"(?P<battery>\d+)%");
+        assert!(settings.default_log_rules);
+
+        assert!(toml::from_str::<Settings>("baud = 115200").is_err(), "Typos should not be silently ignored");
+    }
+}
@@ -3,3 +3,17 @@ pub const SERIAL_READ_SIZE: usize = 8192;
 pub const BAUDRATE: u32 = 2_000_000;
 pub const PACKET_LENGTH: usize = 4012;
 pub const TARGET_SEQUENCE: [u8; 8] = [0xFF, 0x01, 0xFF, 0x02, 0xFF, 0x03, 0xFF, 0x04];
+pub const AUDIO_PAYLOAD_LENGTH: usize = 4000;
+pub const FRAME_NUMBER_LENGTH: usize = 4;
+pub const SAMPLE_RATE: u32 = 48_000;
+pub const CHANNELS: u16 = 1;
+pub const BITS_PER_SAMPLE: u16 = 16;
+pub const BOOT_BANNER_MARKER: &[u8] = b"CHIP="; // First line of the firmware boot banner
+pub const OUTPUT_DIR: &str = "recordings";
+pub const PRE_ROLL_SECONDS: f64 = 2.0; // Audio kept from before a start trigger
+pub const POST_ROLL_SECONDS: f64 = 2.0; // Audio still recorded after a stop trigger
+
+/// Audio duration of one frame at `sample_rate`
+pub fn frame_seconds(sample_rate: u32) -> f64 {
+    AUDIO_PAYLOAD_LENGTH as f64 / (sample_rate as f64 * (CHANNELS * BITS_PER_SAMPLE / 8) as f64)
+}
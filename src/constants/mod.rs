@@ -1 +1 @@
-pub mod common;
\ No newline at end of file
+pub mod common;
@@ -0,0 +1,195 @@
+use serde::Deserialize;
+use crate::analysis::levels;
+use crate::constants::common;
+use super::filters::{Biquad, DcBlocker, Gain, Processor};
+use super::resampler::Resampler;
+
+/// Butterworth response of a single biquad
+const DEFAULT_Q: f64 = std::f64::consts::FRAC_1_SQRT_2;
+const DEFAULT_DC_BLOCKER_CUTOFF: f64 = 5.0;
+
+fn default_q() -> f64 {
+    DEFAULT_Q
+}
+
+fn default_dc_blocker_cutoff() -> f64 {
+    DEFAULT_DC_BLOCKER_CUTOFF
+}
+
+/// A processing step as written in the `[[dsp]]` tables of the config file
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum ProcessorSettings {
+    DcBlocker {
+        #[serde(default = "default_dc_blocker_cutoff")]
+        cutoff: f64,
+    },
+    HighPass {
+        frequency: f64,
+        #[serde(default = "default_q")]
+        q: f64,
+    },
+    LowPass {
+        frequency: f64,
+        #[serde(default = "default_q")]
+        q: f64,
+    },
+    Gain { db: f64 },
+}
+
+impl ProcessorSettings {
+    pub fn dc_blocker() -> Self {
+        Self::DcBlocker { cutoff: DEFAULT_DC_BLOCKER_CUTOFF }
+    }
+
+    pub fn high_pass(frequency: f64) -> Self {
+        Self::HighPass { frequency, q: DEFAULT_Q }
+    }
+
+    pub fn low_pass(frequency: f64) -> Self {
+        Self::LowPass { frequency, q: DEFAULT_Q }
+    }
+
+    fn processor(&self, sample_rate: u32) -> Box<dyn Processor> {
+        match *self {
+            Self::DcBlocker { cutoff } => Box::new(DcBlocker::new(cutoff, sample_rate)),
+            Self::HighPass { frequency, q } => Box::new(Biquad::high_pass(frequency, q, sample_rate)),
+            Self::LowPass { frequency, q } => Box::new(Biquad::low_pass(frequency, q, sample_rate)),
+            Self::Gain { db } => Box::new(Gain::from_db(db)),
+        }
+    }
+}
+
+/// Where audio goes; each one gets processed audio unless it is listed as raw
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioSink {
+    Wav,
+    Flac,
+    Stream,
+    Rtp,
+    Http,
+}
+
+/// Runs 16 bit PCM through the processors, each channel on its own, then converts the sample rate
+pub struct DspChain {
+    /// The processors of each channel
+    channels: Vec<Vec<Box<dyn Processor>>>,
+    resampler: Option<Resampler>,
+    /// Add triangular dither when rounding back to 16 bit
+    dither: bool,
+    random: u32,
+}
+
+impl DspChain {
+    /// None when there is nothing to process and no rate to convert, so the audio stays bit-exact;
+    /// an error when the rates cannot be converted
+    pub fn new(settings: &[ProcessorSettings], sample_rate: u32, output_rate: u32, channels: u16, dither: bool) -> Result<Option<Self>, String> {
+        if settings.is_empty() && sample_rate == output_rate {
+            return Ok(None);
+        }
+        let samples_per_frame = common::AUDIO_PAYLOAD_LENGTH / 2 / channels as usize;
+        let resampler = (sample_rate != output_rate)
+            .then(|| Resampler::new(sample_rate, output_rate, channels, samples_per_frame))
+            .transpose()?;
+        let channels = (0..channels)
+            .map(|_| settings.iter().map(|processor| processor.processor(sample_rate)).collect())
+            .collect();
+        Ok(Some(Self { channels, resampler, dither, random: 0x2545_f491 }))
+    }
+
+    /// Uniform random value in [-0.5, 0.5), from a xorshift generator
+    fn random(&mut self) -> f64 {
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 17;
+        self.random ^= self.random << 5;
+        self.random as f64 / 4_294_967_296.0 - 0.5
+    }
+
+    /// Process interleaved 16 bit little-endian PCM; with a rate conversion the output length varies
+    pub fn process(&mut self, payload: &[u8]) -> Vec<u8> {
+        let channel_count = self.channels.len();
+        let mut planar = vec![Vec::with_capacity(payload.len() / 2 / channel_count); channel_count];
+        for (i, sample) in levels::samples(payload).enumerate() {
+            let mut value = sample as f64 / 32768.0;
+            for processor in &mut self.channels[i % channel_count] {
+                value = processor.process(value);
+            }
+            planar[i % channel_count].push(value);
+        }
+        if let Some(resampler) = &mut self.resampler {
+            planar = resampler.process(planar);
+        }
+
+        let mut output = Vec::with_capacity(planar[0].len() * channel_count * 2);
+        for i in 0..planar[0].len() {
+            for channel in &planar {
+                output.extend(self.round(channel[i]).to_le_bytes());
+            }
+        }
+        output
+    }
+
+    /// Round a processed value back to 16 bit
+    fn round(&mut self, value: f64) -> i16 {
+        let mut value = value * 32768.0;
+        if self.dither {
+            // Triangular dither of ±1 LSB turns the rounding error into plain noise
+            value += self.random() + self.random();
+        }
+        value.round().clamp(i16::MIN as f64, i16::MAX as f64) as i16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+
+    fn tone(frequency: f64, amplitude: f64, offset: f64) -> Vec<u8> {
+        (0..48_000).flat_map(|i| {
+            let sample = offset + amplitude * (2.0 * PI * frequency * i as f64 / 48_000.0).sin();
+            (sample.round() as i16).to_le_bytes()
+        }).collect()
+    }
+
+    fn rms_of_last_half(payload: &[u8]) -> f64 {
+        let samples: Vec<f64> = levels::samples(payload).map(|sample| sample as f64).collect();
+        let half = &samples[samples.len() / 2..];
+        (half.iter().map(|sample| sample * sample).sum::<f64>() / half.len() as f64).sqrt()
+    }
+
+    #[test]
+    fn test_dsp_chain() {
+        assert!(DspChain::new(&[], 48_000, 48_000, 1, true).unwrap().is_none(), "No processors, no processing");
+
+        let settings: Vec<ProcessorSettings> = toml::from_str::<toml::Table>(r#"
+            [[dsp]]
+            type = "dc_blocker"
+
+            [[dsp]]
+            type = "high_pass"
+            frequency = 100.0
+
+            [[dsp]]
+            type = "gain"
+            db = 6.0
+        "#).unwrap()["dsp"].clone().try_into().unwrap();
+        assert_eq!(settings, [ProcessorSettings::dc_blocker(), ProcessorSettings::high_pass(100.0), ProcessorSettings::Gain { db: 6.0 }]);
+
+        let mut chain = DspChain::new(&settings, 48_000, 48_000, 1, false).unwrap().unwrap();
+        let output = chain.process(&tone(20.0, 4000.0, 2000.0));
+        let mean = levels::samples(&output[48_000..]).map(|sample| sample as f64).sum::<f64>() / 24_000.0;
+        assert!(mean.abs() < 5.0, "DC offset removed, got {}", mean);
+        assert!(rms_of_last_half(&output) < 0.1 * 4000.0 / 2f64.sqrt(), "20 Hz is 28 dB down at a 100 Hz cutoff, 22 dB after the gain");
+
+        let mut chain = DspChain::new(&[ProcessorSettings::low_pass(1000.0), ProcessorSettings::Gain { db: 6.0 }], 48_000, 48_000, 1, true).unwrap().unwrap();
+        let output = chain.process(&tone(100.0, 4000.0, 0.0));
+        let gain = rms_of_last_half(&output) / (4000.0 / 2f64.sqrt());
+        assert!((gain - 10f64.powf(6.0 / 20.0)).abs() < 0.01, "Passband gain {}", gain);
+        let mut chain = DspChain::new(&[ProcessorSettings::Gain { db: 0.0 }], 48_000, 48_000, 1, true).unwrap().unwrap();
+        let silence = chain.process(&[0; 2000]);
+        assert!(levels::samples(&silence).all(|sample| sample.abs() <= 1), "Dither stays within 1 LSB");
+        assert!(levels::samples(&silence).any(|sample| sample != 0), "Dither is added");
+    }
+}
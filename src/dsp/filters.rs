@@ -0,0 +1,92 @@
+use std::f64::consts::PI;
+
+/// Processes one channel, sample by sample, on samples scaled to ±1.0
+pub trait Processor: Send {
+    fn process(&mut self, sample: f64) -> f64;
+}
+
+/// One-pole high-pass that removes the DC offset and little else
+pub struct DcBlocker {
+    pole: f64,
+    last_input: f64,
+    last_output: f64,
+}
+
+impl DcBlocker {
+    pub fn new(cutoff: f64, sample_rate: u32) -> Self {
+        Self { pole: (-2.0 * PI * cutoff / sample_rate as f64).exp(), last_input: 0.0, last_output: 0.0 }
+    }
+}
+
+impl Processor for DcBlocker {
+    fn process(&mut self, sample: f64) -> f64 {
+        let output = sample - self.last_input + self.pole * self.last_output;
+        self.last_input = sample;
+        self.last_output = output;
+        output
+    }
+}
+
+/// Second order filter with the coefficients of the Audio EQ Cookbook
+pub struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    inputs: [f64; 2],
+    outputs: [f64; 2],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 3]) -> Self {
+        Self {
+            b: b.map(|b| b / a[0]),
+            a: [a[1] / a[0], a[2] / a[0]],
+            inputs: [0.0; 2],
+            outputs: [0.0; 2],
+        }
+    }
+
+    /// Angular frequency and alpha of the cookbook formulas
+    fn parameters(frequency: f64, q: f64, sample_rate: u32) -> (f64, f64) {
+        let omega = 2.0 * PI * frequency / sample_rate as f64;
+        (omega, omega.sin() / (2.0 * q))
+    }
+
+    pub fn high_pass(frequency: f64, q: f64, sample_rate: u32) -> Self {
+        let (omega, alpha) = Self::parameters(frequency, q, sample_rate);
+        let cos = omega.cos();
+        Self::new([(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0], [1.0 + alpha, -2.0 * cos, 1.0 - alpha])
+    }
+
+    pub fn low_pass(frequency: f64, q: f64, sample_rate: u32) -> Self {
+        let (omega, alpha) = Self::parameters(frequency, q, sample_rate);
+        let cos = omega.cos();
+        Self::new([(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0], [1.0 + alpha, -2.0 * cos, 1.0 - alpha])
+    }
+}
+
+impl Processor for Biquad {
+    fn process(&mut self, sample: f64) -> f64 {
+        let output = self.b[0] * sample + self.b[1] * self.inputs[0] + self.b[2] * self.inputs[1]
+            - self.a[0] * self.outputs[0] - self.a[1] * self.outputs[1];
+        self.inputs = [sample, self.inputs[0]];
+        self.outputs = [output, self.outputs[0]];
+        output
+    }
+}
+
+/// Fixed gain
+pub struct Gain {
+    factor: f64,
+}
+
+impl Gain {
+    pub fn from_db(db: f64) -> Self {
+        Self { factor: 10f64.powf(db / 20.0) }
+    }
+}
+
+impl Processor for Gain {
+    fn process(&mut self, sample: f64) -> f64 {
+        sample * self.factor
+    }
+}
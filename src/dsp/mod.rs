@@ -0,0 +1,3 @@
+pub mod chain;
+pub mod filters;
+pub mod resampler;
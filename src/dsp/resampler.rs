@@ -0,0 +1,79 @@
+use rubato::{FftFixedIn, Resampler as _};
+
+/// Converts audio between two fixed sample rates with an FFT based band-limited resampler
+pub struct Resampler {
+    resampler: FftFixedIn<f64>,
+    /// Samples of each channel waiting for a full input chunk
+    input: Vec<Vec<f64>>,
+    /// Output samples still to drop, so the output lines up with the input
+    delay: usize,
+}
+
+impl Resampler {
+    /// `chunk` is the number of samples per channel usually passed at once
+    pub fn new(from: u32, to: u32, channels: u16, chunk: usize) -> Result<Self, String> {
+        let resampler = FftFixedIn::new(from as usize, to as usize, chunk, 1, channels as usize)
+            .map_err(|e| format!("Cannot resample from {} Hz to {} Hz: {}", from, to, e))?;
+        Ok(Self {
+            delay: resampler.output_delay(),
+            resampler,
+            input: vec![Vec::new(); channels as usize],
+        })
+    }
+
+    /// Resample the next samples of each channel; returns what is complete so far
+    pub fn process(&mut self, channels: Vec<Vec<f64>>) -> Vec<Vec<f64>> {
+        for (input, samples) in self.input.iter_mut().zip(channels) {
+            input.extend(samples);
+        }
+        let mut output = vec![Vec::new(); self.input.len()];
+        while self.input[0].len() >= self.resampler.input_frames_next() {
+            let length = self.resampler.input_frames_next();
+            let chunk: Vec<Vec<f64>> = self.input.iter_mut().map(|input| input.drain(..length).collect()).collect();
+            let resampled = self.resampler.process(&chunk, None).expect("Chunks have the length the resampler asks for");
+            for (output, samples) in output.iter_mut().zip(resampled) {
+                output.extend(samples);
+            }
+        }
+        let skip = self.delay.min(output[0].len());
+        if skip > 0 {
+            for output in &mut output {
+                output.drain(..skip);
+            }
+            self.delay -= skip;
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+    use crate::analysis::spectrum::SpectrumAnalyzer;
+
+    #[test]
+    fn test_resampler() {
+        let mut resampler = Resampler::new(16_000, 48_000, 1, 2000).unwrap();
+        assert!(Resampler::new(0, 48_000, 1, 2000).is_err());
+        let mut output = Vec::new();
+        for frame in 0..20 {
+            let input: Vec<f64> = (frame * 2000..(frame + 1) * 2000)
+                .map(|i| 0.5 * (2.0 * PI * 1000.0 * i as f64 / 16_000.0).sin())
+                .collect();
+            output.extend(resampler.process(vec![input]).remove(0));
+        }
+        let expected = 20 * 6000 - resampler.resampler.output_delay();
+        assert!(output.len() <= expected && output.len() > expected - 6000, "{} samples", output.len());
+        // The delay is dropped, so the first samples are the start of the sine
+        let start = (0.5 * (2.0 * PI * 1000.0 * 10.0 / 48_000.0).sin() - output[10]).abs();
+        assert!(start < 0.01, "Off by {}", start);
+
+        let payload: Vec<u8> = output.iter().flat_map(|sample| ((sample * 32768.0).round() as i16).to_le_bytes()).collect();
+        let spectra = SpectrumAnalyzer::new(48_000, 1).push(&payload);
+        let tone = spectra[spectra.len() / 2].tone().unwrap();
+        assert!((tone.frequency - 1000.0).abs() < 1.0, "Frequency {}", tone.frequency);
+        assert!((tone.level_dbfs - (-9.03)).abs() < 0.1, "Level {}", tone.level_dbfs);
+        assert!(tone.snr_db > 80.0, "SNR {}", tone.snr_db);
+    }
+}
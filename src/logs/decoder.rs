@@ -0,0 +1,178 @@
+/// What to do with ANSI escape sequences (colors, cursor moves) in the firmware log
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AnsiMode {
+    /// Pass them through so a terminal renders the colors
+    Render,
+    /// Remove them
+    Strip,
+}
+
+const ESC: u8 = 0x1b;
+
+/// Turns raw LogData bytes into text.
+///
+/// UTF-8 is decoded, `\r\n`, `\n\r` and lone `\r` become `\n`, NUL string
+/// terminators are dropped and any other byte that is not printable text is
+/// written as a visible `\x8f` escape. Sequences split across chunks are
+/// carried over to the next call.
+pub struct LogDecoder {
+    ansi_mode: AnsiMode,
+    pending: Vec<u8>,
+}
+
+enum Token {
+    /// A complete token of this many bytes
+    Complete(usize),
+    /// The data ends before the token does
+    Incomplete,
+    /// Not a valid token
+    Invalid,
+}
+
+impl LogDecoder {
+    pub fn new(ansi_mode: AnsiMode) -> Self {
+        Self {
+            ansi_mode,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Decode a LogData chunk
+    pub fn decode(&mut self, data: &[u8]) -> String {
+        let mut bytes = std::mem::take(&mut self.pending);
+        bytes.extend_from_slice(data);
+
+        let mut text = String::with_capacity(bytes.len());
+        let mut i = 0;
+        while i < bytes.len() {
+            let rest = &bytes[i..];
+            let token = match rest[0] {
+                b'\r' | b'\n' => match rest.get(1) {
+                    None => Token::Incomplete,
+                    Some(&next) if (next == b'\r' || next == b'\n') && next != rest[0] => Token::Complete(2),
+                    Some(_) => Token::Complete(1),
+                },
+                ESC => Self::ansi_length(rest),
+                0x80..=0xff => Self::utf8_length(rest),
+                _ => Token::Complete(1),
+            };
+
+            match token {
+                Token::Incomplete => {
+                    self.pending = rest.to_vec();
+                    break;
+                }
+                Token::Invalid => {
+                    text.push_str(&format!("\\x{:02x}", rest[0]));
+                    i += 1;
+                }
+                Token::Complete(length) => {
+                    self.push_token(&mut text, &rest[..length]);
+                    i += length;
+                }
+            }
+        }
+        text
+    }
+
+    fn push_token(&self, text: &mut String, token: &[u8]) {
+        match token[0] {
+            b'\r' | b'\n' => text.push('\n'),
+            ESC => {
+                if self.ansi_mode == AnsiMode::Render {
+                    text.push_str(std::str::from_utf8(token).unwrap_or_default());
+                }
+            }
+            0x00 => {}
+            b'\t' | 0x20..=0x7e => text.push(token[0] as char),
+            0x01..=0x1f | 0x7f => text.push_str(&format!("\\x{:02x}", token[0])),
+            _ => text.push_str(std::str::from_utf8(token).unwrap_or_default()),
+        }
+    }
+
+    /// Length of a CSI sequence such as `ESC [ 1 ; 31 m`
+    fn ansi_length(data: &[u8]) -> Token {
+        match data.get(1) {
+            None => return Token::Incomplete,
+            Some(b'[') => {}
+            Some(_) => return Token::Invalid,
+        }
+        for (i, &b) in data.iter().enumerate().skip(2) {
+            match b {
+                0x40..=0x7e => return Token::Complete(i + 1),
+                0x20..=0x3f => {}
+                _ => return Token::Invalid,
+            }
+        }
+        Token::Incomplete
+    }
+
+    /// Length of the UTF-8 sequence starting with a non-ASCII byte
+    fn utf8_length(data: &[u8]) -> Token {
+        let length = match data[0] {
+            0xc2..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf4 => 4,
+            _ => return Token::Invalid,
+        };
+        if data.len() < length {
+            // Wait for the rest only if what we have so far can still be valid
+            return if data[1..].iter().all(|&b| (0x80..=0xbf).contains(&b)) {
+                Token::Incomplete
+            } else {
+                Token::Invalid
+            };
+        }
+        match std::str::from_utf8(&data[..length]) {
+            Ok(_) => Token::Complete(length),
+            Err(_) => Token::Invalid,
+        }
+    }
+}
+
+/// Remove ANSI escape sequences from decoded text, e.g. before writing it to a file
+pub fn strip_ansi(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == ESC as char && chars.peek() == Some(&'[') {
+            chars.next();
+            for c in chars.by_ref() {
+                if ('\u{40}'..='\u{7e}').contains(&c) {
+                    break;
+                }
+            }
+        } else {
+            stripped.push(c);
+        }
+    }
+    stripped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decoder() {
+        let mut decoder = LogDecoder::new(AnsiMode::Render);
+
+        // Line endings, NUL terminators and invalid bytes
+        assert_eq!(decoder.decode(b"a\r\nb\n\rc\rd\x00\x8f\x07e\n"), "a\nb\nc\nd\\x8f\\x07e");
+        // The trailing newline waited for a possible '\r'
+        assert_eq!(decoder.decode(b"\rf\r"), "\nf");
+        assert_eq!(decoder.decode(b"\ng"), "\ng");
+
+        // UTF-8 and color codes split across chunks
+        assert_eq!(decoder.decode(b"\xc3"), "");
+        assert_eq!(decoder.decode(b"\xa9 \x1b[1;3"), "\u{e9} ");
+        assert_eq!(decoder.decode(b"1mred\x1b[0m"), "\x1b[1;31mred\x1b[0m");
+        // A lead byte followed by ASCII is corruption, not a split character
+        assert_eq!(decoder.decode(b"\xe2\x82x"), "\\xe2\\x82x");
+
+        let mut decoder = LogDecoder::new(AnsiMode::Strip);
+        assert_eq!(decoder.decode(b"\x1b[32mok\x1b[0m \x1bx"), "ok \\x1bx");
+
+        assert_eq!(strip_ansi("\x1b[1;31mred\x1b[0m \u{e9}"), "red \u{e9}");
+    }
+}
@@ -0,0 +1,141 @@
+use serde::Deserialize;
+use super::pattern::Pattern;
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterAction {
+    /// Show only lines matching one of the include rules
+    Include,
+    /// Hide matching lines
+    Exclude,
+    /// Color matching lines
+    Highlight,
+}
+
+/// A filter rule as written in the config file or on the command line.
+/// `pattern` is a substring, or a regex when written as `/regex/`.
+#[derive(Deserialize, Clone, Debug)]
+pub struct FilterRuleSettings {
+    pub action: FilterAction,
+    pub pattern: String,
+    pub color: Option<String>,
+}
+
+impl FilterRuleSettings {
+    /// Parse a `--highlight` value of the form `[COLOR:]PATTERN`
+    pub fn highlight(value: &str) -> Self {
+        let (color, pattern) = match value.split_once(':') {
+            Some((color, pattern)) if ansi_color(color).is_some() => (Some(color.to_string()), pattern),
+            _ => (None, value),
+        };
+        Self {
+            action: FilterAction::Highlight,
+            pattern: pattern.to_string(),
+            color,
+        }
+    }
+}
+
+/// ANSI color code of a color name
+fn ansi_color(name: &str) -> Option<u8> {
+    match name.to_ascii_lowercase().as_str() {
+        "red" => Some(31),
+        "green" => Some(32),
+        "yellow" => Some(33),
+        "blue" => Some(34),
+        "magenta" => Some(35),
+        "cyan" => Some(36),
+        "white" => Some(37),
+        _ => None,
+    }
+}
+
+const DEFAULT_HIGHLIGHT_COLOR: u8 = 33;
+
+struct FilterRule {
+    action: FilterAction,
+    pattern: Pattern,
+    color: u8,
+}
+
+/// Decides which log lines are printed and how they are colored
+pub struct LogFilter {
+    rules: Vec<FilterRule>,
+    has_include_rules: bool,
+}
+
+impl LogFilter {
+    pub fn new(settings: &[FilterRuleSettings]) -> Result<Self, String> {
+        let mut rules = Vec::new();
+        for rule in settings {
+            let pattern = Pattern::parse(&rule.pattern)?;
+            let color = match &rule.color {
+                Some(name) => ansi_color(name).ok_or_else(|| format!("Unknown highlight color: {}", name))?,
+                None => DEFAULT_HIGHLIGHT_COLOR,
+            };
+            rules.push(FilterRule { action: rule.action, pattern, color });
+        }
+        let has_include_rules = rules.iter().any(|rule| rule.action == FilterAction::Include);
+        Ok(Self { rules, has_include_rules })
+    }
+
+    /// Return the line to print, highlighted if a rule asks for it, or None to hide it.
+    /// `plain_line` is the line without ANSI codes and is what the patterns are matched against.
+    pub fn apply(&self, line: &str, plain_line: &str) -> Option<String> {
+        let matching = |action: FilterAction| {
+            self.rules.iter().filter(move |rule| rule.action == action && rule.pattern.is_match(plain_line))
+        };
+
+        if self.has_include_rules && matching(FilterAction::Include).next().is_none() {
+            return None;
+        }
+        if matching(FilterAction::Exclude).next().is_some() {
+            return None;
+        }
+        match matching(FilterAction::Highlight).next() {
+            // Highlight the plain text so the firmware's own colors don't reset ours mid-line
+            Some(rule) => Some(format!("\x1b[{}m{}\x1b[0m", rule.color, plain_line)),
+            None => Some(line.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(action: FilterAction, pattern: &str) -> FilterRuleSettings {
+        FilterRuleSettings { action, pattern: pattern.to_string(), color: None }
+    }
+
+    #[test]
+    fn test_log_filter() {
+        let filter = LogFilter::new(&[
+            rule(FilterAction::Exclude, "aaf_stream_store"),
+            FilterRuleSettings::highlight("red:/^app_\\w+_init$/"),
+            FilterRuleSettings::highlight("isInputMuted"),
+        ]).unwrap();
+
+        assert_eq!(filter.apply("aaf_stream_store", "aaf_stream_store"), None);
+        assert_eq!(filter.apply("\x1b[32mapp_init\x1b[0m", "app_init"), Some("\x1b[32mapp_init\x1b[0m".to_string()));
+        assert_eq!(filter.apply("app_poweron_key_init", "app_poweron_key_init"), Some("\x1b[31mapp_poweron_key_init\x1b[0m".to_string()));
+        assert_eq!(
+            filter.apply("app_aaf_mute_toggle isInputMuted true", "app_aaf_mute_toggle isInputMuted true"),
+            Some("\x1b[33mapp_aaf_mute_toggle isInputMuted true\x1b[0m".to_string())
+        );
+
+        let filter = LogFilter::new(&[
+            rule(FilterAction::Include, "/^\\[af_stream/"),
+            rule(FilterAction::Include, "codec"),
+            rule(FilterAction::Exclude, "stream = 0"),
+        ]).unwrap();
+
+        assert!(filter.apply("[af_stream_open] id = 0, stream = 1", "[af_stream_open] id = 0, stream = 1").is_some());
+        assert!(filter.apply("[af_stream_open] id = 0, stream = 0", "[af_stream_open] id = 0, stream = 0").is_none());
+        assert!(filter.apply("codec_hw_open", "codec_hw_open").is_some());
+        assert!(filter.apply("app_init", "app_init").is_none(), "Lines matching no include rule are hidden");
+
+        assert!(LogFilter::new(&[rule(FilterAction::Include, "/(/")]).is_err());
+        assert!(LogFilter::new(&[FilterRuleSettings { color: Some("pink".to_string()), ..rule(FilterAction::Highlight, "x") }]).is_err());
+    }
+}
@@ -0,0 +1,33 @@
+/// Splits text arriving in chunks into complete lines
+#[derive(Default)]
+pub struct LineBuffer {
+    partial_line: String,
+}
+
+impl LineBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a chunk and return the lines it completes, without their line endings.
+    /// An unterminated last line is kept until a later chunk completes it.
+    pub fn push(&mut self, text: &str) -> Vec<String> {
+        self.partial_line.push_str(text);
+        let mut lines = Vec::new();
+        while let Some(end) = self.partial_line.find('\n') {
+            let mut line: String = self.partial_line.drain(..=end).collect();
+            line.truncate(line.trim_end_matches(['\r', '\n']).len());
+            lines.push(line);
+        }
+        lines
+    }
+
+    /// Return the unterminated last line, if any
+    pub fn flush(&mut self) -> Option<String> {
+        if self.partial_line.is_empty() {
+            None
+        } else {
+            Some(std::mem::take(&mut self.partial_line))
+        }
+    }
+}
@@ -0,0 +1,185 @@
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use super::line_buffer::LineBuffer;
+
+/// A regex whose named capture groups become fields of a parsed log line.
+/// A rule with `key` and `value` groups is applied repeatedly and adds one
+/// numeric or string field per match instead.
+pub struct LogRule {
+    pattern: Regex,
+}
+
+impl LogRule {
+    pub fn new(pattern: &str) -> Result<Self, regex::Error> {
+        Ok(Self { pattern: Regex::new(pattern)? })
+    }
+
+    fn is_key_value(&self) -> bool {
+        let names: Vec<&str> = self.pattern.capture_names().flatten().collect();
+        names.contains(&"key") && names.contains(&"value")
+    }
+}
+
+/// A rule as written in the `[[log_rule]]` tables of the config file or with `--log-rule`
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct LogRuleSettings {
+    pub pattern: String,
+}
+
+/// Rules matching the BES firmware log format
+pub const DEFAULT_RULES: [&str; 5] = [
+    // [ERROR] / WARN: style severity prefixes
+    r"^\[?(?P<level>ERROR|WARN|INFO|DEBUG|TRACE)\]?[:\s]",
+    // [af_stream_open] id = 0, stream = 1
+    r"^\[(?P<module>[a-z_]\w*)\]",
+    // nv_record_extension_init,main sector is valid. / _sbrk: incr 32
+    r"^(?P<function>[A-Za-z_]\w*)[,:]",
+    // cur=0x2004e1a0 / valid len: 704
+    r"(?P<key>[A-Za-z_]\w*)\s*[=:]\s*(?P<value>-?0x[0-9A-Fa-f]+|-?\d+)\b",
+    // incr 32 / BATTERY 0
+    r"\b(?P<key>[A-Za-z_]\w*) (?P<value>-?0x[0-9A-Fa-f]+|-?\d+)\b",
+];
+
+/// Splits LogData text into lines and extracts structured fields from them
+pub struct LogLineParser {
+    rules: Vec<LogRule>,
+    lines: LineBuffer,
+}
+
+impl LogLineParser {
+    pub fn new(rules: Vec<LogRule>) -> Self {
+        Self {
+            rules,
+            lines: LineBuffer::new(),
+        }
+    }
+
+    /// Configured rules first, so their fields win, then the default rules unless they are turned off
+    pub fn from_settings(settings: &[LogRuleSettings], default_rules: bool) -> Result<Self, String> {
+        let defaults = if default_rules { DEFAULT_RULES.as_slice() } else { &[] };
+        let rules = settings.iter().map(|rule| rule.pattern.as_str())
+            .chain(defaults.iter().copied())
+            .map(|pattern| LogRule::new(pattern).map_err(|e| format!("Invalid log rule {}: {}", pattern, e)))
+            .collect::<Result<_, _>>()?;
+        Ok(Self::new(rules))
+    }
+
+    #[cfg(test)]
+    pub fn with_default_rules() -> Self {
+        let rules = DEFAULT_RULES.iter()
+            .map(|pattern| LogRule::new(pattern).expect("Invalid default log rule"))
+            .collect();
+        Self::new(rules)
+    }
+
+    /// Add text from a LogData chunk and return the records of all completed lines.
+    /// An unterminated last line is kept until the next chunk completes it.
+    pub fn push(&mut self, text: &str, timestamp: &str) -> Vec<Value> {
+        self.lines.push(text)
+            .iter()
+            .filter_map(|line| self.parse_line(line, timestamp))
+            .collect()
+    }
+
+    /// Return the record of the unterminated last line, e.g. before the device resets
+    pub fn flush(&mut self, timestamp: &str) -> Option<Value> {
+        let line = self.lines.flush()?;
+        self.parse_line(&line, timestamp)
+    }
+
+    /// Build a JSON record of a single line, or None if the line is blank
+    pub fn parse_line(&self, line: &str, timestamp: &str) -> Option<Value> {
+        let message = line.trim_matches(|c: char| c.is_whitespace() || c.is_control());
+        if message.is_empty() {
+            return None;
+        }
+
+        let mut record = Map::new();
+        record.insert("timestamp".to_string(), Value::from(timestamp));
+        record.insert("message".to_string(), Value::from(message));
+        let mut values = Map::new();
+
+        for rule in &self.rules {
+            if rule.is_key_value() {
+                for captures in rule.pattern.captures_iter(message) {
+                    values.entry(captures["key"].to_string())
+                        .or_insert_with(|| parse_value(&captures["value"]));
+                }
+            } else if let Some(captures) = rule.pattern.captures(message) {
+                for name in rule.pattern.capture_names().flatten() {
+                    if let Some(field) = captures.name(name) {
+                        record.entry(name.to_string())
+                            .or_insert_with(|| Value::from(field.as_str()));
+                    }
+                }
+            }
+        }
+
+        if !values.is_empty() {
+            record.insert("values".to_string(), Value::Object(values));
+        }
+        Some(Value::Object(record))
+    }
+}
+
+/// Convert a captured value into a JSON number where possible
+fn parse_value(value: &str) -> Value {
+    let (negative, digits) = match value.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, value),
+    };
+    let parsed = match digits.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16),
+        None => digits.parse::<i64>(),
+    };
+    match parsed {
+        Ok(number) if negative => Value::from(-number),
+        Ok(number) => Value::from(number),
+        Err(_) => Value::from(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_parser() {
+        let mut parser = LogLineParser::with_default_rules();
+
+        let records = parser.push("_sbrk: incr 32 cur=0x2004e1a0\n\r\nnv_record_extension_init,do", "t0");
+        assert_eq!(records.len(), 1, "Blank lines and the unterminated line produce no records");
+        assert_eq!(records[0]["timestamp"], "t0");
+        assert_eq!(records[0]["message"], "_sbrk: incr 32 cur=0x2004e1a0");
+        assert_eq!(records[0]["function"], "_sbrk");
+        assert_eq!(records[0]["values"]["incr"], 32);
+        assert_eq!(records[0]["values"]["cur"], 0x2004e1a0);
+
+        let records = parser.push("ne.\r\n[af_stream_open] id = 0, stream = 1\r\nANA: DC CALIB L=0xE024/-36", "t1");
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["message"], "nv_record_extension_init,done.");
+        assert_eq!(records[0]["function"], "nv_record_extension_init");
+        assert_eq!(records[1]["module"], "af_stream_open");
+        assert_eq!(records[1]["values"]["stream"], 1);
+
+        let record = parser.flush("t2").expect("Unterminated line should be flushed");
+        assert_eq!(record["function"], "ANA");
+        assert_eq!(record["values"]["L"], 0xE024);
+        assert!(parser.flush("t3").is_none());
+
+        let record = parser.parse_line("[ERROR] codec open failed", "t4").unwrap();
+        assert_eq!(record["level"], "ERROR");
+        assert!(record.get("module").is_none(), "Severity prefix is not a module tag");
+
+        let rules = [LogRuleSettings { pattern: r"battery (?P<battery>\d+)%".to_string() }];
+        let parser = LogLineParser::from_settings(&rules, true).unwrap();
+        let record = parser.parse_line("app_battery: battery 80%", "t5").unwrap();
+        assert_eq!(record["battery"], "80");
+        assert_eq!(record["function"], "app_battery", "Default rules still apply");
+        let parser = LogLineParser::from_settings(&rules, false).unwrap();
+        assert!(parser.parse_line("app_battery: battery 80%", "t6").unwrap().get("function").is_none());
+        assert!(LogLineParser::from_settings(&[LogRuleSettings { pattern: "(".to_string() }], true).is_err());
+    }
+}
@@ -0,0 +1,5 @@
+pub mod decoder;
+pub mod filter;
+pub mod line_buffer;
+pub mod line_parser;
+pub mod pattern;
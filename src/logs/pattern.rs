@@ -0,0 +1,25 @@
+use regex::Regex;
+
+/// A log line pattern: a substring, or a regex when written as `/regex/`
+pub enum Pattern {
+    Substring(String),
+    Regex(Regex),
+}
+
+impl Pattern {
+    pub fn parse(pattern: &str) -> Result<Self, String> {
+        match pattern.strip_prefix('/').and_then(|p| p.strip_suffix('/')) {
+            Some(regex) => Regex::new(regex)
+                .map(Pattern::Regex)
+                .map_err(|e| format!("Invalid pattern {}: {}", pattern, e)),
+            None => Ok(Pattern::Substring(pattern.to_string())),
+        }
+    }
+
+    pub fn is_match(&self, line: &str) -> bool {
+        match self {
+            Pattern::Substring(substring) => line.contains(substring.as_str()),
+            Pattern::Regex(regex) => regex.is_match(line),
+        }
+    }
+}
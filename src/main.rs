@@ -1,11 +1,23 @@
 use std::io::{self, Read};
 use std::collections::VecDeque;
+use std::path::Path;
 use std::sync::{Arc, Mutex};
 use serialport::SerialPort;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use chrono::Local;
+use clap::Parser;
+mod analysis;
+mod audio;
+mod config;
 mod constants;
+mod dsp;
+mod logs;
 mod parser;
+mod receiver;
+mod recorder;
+mod server;
+mod timing;
+mod tui;
 mod utils;
 
 
@@ -37,38 +49,221 @@ fn clear_serial_buffer(port: &mut Box<dyn SerialPort>, bytes_to_clear: usize) {
     }
 }
 
+/// Seconds a tone test waits for audio beyond the audio it needs
+const TONE_TEST_TIMEOUT_SECONDS: f64 = 5.0;
+
+/// Measure a test tone through the parser, print the JSON report and return the exit code:
+/// 0 on pass, 1 on fail, 2 when the test could not run or got no tone to measure
+fn run_tone_test(settings: &config::settings::Settings, report_path: Option<&Path>) -> i32 {
+    use parser::parser::{FrameType, Parser};
+    let mut port = match serialport::new(&settings.port, settings.baudrate).timeout(Duration::from_secs(1)).open() {
+        Ok(port) => port,
+        Err(e) => {
+            eprintln!("Failed to open serial port: {}", e);
+            return 2;
+        }
+    };
+
+    let mut sample_rate = settings.sample_rate;
+    if settings.infer_sample_rate {
+        eprintln!("Measuring the sample rate on {}...", settings.port);
+        // The test measures fresh audio once the rate is known, so the reads are dropped
+        match measure_sample_rate(&mut port).0 {
+            Some(estimate) => {
+                eprintln!("Sample rate {} Hz, measured {:.0} Hz from the frame rate", estimate.sample_rate, estimate.measured);
+                sample_rate = estimate.sample_rate;
+            }
+            None => {
+                eprintln!("Too few audio frames to measure the sample rate");
+                return 2;
+            }
+        }
+    }
+    let test = Arc::new(Mutex::new(analysis::tone_test::ToneTest::new(&settings.tone_test, sample_rate)));
+    let parser = Arc::new(Mutex::new(Parser::new(constants::common::TARGET_SEQUENCE.to_vec())));
+    {
+        let test = Arc::clone(&test);
+        parser.lock().unwrap().set_timed_callback(move |frame_type, data, _| {
+            let mut test = test.lock().expect("Failed to lock tone test mutex");
+            match frame_type {
+                FrameType::AudioData => test.push(Parser::extract_frame_number(data), &data[..constants::common::AUDIO_PAYLOAD_LENGTH]),
+                FrameType::DeviceReset => test.on_device_reset(),
+                _ => {}
+            }
+        });
+    }
+    Parser::start(Arc::clone(&parser));
+
+    eprintln!("Measuring the tone on {} at {} baud...", settings.port, settings.baudrate);
+    clear_serial_buffer(&mut port, constants::common::SERIAL_READ_SIZE);
+    let limits = &settings.tone_test;
+    let timeout = Duration::from_secs_f64(limits.seconds + limits.settle_seconds + TONE_TEST_TIMEOUT_SECONDS);
+    let start = Instant::now();
+    let mut read_buffer = [0u8; constants::common::SERIAL_READ_SIZE];
+    while !test.lock().expect("Failed to lock tone test mutex").is_complete() && start.elapsed() < timeout {
+        match port.read(&mut read_buffer) {
+            Ok(n) if n > 0 => parser.lock().expect("Failed to lock parser mutex").push_data_at(&read_buffer[..n], Instant::now()),
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => {}
+            Err(e) => {
+                eprintln!("Serial read error: {}", e);
+                break;
+            }
+        }
+    }
+
+    let report = test.lock().expect("Failed to lock tone test mutex").report();
+    let json = serde_json::to_string_pretty(&report).expect("Report serializes");
+    println!("{}", json);
+    if let Some(path) = report_path {
+        if let Err(e) = std::fs::write(path, &json) {
+            eprintln!("Failed to write the report to {}: {}", path.display(), e);
+            return 2;
+        }
+    }
+    if report.error.is_some() {
+        2
+    } else if report.pass {
+        0
+    } else {
+        1
+    }
+}
+
+/// Wait before the first attempt to open the serial port again, doubled after each failed attempt up to the maximum
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// Open the serial port again after a read error, e.g. when the device was unplugged, until it is back.
+/// Failed attempts are reported through the handler, so they show in the terminal UI too.
+fn reopen_serial_port(settings: &config::settings::Settings, handler: &Mutex<receiver::handler::FrameHandler>) -> Box<dyn SerialPort> {
+    let mut delay = RECONNECT_DELAY;
+    loop {
+        std::thread::sleep(delay);
+        match serialport::new(&settings.port, settings.baudrate).timeout(Duration::from_secs(1)).open() {
+            Ok(port) => return port,
+            Err(e) => {
+                delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+                handler.lock().expect("Failed to lock frame handler mutex")
+                    .error(format_args!("Failed to reopen {}: {}, retrying in {} s", settings.port, e, delay.as_secs()));
+            }
+        }
+    }
+}
+
+/// Seconds of audio frames the sample rate is measured from at startup
+const SAMPLE_RATE_PROBE_SECONDS: f64 = 3.0;
+
+/// Bytes read from the serial port and when they arrived
+type TimedReads = Vec<(Vec<u8>, Instant)>;
+
+/// Measure the sample rate from the rate the audio frames arrive at.
+/// Returns the reads too, so the audio and log of the measurement can be replayed into the recording.
+fn measure_sample_rate(port: &mut Box<dyn SerialPort>) -> (Option<timing::rate_estimator::RateEstimate>, TimedReads) {
+    use parser::parser::{FrameType, Parser};
+    let estimator = Arc::new(Mutex::new(timing::rate_estimator::RateEstimator::new(SAMPLE_RATE_PROBE_SECONDS)));
+    let mut parser = Parser::new(constants::common::TARGET_SEQUENCE.to_vec());
+    {
+        let estimator = Arc::clone(&estimator);
+        parser.set_timed_callback(move |frame_type, data, received| {
+            let mut estimator = estimator.lock().expect("Failed to lock rate estimator mutex");
+            match frame_type {
+                FrameType::AudioData => estimator.push(Parser::extract_frame_number(data), received.last_byte),
+                FrameType::DeviceReset => estimator.reset(),
+                _ => {}
+            }
+        });
+    }
+
+    clear_serial_buffer(port, constants::common::SERIAL_READ_SIZE);
+    let start = Instant::now();
+    let mut reads = Vec::new();
+    let mut read_buffer = [0u8; constants::common::SERIAL_READ_SIZE];
+    while start.elapsed().as_secs_f64() < SAMPLE_RATE_PROBE_SECONDS {
+        match port.read(&mut read_buffer) {
+            Ok(n) if n > 0 => {
+                let received = Instant::now();
+                parser.push_data_at(&read_buffer[..n], received);
+                parser.process();
+                reads.push((read_buffer[..n].to_vec(), received));
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => {}
+            Err(e) => {
+                eprintln!("Serial read error: {}", e);
+                return (None, reads);
+            }
+        }
+    }
+    let estimate = estimator.lock().expect("Failed to lock rate estimator mutex").estimate();
+    (estimate, reads)
+}
+
 fn main() -> io::Result<()> {
-    let port = serialport::new(constants::common::SERIAL_PORT, 
-            constants::common::BAUDRATE)
+    let args = config::args::Args::parse();
+    let mut settings = args.settings()?;
+    if let Some(config::args::Command::ToneTest(tone_test)) = &args.command {
+        std::process::exit(run_tone_test(&settings, tone_test.report.as_deref()));
+    }
+
+    let mut port = serialport::new(&settings.port, settings.baudrate)
         .timeout(Duration::from_secs(1))
         .open();
+    let mut probe_reads = Vec::new();
+    if let (true, Ok(port)) = (settings.infer_sample_rate, &mut port) {
+        eprintln!("Measuring the sample rate on {}...", settings.port);
+        let (estimate, reads) = measure_sample_rate(port);
+        probe_reads = reads;
+        match estimate {
+            Some(estimate) => {
+                settings.measured_sample_rate = Some(estimate.measured);
+                settings.sample_rate = estimate.sample_rate;
+            }
+            None => eprintln!("Too few audio frames to measure the sample rate, using {} Hz", settings.sample_rate),
+        }
+    }
 
     let sync_vec: Vec<u8> = vec![0xFF, 0x01, 0xFF, 0x02, 0xFF, 0x03, 0xFF, 0x04];
 
     let parser = Arc::new(Mutex::new(parser::parser::Parser::new(sync_vec)));
 
+    let session_start = Local::now();
+    let session = recorder::session::SessionInfo {
+        name: session_start.format("session_%Y-%m-%d_%H-%M-%S").to_string(),
+        port: settings.port.clone(),
+        start: session_start,
+    };
+    let (console, tui_lines) = if settings.tui {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        (receiver::console::Console::channel(sender), Some(receiver))
+    } else {
+        (receiver::console::Console::open(&settings.console_target(), settings.strip_ansi)?, None)
+    };
+    let handler = Arc::new(Mutex::new(receiver::handler::FrameHandler::new(&settings, session, console)?));
+    let link_stats = handler.lock().expect("Failed to lock frame handler mutex").link_stats();
+    if let Some(lines) = tui_lines {
+        tui::app::spawn(Arc::clone(&handler), lines, settings.baudrate);
+    }
+
+    // Close the files and write the last summaries before exiting on Ctrl-C; the process exits without dropping
+    // the handler, so finish() closes every writer itself. The terminal UI catches Ctrl-C as a key instead.
+    {
+        let handler = Arc::clone(&handler);
+        let result = ctrlc::set_handler(move || {
+            handler.lock().expect("Failed to lock frame handler mutex").finish();
+            std::process::exit(0);
+        });
+        if let Err(e) = result {
+            eprintln!("Failed to set the Ctrl-C handler: {}", e);
+        }
+    }
+
     // Set a callback to handle parsed frames
     {
+        let handler = Arc::clone(&handler);
         let mut parser_lock = parser.lock().unwrap();
-        parser_lock.set_callback(|frame_type, data| {
-            match frame_type {
-                parser::parser::FrameType::LogData => {
-                    let now = Local::now();
-                    let filtered_string: String = data.iter()
-                        .filter(|&&b| b.is_ascii()) // Keep only ASCII bytes
-                        .map(|&b| b as char)        // Convert each byte to a char
-                        .collect(); 
-                    println!("{} - {}", now.format("%Y-%m-%d %H:%M:%S%.3f"), filtered_string)
-                },
-                parser::parser::FrameType::AudioData => {
-                    let now = Local::now();
-                    let frame_number: u32 = (data[4000] as u32)
-                    | ((data[4001] as u32) << 8)
-                    | ((data[4002] as u32) << 16)
-                    | ((data[4003] as u32) << 24);
-                    println!("{} - AUDIO Frame Received Length: {}, frame_number: {}", now.format("%Y-%m-%d %H:%M:%S%.3f"), data.len(), frame_number); 
-                },
-            }
+        parser_lock.set_timed_callback(move |frame_type, data, received| {
+            handler.lock().expect("Failed to lock frame handler mutex").handle(frame_type, data, received);
         });
     }
 
@@ -77,25 +272,57 @@ fn main() -> io::Result<()> {
 
     match port {
         Ok(mut port) => {
-            println!("Listening on {} at {} baud...", constants::common::SERIAL_PORT, 
-                constants::common::BAUDRATE);
+            let mut handler_lock = handler.lock().expect("Failed to lock frame handler mutex");
+            if let Some(measured) = settings.measured_sample_rate {
+                handler_lock.print(format_args!("Sample rate {} Hz, measured {:.0} Hz from the frame rate", settings.sample_rate, measured));
+            }
+            handler_lock.print(format_args!("Listening on {} at {} baud...", settings.port, settings.baudrate));
+            drop(handler_lock);
             
-            // Clear the serial buffer before starting
-            clear_serial_buffer(&mut port, constants::common::SERIAL_READ_SIZE);
+            if probe_reads.is_empty() {
+                // Clear the serial buffer before starting
+                clear_serial_buffer(&mut port, constants::common::SERIAL_READ_SIZE);
+            } else {
+                // Record the audio and log the sample rate was measured from; the port was read on without a gap
+                let mut parser = parser.lock().expect("Failed to lock parser mutex");
+                for (data, received) in probe_reads {
+                    parser.push_data_at(&data, received);
+                }
+            }
 
             let mut read_buffer: [u8; constants::common::SERIAL_READ_SIZE] = [0u8; constants::common::SERIAL_READ_SIZE]; // choose an appropriate size
             loop {
+                let read_start = Instant::now();
                 match port.read(&mut read_buffer) {
                     Ok(n) if n > 0 => {
+                        // Take the time before waiting for the lock
+                        let received = Instant::now();
                         let mut parser = parser.lock().expect("Failed to lock parser mutex");
-                        parser.push_data(&read_buffer[..n]);
+                        // What the parser has not processed yet, without the bytes just read
+                        let queued = parser.queued_bytes();
+                        parser.push_data_at(&read_buffer[..n], received);
+                        // The frame handler locks the link stats while the parser is locked, so never hold both here
+                        drop(parser);
+                        link_stats.lock().expect("Failed to lock link stats mutex")
+                            .on_read(n, received - read_start, queued, received);
                     }
                     Ok(_) => {
                         // n == 0 means EOF or no data; depending on serial config
+                        link_stats.lock().expect("Failed to lock link stats mutex").on_timeout(read_start.elapsed(), Instant::now());
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                        link_stats.lock().expect("Failed to lock link stats mutex").on_timeout(read_start.elapsed(), Instant::now());
                     }
                     Err(e) => {
-                        eprintln!("Serial read error: {}", e);
-                        // Possibly break or handle error
+                        handler.lock().expect("Failed to lock frame handler mutex").error(format_args!("Serial read error, reconnecting: {}", e));
+                        port = reopen_serial_port(&settings, &handler);
+                        // The bytes before the error end mid-frame and the driver may still hold stale ones
+                        parser.lock().expect("Failed to lock parser mutex").reset();
+                        let mut handler = handler.lock().expect("Failed to lock frame handler mutex");
+                        if let Err(e) = port.clear(serialport::ClearBuffer::Input) {
+                            handler.error(format_args!("Failed to flush the serial port: {}", e));
+                        }
+                        handler.on_serial_reconnect(&settings.port);
                     }
                 }
             }
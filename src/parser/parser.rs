@@ -3,19 +3,37 @@ use std::collections::VecDeque;
 use std::ptr::null;
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use crate::constants::common;
 
 #[derive(PartialEq, Debug)]
 pub enum FrameType {
     LogData,
     AudioData,
+    DeviceReset,
+    /// An audio frame cut short: its sync bytes follow the previous frame too closely
+    BadFrame,
 }
 
+/// When the bytes of an emitted frame arrived from the serial port
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ReceiveTime {
+    pub first_byte: Instant,
+    pub last_byte: Instant,
+}
+
+type Callback = Box<dyn Fn(FrameType, &[u8], ReceiveTime) + Send + Sync>;
+
 pub struct Parser {
     last_frame_number: u64,
+    seen_data: bool,
+    awaiting_first_frame: bool,
     data_queue: VecDeque<u8>,
-    callback: Option<Box<dyn Fn(FrameType, &[u8]) + Send + Sync>>,
+    /// Stream offset of the first byte in the queue
+    queue_offset: u64,
+    /// Stream offset just past the end of each pushed chunk, with its receive time
+    chunk_times: VecDeque<(u64, Instant)>,
+    callback: Option<Callback>,
     sync_bytes: Vec<u8>
 }
 
@@ -24,23 +42,76 @@ impl Parser {
     pub fn new(sync_bytes: Vec<u8>) -> Self {
         Self {
             last_frame_number: 0,
+            seen_data: false,
+            awaiting_first_frame: false,
             data_queue: VecDeque::new(),
+            queue_offset: 0,
+            chunk_times: VecDeque::new(),
             callback: None,
             sync_bytes,
         }
     }
 
     /// Set a callback function
+    #[cfg(test)]
     pub fn set_callback<F>(&mut self, callback: F)
     where
         F: Fn(FrameType, &[u8]) + Send + Sync + 'static,
+    {
+        self.callback = Some(Box::new(move |frame_type, data, _| callback(frame_type, data)));
+    }
+
+    /// Set a callback function that also gets the receive time of the frame
+    pub fn set_timed_callback<F>(&mut self, callback: F)
+    where
+        F: Fn(FrameType, &[u8], ReceiveTime) + Send + Sync + 'static,
     {
         self.callback = Some(Box::new(callback));
     }
 
     /// Push data into the parser's queue
+    #[cfg(test)]
     pub fn push_data(&mut self, data: &[u8]) {
+        self.push_data_at(data, Instant::now());
+    }
+
+    /// Push data into the parser's queue together with the time it was read
+    pub fn push_data_at(&mut self, data: &[u8], received: Instant) {
+        if data.is_empty() {
+            return;
+        }
         self.data_queue.extend(data);
+        self.chunk_times.push_back((self.queue_offset + self.data_queue.len() as u64, received));
+    }
+
+    /// Bytes waiting to be parsed
+    pub fn queued_bytes(&self) -> usize {
+        self.data_queue.len()
+    }
+
+    /// Drop the bytes not parsed yet, e.g. the partial frame left when the serial port dropped out
+    pub fn reset(&mut self) {
+        self.queue_offset += self.data_queue.len() as u64;
+        self.data_queue.clear();
+        self.chunk_times.clear();
+    }
+
+    /// Receive time of the byte at `index` in the queue
+    fn arrival_time(&self, index: usize) -> Instant {
+        let offset = self.queue_offset + index as u64;
+        let chunk = self.chunk_times.partition_point(|&(end, _)| end <= offset);
+        self.chunk_times.get(chunk)
+            .or(self.chunk_times.back())
+            .map(|&(_, received)| received)
+            .expect("Receive time of a byte that was never pushed")
+    }
+
+    /// Receive time of `length` bytes starting at `index` in the queue
+    fn receive_time(&self, index: usize, length: usize) -> ReceiveTime {
+        ReceiveTime {
+            first_byte: self.arrival_time(index),
+            last_byte: self.arrival_time(index + length.saturating_sub(1)),
+        }
     }
 
     // Process data in the queue
@@ -66,24 +137,31 @@ impl Parser {
                 if position < 4004 {
                     continue;
                 }
+                if position - 4004 < last_audio_frame_position {
+                    let bad_frame = &packet_to_review[last_audio_frame_position..position + 8];
+                    self.emit(FrameType::BadFrame, bad_frame, last_audio_frame_position);
+                    last_audio_frame_position = position + 8;
+                    continue;
+                }
                 let log_end_index: usize = position - 4004;
                 let packet_logs_size = log_end_index - last_audio_frame_position;
                 if packet_logs_size > 0 {
                     let packet_logs = &packet_to_review[last_audio_frame_position..log_end_index];
-                    if let Some(callback) = &self.callback {
-                        callback(FrameType::LogData, packet_logs);
-                    }
+                    self.process_log(packet_logs, last_audio_frame_position);
                 }
                 let audio_packet = &packet_to_review[log_end_index..position + 8];
-                    if let Some(callback) = &self.callback {
-                        callback(FrameType::AudioData, audio_packet);
-                    }
+                self.process_audio_frame(audio_packet, log_end_index);
                 last_audio_frame_position = position + 8;
             }
 
             // Remove the all found bytes from the queue
             if !found_positions.is_empty() {
-                self.data_queue.drain(..*found_positions.last().unwrap() + 8);
+                let drained = *found_positions.last().unwrap() + 8;
+                self.data_queue.drain(..drained);
+                self.queue_offset += drained as u64;
+                while self.chunk_times.front().is_some_and(|&(end, _)| end <= self.queue_offset) {
+                    self.chunk_times.pop_front();
+                }
             }
 
 
@@ -127,18 +205,63 @@ impl Parser {
         });
     }
 
-    fn process_log(data: Vec<u8>) {
+    /// Emit `data`, which starts at `index` in the queue
+    fn emit(&self, frame_type: FrameType, data: &[u8], index: usize) {
+        if let Some(callback) = &self.callback {
+            callback(frame_type, data, self.receive_time(index, data.len()));
+        }
+    }
 
+    /// Emit a log chunk, reporting a device reset if it contains a boot banner
+    fn process_log(&mut self, data: &[u8], index: usize) {
+        match Self::find_boot_banner(data) {
+            // The very first banner of a session is a normal boot, not a reset
+            Some(banner_start) if self.seen_data => {
+                if banner_start > 0 {
+                    self.emit(FrameType::LogData, &data[..banner_start], index);
+                }
+                self.emit(FrameType::DeviceReset, &[], index + banner_start);
+                self.emit(FrameType::LogData, &data[banner_start..], index + banner_start);
+                // The counter restart that follows the banner belongs to the same reset
+                self.awaiting_first_frame = true;
+            }
+            Some(_) => {
+                self.emit(FrameType::LogData, data, index);
+                self.awaiting_first_frame = true;
+            }
+            None => self.emit(FrameType::LogData, data, index),
+        }
+        self.seen_data = true;
     }
-    
-    fn process_audio_frame(data: Vec<u8>) {
-        
+
+    /// Emit an audio frame, reporting a device reset if its frame counter went backwards
+    fn process_audio_frame(&mut self, data: &[u8], index: usize) {
+        let frame_number = Self::extract_frame_number(data);
+        if self.awaiting_first_frame {
+            self.awaiting_first_frame = false;
+        } else if frame_number < self.last_frame_number {
+            self.emit(FrameType::DeviceReset, &[], index);
+        }
+        self.last_frame_number = frame_number;
+        self.seen_data = true;
+        self.emit(FrameType::AudioData, data, index);
+    }
+
+    /// Find the start of the line holding the firmware boot banner
+    fn find_boot_banner(data: &[u8]) -> Option<usize> {
+        let marker = common::BOOT_BANNER_MARKER;
+        let marker_position = data.windows(marker.len()).position(|window| window == marker)?;
+        let line_start = data[..marker_position]
+            .iter()
+            .rposition(|&b| b == b'\n')
+            .map_or(0, |i| i + 1);
+        Some(line_start)
     }
 
     pub fn extract_frame_number(packet: &[u8]) -> u64 {
-        // TODO: fix the framing error let frame_number_bytes = &packet[3999..4007];
-        let frame_number_bytes = &packet[3999..4007];
-        u64::from_le_bytes(frame_number_bytes.try_into().expect("Invalid frame number length"))
+        let start = common::AUDIO_PAYLOAD_LENGTH;
+        let frame_number_bytes = &packet[start..start + common::FRAME_NUMBER_LENGTH];
+        u32::from_le_bytes(frame_number_bytes.try_into().expect("Invalid frame number length")) as u64
     }
 }
 
@@ -357,4 +480,124 @@ mod tests {
 
         }
     }
+
+    type CallbackResults = Arc<Mutex<Vec<(FrameType, Vec<u8>)>>>;
+
+    fn collect_frames(parser: &mut Parser) -> CallbackResults {
+        let callback_results = Arc::new(Mutex::new(Vec::<(FrameType, Vec<u8>)>::new()));
+        let callback_results_clone = Arc::clone(&callback_results);
+        parser.set_callback(move |frame_type, data| {
+            callback_results_clone.lock().unwrap().push((frame_type, data.to_vec()));
+        });
+        callback_results
+    }
+
+    #[test]
+    fn test_device_reset() {
+        let path = "tests/data/CoolTerm Capture (Untitled_1) 2024-12-21 12-24-34-830.txt";
+        let data = test_utils::read_file_as_bytes(path).expect("Failed to read the file");
+
+        // 1️⃣ Banner reappears: the earbud rebooted and streams the same capture again
+        let mut parser = Parser::new(common::TARGET_SEQUENCE.to_vec());
+        let callback_results = collect_frames(&mut parser);
+        parser.push_data(&data);
+        parser.push_data(&data);
+        parser.process();
+
+        {
+            let results = callback_results.lock().unwrap();
+            let resets: Vec<usize> = results.iter()
+                .enumerate()
+                .filter(|(_, (frame_type, _))| *frame_type == FrameType::DeviceReset)
+                .map(|(i, _)| i)
+                .collect();
+            assert_eq!(resets.len(), 1, "Banner and counter restart should report a single reset");
+
+            let reset = resets[0];
+            assert_eq!(results[reset - 1].0, FrameType::LogData, "Logs before the banner stay in the old boot");
+            assert_eq!(results[reset + 1].0, FrameType::LogData, "Banner should open the new boot");
+            assert!(results[reset + 1].1.starts_with(b"CHIP="), "Banner should start the new log segment");
+            assert_eq!(results[reset + 2].0, FrameType::AudioData, "First frame of the new boot should follow");
+            assert_eq!(0, Parser::extract_frame_number(&results[reset + 2].1), "Frame counter should restart at 0");
+        }
+
+        // 2️⃣ Counter restart without a banner, e.g. the banner was lost to a framing error
+        let mut parser = Parser::new(common::TARGET_SEQUENCE.to_vec());
+        let callback_results = collect_frames(&mut parser);
+        parser.push_data(&data);
+        parser.push_data(&data[7361 - 4004..]);
+        parser.process();
+
+        {
+            let results = callback_results.lock().unwrap();
+            let reset = results.iter()
+                .position(|(frame_type, _)| *frame_type == FrameType::DeviceReset)
+                .expect("Counter restart should report a reset");
+            assert_eq!(results[reset - 2].0, FrameType::AudioData, "Reset should follow the old boot");
+            assert_eq!(77, Parser::extract_frame_number(&results[reset - 2].1), "Last frame of the old boot should be 77");
+            assert_eq!(0, Parser::extract_frame_number(&results[reset + 1].1), "Frame counter should restart at 0");
+        }
+    }
+
+    #[test]
+    fn test_bad_frame() {
+        let path = "tests/data/CoolTerm Capture (Untitled_1) 2024-12-21 12-24-34-830.txt";
+        let data = test_utils::read_file_as_bytes(path).expect("Failed to read the file");
+
+        // Frame 0, the second half of frame 1, frame 2
+        let mut parser = Parser::new(common::TARGET_SEQUENCE.to_vec());
+        let callback_results = collect_frames(&mut parser);
+        parser.push_data(&data[3357..7369]);
+        parser.push_data(&data[7369 + 2000..11381]);
+        parser.push_data(&data[11381..15393]);
+        parser.process();
+
+        let results = callback_results.lock().unwrap();
+        let frame_types: Vec<&FrameType> = results.iter().map(|(frame_type, _)| frame_type).collect();
+        assert_eq!(frame_types, [&FrameType::AudioData, &FrameType::BadFrame, &FrameType::AudioData]);
+        assert_eq!(results[1].1.len(), 2012, "Bad frame holds the bytes since the previous frame");
+        assert_eq!(2, Parser::extract_frame_number(&results[2].1), "Next frame is intact");
+    }
+
+    #[test]
+    fn test_receive_time() {
+        let path = "tests/data/CoolTerm Capture (Untitled_1) 2024-12-21 12-24-34-830.txt";
+        let data = test_utils::read_file_as_bytes(path).expect("Failed to read the file");
+
+        let callback_results = Arc::new(Mutex::new(Vec::<(FrameType, ReceiveTime)>::new()));
+        let callback_results_clone = Arc::clone(&callback_results);
+        let mut parser = Parser::new(common::TARGET_SEQUENCE.to_vec());
+        parser.set_timed_callback(move |frame_type, _, received| {
+            callback_results_clone.lock().unwrap().push((frame_type, received));
+        });
+
+        // Chunks read at 0, 1, 2 and 3 ms
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+        parser.push_data_at(&data[0..5000], at(0));
+        parser.push_data_at(&data[5000..10000], at(1));
+        parser.process();
+        parser.push_data_at(&data[10000..12000], at(2));
+        parser.process();
+        parser.push_data_at(&data[12000..19500], at(3));
+        parser.process();
+
+        let results = callback_results.lock().unwrap();
+        assert_eq!(results.len(), 5);
+        // Log 0..3357, audio 3357..7369, audio 7369..11381, audio 11381..15393, audio 15393..19405
+        assert_eq!(results[0], (FrameType::LogData, ReceiveTime { first_byte: at(0), last_byte: at(0) }));
+        assert_eq!(results[1], (FrameType::AudioData, ReceiveTime { first_byte: at(0), last_byte: at(1) }));
+        assert_eq!(results[2], (FrameType::AudioData, ReceiveTime { first_byte: at(1), last_byte: at(2) }), "Time of the last byte comes from a later chunk");
+        assert_eq!(results[3], (FrameType::AudioData, ReceiveTime { first_byte: at(2), last_byte: at(3) }));
+        assert_eq!(results[4], (FrameType::AudioData, ReceiveTime { first_byte: at(3), last_byte: at(3) }));
+        assert_eq!(parser.chunk_times.len(), 1, "Times of drained chunks are dropped");
+        drop(results);
+
+        parser.reset();
+        assert_eq!((parser.queued_bytes(), parser.chunk_times.len()), (0, 0), "The partial frame is dropped");
+        parser.push_data_at(&data[15393..19405], at(4));
+        parser.process();
+        let results = callback_results.lock().unwrap();
+        assert_eq!(results[5], (FrameType::AudioData, ReceiveTime { first_byte: at(4), last_byte: at(4) }), "Frames after a reset line up");
+    }
 }
@@ -0,0 +1,98 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::sync::mpsc::Sender;
+use crate::logs::decoder;
+
+/// Log pane of the terminal UI, for errors reported away from the handler's console
+static UI_ERRORS: OnceLock<Sender<String>> = OnceLock::new();
+
+/// Report an error from where the console is out of reach, such as a file finished on drop or an HTTP client thread:
+/// into the terminal UI's log pane while it runs, so the screen is not garbled, otherwise to stderr
+pub fn report_error(line: fmt::Arguments) {
+    match UI_ERRORS.get() {
+        Some(sender) if sender.send(line.to_string()).is_ok() => {}
+        _ => eprintln!("{}", line),
+    }
+}
+
+/// Where the receiver prints log lines and status messages
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConsoleTarget {
+    Stdout,
+    /// Keeps stdout free for an audio stream
+    Stderr,
+    /// A file, without ANSI colors
+    File(PathBuf),
+}
+
+enum Output {
+    Writer(Box<dyn Write + Send>),
+    /// Lines for the terminal UI
+    Channel(Sender<String>),
+}
+
+/// Prints lines to the console target
+pub struct Console {
+    output: Output,
+    strip_ansi: bool,
+    /// Errors go to stderr rather than between the lines on stdout
+    stdout: bool,
+}
+
+impl Console {
+    /// `strip_ansi` removes colors on the terminal too; files never get them
+    pub fn open(target: &ConsoleTarget, strip_ansi: bool) -> io::Result<Self> {
+        let writer = |output: Box<dyn Write + Send>, strip_ansi| Self { output: Output::Writer(output), strip_ansi, stdout: false };
+        Ok(match target {
+            ConsoleTarget::Stdout => Self { stdout: true, ..writer(Box::new(io::stdout()), strip_ansi) },
+            ConsoleTarget::Stderr => writer(Box::new(io::stderr()), strip_ansi),
+            ConsoleTarget::File(path) => writer(Box::new(BufWriter::new(File::create(path)?)), true),
+        })
+    }
+
+    /// Send the lines, without ANSI colors, to the terminal UI; `report_error` sends there too from then on
+    pub fn channel(sender: Sender<String>) -> Self {
+        let _ = UI_ERRORS.set(sender.clone());
+        Self { output: Output::Channel(sender), strip_ansi: true, stdout: false }
+    }
+
+    /// Whether a line per audio frame can be printed; the terminal UI shows frame statistics instead
+    pub fn shows_frames(&self) -> bool {
+        matches!(self.output, Output::Writer(_))
+    }
+
+    /// Print an error where it does not garble the output: stderr next to lines on stdout,
+    /// otherwise the console file or the terminal UI's log pane
+    pub fn error(&mut self, line: fmt::Arguments) {
+        if self.stdout {
+            eprintln!("{}", line);
+        } else {
+            self.print(line);
+        }
+    }
+
+    /// Print one line, e.g. `console.print(format_args!("{} - {}", timestamp, line))`
+    pub fn print(&mut self, line: fmt::Arguments) {
+        let line = if self.strip_ansi {
+            decoder::strip_ansi(&line.to_string())
+        } else {
+            line.to_string()
+        };
+        let result = match &mut self.output {
+            Output::Writer(output) => writeln!(output, "{}", line).and_then(|_| output.flush()),
+            // The terminal UI has quit when nobody receives, and the terminal is back to normal
+            Output::Channel(sender) => {
+                if let Err(unsent) = sender.send(line) {
+                    eprintln!("{}", unsent.0);
+                }
+                Ok(())
+            }
+        };
+        if let Err(e) = result {
+            eprintln!("Failed to print to the console: {}", e);
+        }
+    }
+}
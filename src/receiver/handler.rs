@@ -0,0 +1,638 @@
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use chrono::{DateTime, Local};
+use serde_json::Value;
+use crate::analysis::analyzer::{AnalysisEvent, AudioAnalyzer};
+use crate::analysis::spectrogram::Spectrogram;
+use crate::analysis::spectrum::{self, SpectrumAnalyzer};
+use crate::audio::rtp::RtpSender;
+use crate::audio::stream::AudioStream;
+use crate::config::settings::{AudioFormat, Settings};
+use crate::constants::common;
+use crate::dsp::chain::{AudioSink, DspChain, ProcessorSettings};
+use crate::logs::decoder::{self, AnsiMode, LogDecoder};
+use crate::logs::filter::LogFilter;
+use crate::logs::line_buffer::LineBuffer;
+use crate::logs::line_parser::LogLineParser;
+use crate::parser::parser::{FrameType, Parser, ReceiveTime};
+use crate::recorder::session::{Recorder, SessionInfo};
+use crate::recorder::trigger::{self, RecordingGate, TriggerAction, TriggerEvent};
+use crate::server::feed::LiveFeed;
+use crate::server::http::HttpServer;
+use crate::timing::device_clock::DeviceClock;
+use crate::timing::rate_estimator::{RateEstimate, RateEstimator};
+use super::console::Console;
+use super::link_stats::LinkStats;
+
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.3f";
+/// Boot banner values stored in the wav metadata
+const FIRMWARE_INFO_KEYS: [&str; 2] = ["REV_INFO", "BUILD_DATE"];
+/// Audio frames between rewrites of the analysis summary, one second
+const AUDIO_SUMMARY_FRAMES: u64 = 24;
+/// Seconds of frame arrivals the sample rate is estimated from
+const RATE_WINDOW_SECONDS: f64 = 10.0;
+
+/// What is rebuilt when the estimated sample rate is applied, with `--use-measured-rate`
+struct MeasuredRateSettings {
+    dsp: Vec<ProcessorSettings>,
+    dither: bool,
+    pre_roll_seconds: f64,
+    post_roll_seconds: f64,
+    /// SDP file of the RTP stream, rewritten with the new rate
+    sdp_path: Option<PathBuf>,
+    session_name: String,
+}
+
+/// Handles the frames emitted by the parser: prints them and writes them to the recorder
+pub struct FrameHandler {
+    console: Console,
+    recorder: Recorder,
+    /// Print a line per audio frame, with `--verbose`
+    print_frames: bool,
+    /// Live audio stream, dropped once its reader goes away
+    stream: Option<AudioStream>,
+    rtp: Option<RtpSender>,
+    /// Published to the HTTP server and the terminal UI, if they run
+    feed: Option<Arc<LiveFeed>>,
+    /// Processes every received frame for the live sinks that take processed audio
+    live_dsp: Option<DspChain>,
+    /// Which live sinks get the processed audio: stream, RTP and HTTP
+    live_processed: [bool; 3],
+    log_decoder: LogDecoder,
+    log_lines: LineBuffer,
+    log_parser: LogLineParser,
+    log_filter: LogFilter,
+    recording_gate: RecordingGate,
+    analyzer: AudioAnalyzer,
+    spectrum: SpectrumAnalyzer,
+    /// Spectra of the whole session, if a spectrogram is rendered
+    spectrogram: Option<Spectrogram>,
+    device_clock: DeviceClock,
+    /// The same moment on the monotonic and the wall clock, to convert receive times
+    clock_origin: (Instant, DateTime<Local>),
+    /// Audio duration of a frame and the rate of the received audio
+    frame_seconds: f64,
+    sample_rate: u32,
+    rate_estimator: RateEstimator,
+    /// Standard rate the frames last arrived at, and the one last warned about
+    estimated_sample_rate: Option<u32>,
+    warned_sample_rate: Option<u32>,
+    /// Take the audio as the estimated rate from then on
+    measured_rate: Option<MeasuredRateSettings>,
+    /// Rewritten when the estimated sample rate changes
+    manifest: Value,
+    /// Filled by the serial read loop, checked for overruns when frames go missing
+    link_stats: Arc<Mutex<LinkStats>>,
+    last_frame_number: Option<u64>,
+}
+
+fn invalid_input(error: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, error)
+}
+
+impl FrameHandler {
+    /// Print to `console`, which is the terminal UI's log pane in `--tui` mode
+    pub fn new(settings: &Settings, session: SessionInfo, mut console: Console) -> io::Result<Self> {
+        let ansi_mode = if settings.strip_ansi {
+            AnsiMode::Strip
+        } else {
+            AnsiMode::Render
+        };
+        let stream = match &settings.stream {
+            Some(target) => Some(AudioStream::open(target, settings.stream_format,
+                settings.sink_sample_rate(AudioSink::Stream), common::CHANNELS, common::BITS_PER_SAMPLE)?),
+            None => None,
+        };
+        let sdp_path = settings.rtp.as_ref().map(|_| settings.output_dir.join(format!("{}.sdp", session.name)));
+        let rtp = match (&settings.rtp, &sdp_path) {
+            (Some(destination), Some(sdp_path)) => {
+                let rtp = RtpSender::connect(destination, settings.sink_sample_rate(AudioSink::Rtp), common::CHANNELS,
+                    common::frame_seconds(settings.sample_rate))?;
+                std::fs::create_dir_all(&settings.output_dir)?;
+                std::fs::write(sdp_path, rtp.sdp(&session.name)?)?;
+                console.print(format_args!("Sending RTP to {}, described by {}", destination, sdp_path.display()));
+                Some(rtp)
+            },
+            _ => None,
+        };
+        let measured_rate = settings.use_measured_sample_rate.then(|| MeasuredRateSettings {
+            dsp: settings.dsp.clone(),
+            dither: settings.dither,
+            pre_roll_seconds: settings.pre_roll_seconds,
+            post_roll_seconds: settings.post_roll_seconds,
+            sdp_path,
+            session_name: session.name.clone(),
+        });
+        let feed = (settings.http.is_some() || settings.tui).then(|| {
+            Arc::new(LiveFeed::new(&session.name, &settings.port, settings.sink_sample_rate(AudioSink::Http)))
+        });
+        if let (Some(address), Some(feed)) = (&settings.http, &feed) {
+            let bound = HttpServer::start(address, Arc::clone(feed))?;
+            console.print(format_args!("Serving http://{}/audio.wav, /log and /status", bound));
+        }
+        if settings.normalize_dbfs.is_some() && settings.formats.contains(&AudioFormat::Flac) && settings.is_processed(AudioSink::Flac) {
+            console.print(format_args!("FLAC files are not normalized, only the processed wav files"));
+        }
+        let dsp = || DspChain::new(&settings.dsp, settings.sample_rate, settings.output_sample_rate(), common::CHANNELS, settings.dither)
+            .map_err(invalid_input);
+        let manifest = Self::manifest(settings, &session);
+        let mut recorder = Recorder::new(&settings.output_dir, session, settings.audio_file_options())?;
+        recorder.set_dsp(dsp()?);
+        recorder.write_session_summary("manifest", &manifest)?;
+        let live_processed = [
+            stream.is_some() && settings.is_processed(AudioSink::Stream),
+            rtp.is_some() && settings.is_processed(AudioSink::Rtp),
+            settings.http.is_some() && settings.is_processed(AudioSink::Http),
+        ];
+        let live_dsp = if live_processed.contains(&true) { dsp()? } else { None };
+        Ok(Self {
+            print_frames: settings.verbose && console.shows_frames(),
+            console,
+            recorder,
+            stream,
+            rtp,
+            feed,
+            live_dsp,
+            live_processed,
+            log_decoder: LogDecoder::new(ansi_mode),
+            log_lines: LineBuffer::new(),
+            log_parser: LogLineParser::from_settings(&settings.log_rule, settings.default_log_rules).map_err(invalid_input)?,
+            log_filter: LogFilter::new(&settings.log_filter).map_err(invalid_input)?,
+            recording_gate: RecordingGate::new(
+                    &settings.trigger,
+                    trigger::seconds_to_frames(settings.pre_roll_seconds, settings.sample_rate),
+                    trigger::seconds_to_frames(settings.post_roll_seconds, settings.sample_rate))
+                .map_err(invalid_input)?,
+            analyzer: AudioAnalyzer::new(&settings.analysis, settings.sample_rate),
+            spectrum: SpectrumAnalyzer::new(settings.sample_rate, common::CHANNELS),
+            spectrogram: settings.analysis.spectrogram.then(Spectrogram::new),
+            device_clock: DeviceClock::new(settings.sample_rate),
+            clock_origin: (Instant::now(), Local::now()),
+            frame_seconds: common::frame_seconds(settings.sample_rate),
+            sample_rate: settings.sample_rate,
+            rate_estimator: RateEstimator::new(RATE_WINDOW_SECONDS),
+            estimated_sample_rate: None,
+            warned_sample_rate: None,
+            measured_rate,
+            manifest,
+            link_stats: Arc::new(Mutex::new(LinkStats::new(settings.baudrate, common::SERIAL_READ_SIZE))),
+            last_frame_number: None,
+        })
+    }
+
+    /// What the session's files hold, with the sample rate of the received audio and of each sink
+    fn manifest(settings: &Settings, session: &SessionInfo) -> Value {
+        let mut sinks = serde_json::Map::new();
+        let enabled = [
+            (AudioSink::Wav, "wav", settings.formats.contains(&AudioFormat::Wav)),
+            (AudioSink::Flac, "flac", settings.formats.contains(&AudioFormat::Flac)),
+            (AudioSink::Stream, "stream", settings.stream.is_some()),
+            (AudioSink::Rtp, "rtp", settings.rtp.is_some()),
+            (AudioSink::Http, "http", settings.http.is_some()),
+        ];
+        for (sink, name, _) in enabled.into_iter().filter(|(_, _, enabled)| *enabled) {
+            sinks.insert(name.to_string(), serde_json::json!({
+                "sample_rate": settings.sink_sample_rate(sink),
+                "processed": settings.is_processed(sink),
+            }));
+        }
+        serde_json::json!({
+            "session": session.name,
+            "port": session.port,
+            "start": session.start.format(TIMESTAMP_FORMAT).to_string(),
+            "source_sample_rate": settings.sample_rate,
+            "measured_sample_rate": settings.measured_sample_rate,
+            "estimated_sample_rate": Value::Null,
+            "output_sample_rate": settings.output_sample_rate(),
+            "channels": common::CHANNELS,
+            "bits_per_sample": common::BITS_PER_SAMPLE,
+            "sinks": sinks,
+        })
+    }
+
+    /// Print a status line next to the log output
+    pub fn print(&mut self, line: std::fmt::Arguments) {
+        self.console.print(line);
+    }
+
+    /// Print an error without garbling the log output
+    pub fn error(&mut self, line: std::fmt::Arguments) {
+        self.console.error(line);
+    }
+
+    /// The live feed, when the HTTP server or the terminal UI needs it
+    pub fn feed(&self) -> Option<Arc<LiveFeed>> {
+        self.feed.clone()
+    }
+
+    /// The serial port was opened again after a read error
+    pub fn on_serial_reconnect(&mut self, port: &str) {
+        let timestamp = Local::now().format(TIMESTAMP_FORMAT).to_string();
+        self.console.print(format_args!("{} - SERIAL reconnected to {}", timestamp, port));
+        if let Some(feed) = &self.feed {
+            feed.on_reconnect();
+        }
+    }
+
+    /// Serial link figures, for the read loop to fill
+    pub fn link_stats(&self) -> Arc<Mutex<LinkStats>> {
+        Arc::clone(&self.link_stats)
+    }
+
+    fn lock_link_stats(&self) -> std::sync::MutexGuard<'_, LinkStats> {
+        self.link_stats.lock().expect("Failed to lock link stats mutex")
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording_gate.is_recording()
+    }
+
+    /// Start or stop recording by hand, like a start or stop trigger
+    pub fn toggle_recording(&mut self) {
+        let action = if self.is_recording() { TriggerAction::Stop } else { TriggerAction::Start };
+        let timestamp = Local::now().format(TIMESTAMP_FORMAT).to_string();
+        if let Some(event) = self.recording_gate.trigger(action, "") {
+            self.on_trigger_event(event, &timestamp);
+        }
+    }
+
+    /// Drop a marker by hand, like a mark trigger
+    pub fn mark(&mut self, label: &str) {
+        let timestamp = Local::now().format(TIMESTAMP_FORMAT).to_string();
+        if let Some(event) = self.recording_gate.trigger(TriggerAction::Mark, label) {
+            self.console.print(format_args!("{} - MARK: {}", timestamp, label));
+            self.on_trigger_event(event, &timestamp);
+        }
+    }
+
+    fn wall_time(&self, instant: Instant) -> DateTime<Local> {
+        let (origin_instant, origin_time) = self.clock_origin;
+        // Data read before the handler existed, such as the sample rate measurement, lies before the origin
+        match instant.checked_duration_since(origin_instant) {
+            Some(elapsed) => origin_time + chrono::Duration::from_std(elapsed).unwrap_or_default(),
+            None => origin_time - chrono::Duration::from_std(origin_instant - instant).unwrap_or_default(),
+        }
+    }
+
+    pub fn handle(&mut self, frame_type: FrameType, data: &[u8], received: ReceiveTime) {
+        match frame_type {
+            FrameType::LogData => self.on_log_data(data, received),
+            FrameType::AudioData => self.on_audio_data(data, received),
+            FrameType::DeviceReset => self.on_device_reset(received),
+            FrameType::BadFrame => self.on_bad_frame(data, received),
+        }
+    }
+
+    fn on_bad_frame(&mut self, data: &[u8], received: ReceiveTime) {
+        let timestamp = self.wall_time(received.last_byte).format(TIMESTAMP_FORMAT).to_string();
+        self.console.print(format_args!("{} - BAD FRAME of {} bytes dropped", timestamp, data.len()));
+        self.lock_link_stats().on_bad_frame(received.last_byte);
+        if let Some(feed) = &self.feed {
+            feed.on_bad_frame(data.len(), received.last_byte);
+        }
+    }
+
+    fn on_log_data(&mut self, data: &[u8], received: ReceiveTime) {
+        let decoded_string = self.log_decoder.decode(data);
+        let plain_string = decoder::strip_ansi(&decoded_string);
+        // The first byte is the closest we get to when the firmware printed the chunk
+        let timestamp = self.wall_time(received.first_byte).format(TIMESTAMP_FORMAT).to_string();
+        if let Some(feed) = &self.feed {
+            feed.on_log_data(data.len(), received.last_byte);
+        }
+
+        for line in self.log_lines.push(&decoded_string) {
+            let plain_line = decoder::strip_ansi(&line);
+            if let Some(feed) = &self.feed {
+                feed.on_log_line(&format!("{} - {}", timestamp, plain_line.trim_end()));
+            }
+            if let Some((key, value)) = plain_line.trim().split_once('=') {
+                if FIRMWARE_INFO_KEYS.contains(&key) {
+                    if let Err(e) = self.recorder.set_firmware_info(key, value) {
+                        self.console.error(format_args!("Failed to write firmware info: {}", e));
+                    }
+                }
+            }
+            for event in self.recording_gate.on_log_line(&plain_line) {
+                self.on_trigger_event(event, &timestamp);
+            }
+            if !plain_line.trim().is_empty() {
+                if let Err(e) = self.recorder.write_log_line_label(plain_line.trim()) {
+                    self.console.error(format_args!("Failed to write log label: {}", e));
+                }
+            }
+            // Filter whole lines for the terminal, the files keep everything
+            if let Some(shown) = self.log_filter.apply(&line, &plain_line) {
+                self.console.print(format_args!("{} - {}", timestamp, shown));
+            }
+        }
+
+        if let Err(e) = self.recorder.write_log(&format!("{} - {}", timestamp, plain_string)) {
+            self.console.error(format_args!("Failed to write log: {}", e));
+        }
+        for record in self.log_parser.push(&plain_string, &timestamp) {
+            self.write_log_record(record);
+        }
+    }
+
+    fn on_trigger_event(&mut self, event: TriggerEvent, timestamp: &str) {
+        match event {
+            TriggerEvent::Started { pre_roll } => {
+                self.console.print(format_args!("{} - RECORDING started with {} pre-roll frames", timestamp, pre_roll.len()));
+                for frame in pre_roll {
+                    self.write_audio(&frame);
+                }
+            },
+            TriggerEvent::Stopping => {
+                self.console.print(format_args!("{} - RECORDING stopping after the post-roll", timestamp));
+            },
+            TriggerEvent::Marked { label } => {
+                if let Err(e) = self.recorder.write_marker(timestamp, &label) {
+                    self.console.error(format_args!("Failed to write marker: {}", e));
+                }
+            },
+        }
+    }
+
+    /// Add the device timeline position to a parsed log record and write it
+    fn write_log_record(&mut self, mut record: Value) {
+        if let Some(device_seconds) = self.device_clock.device_seconds() {
+            record["device_time"] = Value::from(device_seconds);
+            if let Some(host_time) = self.device_clock.host_time(device_seconds) {
+                record["host_time"] = Value::from(host_time.format(TIMESTAMP_FORMAT).to_string());
+            }
+        }
+        if let Err(e) = self.recorder.write_log_record(&record) {
+            self.console.error(format_args!("Failed to write log record: {}", e));
+        }
+    }
+
+    fn on_audio_data(&mut self, data: &[u8], received: ReceiveTime) {
+        let frame_number = Parser::extract_frame_number(data);
+        // The frame is complete once its last byte arrived
+        let received_time = self.wall_time(received.last_byte);
+        let timing = self.device_clock.on_frame(frame_number, received_time);
+        let latency = received.last_byte.elapsed();
+        if let Some(last) = self.last_frame_number.replace(frame_number) {
+            if frame_number > last + 1 {
+                self.on_frame_gap(frame_number - last - 1, received.last_byte, &received_time.format(TIMESTAMP_FORMAT).to_string());
+            }
+        }
+        if self.print_frames {
+            self.console.print(format_args!("{} - AUDIO Frame Received Length: {}, frame_number: {}, device_time: {:.3}, captured: {}, latency: {:.1} ms",
+                received_time.format(TIMESTAMP_FORMAT), data.len(), frame_number,
+                timing.device_seconds, timing.host_time.format(TIMESTAMP_FORMAT), latency.as_secs_f64() * 1000.0));
+        }
+        let raw = &data[..common::AUDIO_PAYLOAD_LENGTH];
+        let processed = self.live_dsp.as_mut().map(|dsp| dsp.process(raw));
+        let [stream_payload, rtp_payload, http_payload] = self.live_processed.map(|use_processed| match &processed {
+            Some(processed) if use_processed => processed.as_slice(),
+            _ => raw,
+        });
+        // The live stream carries every frame, whether or not the triggers record it
+        if let Some(stream) = &mut self.stream {
+            if let Err(e) = stream.write_pcm(stream_payload) {
+                self.console.error(format_args!("Audio stream closed, recording continues: {}", e));
+                self.stream = None;
+            }
+        }
+        if let Some(feed) = &self.feed {
+            feed.on_audio_frame(frame_number, data.len(), http_payload, received.last_byte);
+        }
+        if let Some(rtp) = &mut self.rtp {
+            if let Err(e) = rtp.send_frame(frame_number, rtp_payload) {
+                self.console.error(format_args!("Failed to send RTP: {}", e));
+            }
+        }
+        self.rate_estimator.push(frame_number, received.last_byte);
+        let timestamp = received_time.format(TIMESTAMP_FORMAT).to_string();
+        if frame_number.is_multiple_of(AUDIO_SUMMARY_FRAMES) {
+            if let Some(estimate) = self.rate_estimator.estimate() {
+                self.on_rate_estimate(estimate, &timestamp);
+            }
+        }
+        self.analyze_audio(frame_number, raw, &timestamp);
+        if self.recording_gate.on_audio_frame(data) {
+            self.write_audio(data);
+        }
+    }
+
+    /// Report frames lost when the host most likely fell behind the serial link
+    fn on_frame_gap(&mut self, lost_frames: u64, received: Instant, timestamp: &str) {
+        let mut link_stats = self.lock_link_stats();
+        let Some(reason) = link_stats.on_frame_gap(lost_frames, received) else {
+            return;
+        };
+        let summary = link_stats.summary();
+        drop(link_stats);
+        self.console.print(format_args!("{} - SERIAL likely overrun, {} frames lost: {}, link {:.0}% busy, {:.1} kB queued",
+            timestamp, lost_frames, reason, summary.uart_utilization * 100.0, summary.queue_bytes as f64 / 1000.0));
+        let record = serde_json::json!({
+            "event": "serial_overrun",
+            "timestamp": timestamp,
+            "lost_frames": lost_frames,
+            "reason": reason,
+            "uart_utilization": summary.uart_utilization,
+            "queue_bytes": summary.queue_bytes,
+        });
+        if let Err(e) = self.recorder.write_log_record(&record) {
+            self.console.error(format_args!("Failed to write the serial overrun: {}", e));
+        }
+    }
+
+    /// Note the rate the frames arrive at and warn once per rate that disagrees with the configured one
+    fn on_rate_estimate(&mut self, estimate: RateEstimate, timestamp: &str) {
+        if let Some(feed) = &self.feed {
+            feed.on_rate_estimate(estimate);
+        }
+        if self.estimated_sample_rate != Some(estimate.sample_rate) {
+            self.estimated_sample_rate = Some(estimate.sample_rate);
+            self.manifest["estimated_sample_rate"] = serde_json::to_value(estimate).expect("Estimate serializes");
+            if let Err(e) = self.recorder.write_session_summary("manifest", &self.manifest) {
+                self.console.error(format_args!("Failed to write the manifest: {}", e));
+            }
+        }
+        if estimate.sample_rate == self.sample_rate || self.warned_sample_rate == Some(estimate.sample_rate) {
+            return;
+        }
+        self.warned_sample_rate = Some(estimate.sample_rate);
+        self.console.print(format_args!("{} - SAMPLE RATE frames arrive at {:.0} Hz, {} Hz, but the audio is taken as {} Hz",
+            timestamp, estimate.measured, estimate.sample_rate, self.sample_rate));
+        let record = serde_json::json!({
+            "event": "sample_rate_mismatch",
+            "timestamp": timestamp,
+            "measured": estimate.measured,
+            "estimated_sample_rate": estimate.sample_rate,
+            "sample_rate": self.sample_rate,
+        });
+        if let Err(e) = self.recorder.write_log_record(&record) {
+            self.console.error(format_args!("Failed to write the sample rate mismatch: {}", e));
+        }
+        if self.measured_rate.is_some() {
+            match self.apply_sample_rate(estimate.sample_rate, timestamp) {
+                Ok(()) => self.console.print(format_args!("{} - SAMPLE RATE set to {} Hz", timestamp, estimate.sample_rate)),
+                Err(e) => self.console.error(format_args!("Failed to correct the sample rate: {}", e)),
+            }
+        }
+    }
+
+    /// Take the received audio as `sample_rate` from now on, in the files, the analysis and the live sinks.
+    /// Resampling is ruled out with `--use-measured-rate`, so every sink gets the received rate.
+    fn apply_sample_rate(&mut self, sample_rate: u32, timestamp: &str) -> io::Result<()> {
+        let Some(rate_settings) = &self.measured_rate else {
+            return Ok(());
+        };
+        if self.stream.as_ref().is_some_and(AudioStream::has_header) {
+            self.console.print(format_args!("{} - SAMPLE RATE the wav header of the live stream keeps {} Hz", timestamp, self.sample_rate));
+        }
+        let dsp = || DspChain::new(&rate_settings.dsp, sample_rate, sample_rate, common::CHANNELS, rate_settings.dither)
+            .map_err(invalid_input);
+        self.recorder.set_sample_rate(sample_rate)?;
+        self.recorder.set_dsp(dsp()?);
+        if self.live_processed.contains(&true) {
+            self.live_dsp = dsp()?;
+        }
+        self.sample_rate = sample_rate;
+        self.frame_seconds = common::frame_seconds(sample_rate);
+        self.analyzer.set_sample_rate(sample_rate);
+        self.spectrum = SpectrumAnalyzer::new(sample_rate, common::CHANNELS);
+        self.device_clock.set_sample_rate(sample_rate);
+        self.recording_gate.set_roll_frames(
+            trigger::seconds_to_frames(rate_settings.pre_roll_seconds, sample_rate),
+            trigger::seconds_to_frames(rate_settings.post_roll_seconds, sample_rate));
+        if let Some(rtp) = &mut self.rtp {
+            rtp.set_sample_rate(sample_rate, self.frame_seconds);
+            if let Some(sdp_path) = &rate_settings.sdp_path {
+                std::fs::write(sdp_path, rtp.sdp(&rate_settings.session_name)?)?;
+            }
+        }
+        if let Some(feed) = &self.feed {
+            feed.set_sample_rate(sample_rate);
+        }
+
+        self.manifest["source_sample_rate"] = Value::from(sample_rate);
+        self.manifest["output_sample_rate"] = Value::from(sample_rate);
+        if let Some(sinks) = self.manifest["sinks"].as_object_mut() {
+            for sink in sinks.values_mut() {
+                sink["sample_rate"] = Value::from(sample_rate);
+            }
+        }
+        self.recorder.write_session_summary("manifest", &self.manifest)
+    }
+
+    /// Measure the levels of every received frame, recorded or not, and log what changed
+    fn analyze_audio(&mut self, frame_number: u64, payload: &[u8], timestamp: &str) {
+        let (analysis, events) = self.analyzer.analyze(frame_number, payload);
+        if let Some(feed) = &self.feed {
+            feed.on_frame_analysis(&analysis);
+        }
+        for event in events {
+            self.on_analysis_event(event, timestamp);
+        }
+        for spectrum in self.spectrum.push(payload) {
+            if let Some(feed) = &self.feed {
+                feed.on_tone(spectrum.tone());
+            }
+            if let Some(spectrogram) = &mut self.spectrogram {
+                spectrogram.push(&spectrum);
+            }
+        }
+
+        // Keep the summary current in case the receiver is killed; the spectrogram is only rendered at the end
+        let frames = self.analyzer.summary().frames;
+        if frames.is_multiple_of(AUDIO_SUMMARY_FRAMES) {
+            self.write_analysis_summary();
+            self.write_link_summary();
+        }
+    }
+
+    fn write_analysis_summary(&mut self) {
+        let summary = serde_json::to_value(self.analyzer.summary()).expect("Summary serializes");
+        if let Err(e) = self.recorder.write_session_summary("analysis", &summary) {
+            self.console.error(format_args!("Failed to write the analysis summary: {}", e));
+        }
+    }
+
+    fn write_link_summary(&mut self) {
+        let summary = self.lock_link_stats().summary();
+        if let Some(feed) = &self.feed {
+            feed.on_link_summary(summary.clone());
+        }
+        let summary = serde_json::to_value(summary).expect("Link summary serializes");
+        if let Err(e) = self.recorder.write_session_summary("link", &summary) {
+            self.console.error(format_args!("Failed to write the link summary: {}", e));
+        }
+    }
+
+    fn write_spectrogram(&mut self) {
+        if let Some(spectrogram) = self.spectrogram.as_ref().filter(|spectrogram| !spectrogram.is_empty()) {
+            let hop_seconds = spectrum::HOP as f64 / self.sample_rate as f64;
+            if let Err(e) = spectrogram.write_png(self.recorder.session_file("spectrogram.png"), hop_seconds) {
+                self.console.error(format_args!("Failed to write the spectrogram: {}", e));
+            }
+        }
+    }
+
+    /// Finish the files at the end of the session: normalize the audio and write the last summaries
+    pub fn finish(&mut self) {
+        if let Err(e) = self.recorder.finish() {
+            self.console.error(format_args!("Failed to finish the recording: {}", e));
+        }
+        self.write_analysis_summary();
+        self.write_link_summary();
+        self.write_spectrogram();
+    }
+
+    fn on_analysis_event(&mut self, event: AnalysisEvent, timestamp: &str) {
+        self.console.print(format_args!("{} - AUDIO {}", timestamp, event));
+        let mut record = serde_json::to_value(&event).expect("Analysis event serializes");
+        record["timestamp"] = Value::from(timestamp);
+        if let Err(e) = self.recorder.write_log_record(&record) {
+            self.console.error(format_args!("Failed to write analysis event: {}", e));
+        }
+    }
+
+    fn write_audio(&mut self, frame: &[u8]) {
+        // The capture time of the first sample aligns the file with other recordings
+        if !self.recorder.has_audio() {
+            let frame_start = Parser::extract_frame_number(frame) as f64 * self.frame_seconds;
+            if let Some(captured) = self.device_clock.host_time(frame_start) {
+                if let Err(e) = self.recorder.set_time_reference(captured) {
+                    self.console.error(format_args!("Failed to write time reference: {}", e));
+                }
+            }
+        }
+        if let Err(e) = self.recorder.write_audio(frame) {
+            self.console.error(format_args!("Failed to write audio: {}", e));
+        }
+    }
+
+    fn on_device_reset(&mut self, received: ReceiveTime) {
+        let timestamp = self.wall_time(received.first_byte).format(TIMESTAMP_FORMAT).to_string();
+        // Keep the last line of the old boot in its own segment
+        if let Some(record) = self.log_parser.flush(&timestamp) {
+            self.write_log_record(record);
+        }
+        if let (Some(drift), Some(offset), Some(jitter)) =
+            (self.device_clock.drift_ppm(), self.device_clock.offset(), self.device_clock.jitter_seconds()) {
+            self.console.print(format_args!("{} - DEVICE CLOCK of the last boot started at {}, drift {:.1} ppm, receive jitter {:.1} ms",
+                timestamp, offset.format(TIMESTAMP_FORMAT), drift, jitter * 1000.0));
+        }
+        self.device_clock.reset();
+        self.rate_estimator.reset();
+        self.last_frame_number = None;
+        self.analyzer.reset();
+        if let Some(rtp) = &mut self.rtp {
+            rtp.reset();
+        }
+        match self.recorder.start_new_segment() {
+            Ok(()) => self.console.print(format_args!("{} - DEVICE RESET detected, starting segment {}", timestamp, self.recorder.segment())),
+            Err(e) => self.console.error(format_args!("Failed to start a new segment: {}", e)),
+        }
+        if let Some(feed) = &self.feed {
+            feed.on_device_reset(self.recorder.segment());
+        }
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use serde::Serialize;
+
+/// Window of the live figures
+const WINDOW: Duration = Duration::from_secs(5);
+/// Lost frames this soon after a sign of the host falling behind count as an overrun
+const OVERRUN_WINDOW: Duration = Duration::from_secs(1);
+/// UART utilization above which the host is taken to fall behind
+const BUSY_UTILIZATION: f64 = 0.95;
+/// Start, 8 data and stop bits
+const UART_BITS_PER_BYTE: f64 = 10.0;
+
+/// Figures of the serial link, served in `/status` and written as `session_<date>_link.json`
+#[derive(Serialize, Default, Clone, Debug, PartialEq)]
+pub struct LinkSummary {
+    pub baudrate: u32,
+    pub bytes: u64,
+    pub reads: u64,
+    pub mean_read_bytes: f64,
+    pub max_read_bytes: usize,
+    /// Reads that filled the buffer, so the driver had more waiting
+    pub full_reads: u64,
+    /// Time spent waiting in `port.read`, reads that timed out included
+    pub blocked_seconds: f64,
+    /// Over the last 5 seconds
+    pub bytes_per_second: f64,
+    pub blocked_ratio: f64,
+    pub uart_utilization: f64,
+    pub peak_uart_utilization: f64,
+    /// Bytes the parser had not processed yet when the last read arrived
+    pub queue_bytes: usize,
+    pub max_queue_bytes: usize,
+    /// Frame gaps that most likely come from the host dropping bytes rather than from the device
+    pub likely_overruns: u64,
+    pub overrun_lost_frames: u64,
+}
+
+/// A read from the serial port within the window
+struct Read {
+    time: Instant,
+    bytes: usize,
+    blocked: Duration,
+}
+
+/// Tracks the reads from the serial port and judges whether lost frames come from overruns
+pub struct LinkStats {
+    summary: LinkSummary,
+    read_size: usize,
+    start: Option<Instant>,
+    recent: VecDeque<Read>,
+    last_full_read: Option<Instant>,
+    last_bad_frame: Option<Instant>,
+}
+
+impl LinkStats {
+    /// `read_size` is the length of the read buffer
+    pub fn new(baudrate: u32, read_size: usize) -> Self {
+        Self {
+            summary: LinkSummary { baudrate, ..Default::default() },
+            read_size,
+            start: None,
+            recent: VecDeque::new(),
+            last_full_read: None,
+            last_bad_frame: None,
+        }
+    }
+
+    /// Count a read of `bytes` that waited `blocked` for data, arriving while `queue_bytes` still waited for the parser
+    pub fn on_read(&mut self, bytes: usize, blocked: Duration, queue_bytes: usize, now: Instant) {
+        let summary = &mut self.summary;
+        summary.bytes += bytes as u64;
+        summary.reads += 1;
+        summary.mean_read_bytes = summary.bytes as f64 / summary.reads as f64;
+        summary.max_read_bytes = summary.max_read_bytes.max(bytes);
+        summary.queue_bytes = queue_bytes;
+        summary.max_queue_bytes = summary.max_queue_bytes.max(queue_bytes);
+        if bytes >= self.read_size {
+            summary.full_reads += 1;
+            self.last_full_read = Some(now);
+        }
+        self.record(bytes, blocked, now);
+    }
+
+    /// Count a read that waited `blocked` and timed out without data
+    pub fn on_timeout(&mut self, blocked: Duration, now: Instant) {
+        self.record(0, blocked, now);
+    }
+
+    /// Add the time waited to the totals and update the figures of the window
+    fn record(&mut self, bytes: usize, blocked: Duration, now: Instant) {
+        let start = *self.start.get_or_insert(now - blocked);
+        let summary = &mut self.summary;
+        summary.blocked_seconds += blocked.as_secs_f64();
+        self.recent.push_back(Read { time: now, bytes, blocked });
+        while self.recent.front().is_some_and(|read| now.duration_since(read.time) > WINDOW) {
+            self.recent.pop_front();
+        }
+        let window = now.duration_since(start).min(WINDOW).as_secs_f64();
+        if window > 0.0 {
+            let bytes: usize = self.recent.iter().map(|read| read.bytes).sum();
+            let blocked: Duration = self.recent.iter().map(|read| read.blocked).sum();
+            summary.bytes_per_second = bytes as f64 / window;
+            summary.blocked_ratio = (blocked.as_secs_f64() / window).min(1.0);
+            summary.uart_utilization = summary.bytes_per_second * UART_BITS_PER_BYTE / summary.baudrate as f64;
+            // The first reads drain what piled up before the session
+            if now.duration_since(start) >= WINDOW {
+                summary.peak_uart_utilization = summary.peak_uart_utilization.max(summary.uart_utilization);
+            }
+        }
+    }
+
+    /// A frame cut short means bytes went missing on the way
+    pub fn on_bad_frame(&mut self, now: Instant) {
+        self.last_bad_frame = Some(now);
+    }
+
+    /// Judge a gap of `lost_frames` seen at `now`; returns why it is likely an overrun, if it is
+    pub fn on_frame_gap(&mut self, lost_frames: u64, now: Instant) -> Option<&'static str> {
+        let recent = |time: Option<Instant>| time.is_some_and(|time| now.saturating_duration_since(time) <= OVERRUN_WINDOW);
+        let reason = if recent(self.last_bad_frame) {
+            "a frame was cut short"
+        } else if recent(self.last_full_read) {
+            "reads fill the buffer"
+        } else if self.summary.uart_utilization > BUSY_UTILIZATION {
+            "the link is saturated"
+        } else {
+            return None;
+        };
+        self.summary.likely_overruns += 1;
+        self.summary.overrun_lost_frames += lost_frames;
+        Some(reason)
+    }
+
+    pub fn summary(&self) -> LinkSummary {
+        self.summary.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_link_stats() {
+        let mut stats = LinkStats::new(2_000_000, 8192);
+        let start = Instant::now();
+        // 100 kB/s in 4 kB reads that mostly wait for data
+        for i in 1..=250u64 {
+            stats.on_read(4000, Duration::from_millis(30), 4000, start + Duration::from_millis(40 * i));
+        }
+        let summary = stats.summary();
+        assert_eq!(summary.bytes, 1_000_000);
+        assert_eq!(summary.mean_read_bytes, 4000.0);
+        assert_eq!(summary.full_reads, 0);
+        assert!((summary.bytes_per_second - 100_000.0).abs() < 1000.0, "{}", summary.bytes_per_second);
+        assert!((summary.blocked_ratio - 0.75).abs() < 0.01, "{}", summary.blocked_ratio);
+        assert!((summary.uart_utilization - 0.5).abs() < 0.01, "{}", summary.uart_utilization);
+        assert_eq!(stats.on_frame_gap(2, start + Duration::from_secs(10)), None, "The device skipped frames");
+        stats.on_timeout(Duration::from_millis(500), start + Duration::from_millis(10_500));
+        let summary = stats.summary();
+        assert_eq!((summary.reads, summary.bytes), (250, 1_000_000), "A timeout is not a read");
+        assert!((summary.blocked_seconds - 8.0).abs() < 1e-6, "{}", summary.blocked_seconds);
+        assert!((summary.blocked_ratio - 0.78).abs() < 0.01, "{}", summary.blocked_ratio);
+
+        let now = start + Duration::from_secs(11);
+        stats.on_read(8192, Duration::ZERO, 20_000, now);
+        assert_eq!(stats.on_frame_gap(3, now + Duration::from_millis(200)), Some("reads fill the buffer"));
+        stats.on_bad_frame(now + Duration::from_secs(5));
+        assert_eq!(stats.on_frame_gap(1, now + Duration::from_secs(5)), Some("a frame was cut short"));
+        let summary = stats.summary();
+        assert_eq!((summary.likely_overruns, summary.overrun_lost_frames), (2, 4));
+        assert_eq!((summary.full_reads, summary.max_read_bytes, summary.max_queue_bytes), (1, 8192, 20_000));
+    }
+}
@@ -0,0 +1,3 @@
+pub mod console;
+pub mod handler;
+pub mod link_stats;
@@ -0,0 +1,598 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use chrono::{DateTime, Local};
+use crate::audio::flac::FlacWriter;
+use crate::audio::wav::{BroadcastExtension, WavWriter};
+use crate::constants::common;
+use crate::dsp::chain::DspChain;
+use super::subtitles::SubtitleWriter;
+
+/// What is known about the session as a whole
+pub struct SessionInfo {
+    pub name: String,
+    pub port: String,
+    pub start: DateTime<Local>,
+}
+
+/// Which audio files are written for a boot and how they are split
+#[derive(Clone, Copy)]
+pub struct AudioFileOptions {
+    pub wav: bool,
+    pub flac: bool,
+    /// Let files grow past 4 GiB as RF64 instead of splitting them there
+    pub rf64: bool,
+    /// Start a new part after this many seconds of audio
+    pub split_seconds: Option<f64>,
+    /// Start a new part before the audio would exceed this many bytes
+    pub split_bytes: Option<u64>,
+    /// Whether each format gets the audio from the DSP chain instead of the raw audio
+    pub processed_wav: bool,
+    pub processed_flac: bool,
+    /// Peak level a processed wav file is scaled to when it is closed
+    pub normalize_dbfs: Option<f64>,
+    /// Sample rate of the received audio, and of the processed audio
+    pub sample_rate: u32,
+    pub output_sample_rate: u32,
+}
+
+impl Default for AudioFileOptions {
+    fn default() -> Self {
+        Self {
+            wav: true,
+            flac: false,
+            rf64: false,
+            split_seconds: None,
+            split_bytes: None,
+            processed_wav: true,
+            processed_flac: true,
+            normalize_dbfs: None,
+            sample_rate: common::SAMPLE_RATE,
+            output_sample_rate: common::SAMPLE_RATE,
+        }
+    }
+}
+
+/// Plain RIFF files are split before this size, leaving room for the header and markers
+const RIFF_AUDIO_LIMIT: u64 = u32::MAX as u64 - (16 << 20);
+
+fn seconds_to_samples(seconds: f64, sample_rate: u32) -> u64 {
+    (seconds * sample_rate as f64).round() as u64
+}
+
+impl AudioFileOptions {
+    fn wav_sample_rate(&self) -> u32 {
+        if self.processed_wav { self.output_sample_rate } else { self.sample_rate }
+    }
+
+    fn flac_sample_rate(&self) -> u32 {
+        if self.processed_flac { self.output_sample_rate } else { self.sample_rate }
+    }
+
+    /// Bytes of audio a frame turns into at `sample_rate`, rounded up
+    fn bytes_per_frame(&self, sample_rate: u32) -> u64 {
+        (common::AUDIO_PAYLOAD_LENGTH as u64 * sample_rate as u64).div_ceil(self.sample_rate as u64)
+    }
+
+    /// Number of whole frames that go into one audio file, if files are split
+    fn frames_per_part(&self) -> Option<u64> {
+        let largest_rate = [(self.wav, self.wav_sample_rate()), (self.flac, self.flac_sample_rate())].into_iter()
+            .filter_map(|(enabled, sample_rate)| enabled.then_some(sample_rate))
+            .max()
+            .unwrap_or(self.sample_rate);
+        let by_duration = self.split_seconds.map(|seconds| (seconds / common::frame_seconds(self.sample_rate)) as u64);
+        let by_size = self.split_bytes.map(|bytes| bytes / self.bytes_per_frame(largest_rate));
+        let by_format = (self.wav && !self.rf64).then(|| RIFF_AUDIO_LIMIT / self.bytes_per_frame(self.wav_sample_rate()));
+        [by_duration, by_size, by_format].into_iter().flatten().min().map(|frames| frames.max(1))
+    }
+}
+
+/// The audio files currently written for a boot
+struct AudioFiles {
+    wav: Option<WavWriter>,
+    flac: Option<FlacWriter>,
+}
+
+/// Files of one device boot
+struct Segment {
+    name: String,
+    audio: AudioFiles,
+    /// Number of the audio file within the boot, starting at 1
+    part_number: u32,
+    part_frames: u64,
+    /// Start of the current audio file, in seconds since midnight
+    time_reference: Option<f64>,
+    log: BufWriter<File>,
+    json_log: BufWriter<File>,
+    subtitles: SubtitleWriter,
+    /// Frames written to the audio files of the boot
+    frames: u64,
+    /// Firmware banner values such as REV_INFO, in the order they were seen
+    firmware_info: Vec<(String, String)>,
+}
+
+/// Writes the audio and log of a session into files, one set of files per device boot
+pub struct Recorder {
+    output_dir: PathBuf,
+    session: SessionInfo,
+    audio_options: AudioFileOptions,
+    segment_number: u32,
+    segment: Segment,
+    dsp: Option<DspChain>,
+}
+
+impl Recorder {
+    /// Create the output directory and open the first segment
+    pub fn new<P: AsRef<Path>>(output_dir: P, session: SessionInfo, audio_options: AudioFileOptions) -> io::Result<Self> {
+        let output_dir = output_dir.as_ref().to_path_buf();
+        fs::create_dir_all(&output_dir)?;
+        let segment = Self::open_segment(&output_dir, &session, audio_options, 1)?;
+        Ok(Self {
+            output_dir,
+            session,
+            audio_options,
+            segment_number: 1,
+            segment,
+            dsp: None,
+        })
+    }
+
+    /// Process the audio before it is written, for the formats that take processed audio
+    pub fn set_dsp(&mut self, dsp: Option<DspChain>) {
+        self.dsp = dsp;
+    }
+
+    fn segment_name(session: &SessionInfo, segment: u32) -> String {
+        format!("{}_boot{:02}", session.name, segment)
+    }
+
+    /// Name of an audio file of the segment; parts after the first get a `_partNNN` suffix
+    fn part_name(segment_name: &str, part: u32) -> String {
+        match part {
+            1 => segment_name.to_string(),
+            _ => format!("{}_part{:03}", segment_name, part),
+        }
+    }
+
+    fn open_audio(output_dir: &Path, session: &SessionInfo, audio_options: AudioFileOptions, name: &str,
+            firmware_info: &[(String, String)], time_reference: f64) -> io::Result<AudioFiles> {
+        let description = Self::description(session, firmware_info);
+        let date = session.start.format("%Y-%m-%d").to_string();
+        let mut audio = AudioFiles { wav: None, flac: None };
+
+        if audio_options.wav {
+            let bext = BroadcastExtension {
+                description: description.clone(),
+                originator: "Serial2Wave".to_string(),
+                origination: session.start,
+                time_reference: seconds_to_samples(time_reference, audio_options.wav_sample_rate()),
+            };
+            let path = output_dir.join(format!("{}.wav", name));
+            let mut wav = WavWriter::create_broadcast(path, audio_options.wav_sample_rate(), common::CHANNELS, common::BITS_PER_SAMPLE,
+                &bext, audio_options.rf64)?;
+            wav.set_info(b"INAM", name)?;
+            wav.set_info(b"ISFT", concat!("Serial2Wave ", env!("CARGO_PKG_VERSION")))?;
+            wav.set_info(b"ICRD", &date)?;
+            wav.set_info(b"ICMT", &description)?;
+            audio.wav = Some(wav);
+        }
+
+        if audio_options.flac {
+            let path = output_dir.join(format!("{}.flac", name));
+            let sample_rate = audio_options.flac_sample_rate();
+            let mut flac = FlacWriter::create(path, sample_rate, common::CHANNELS, common::BITS_PER_SAMPLE)?;
+            flac.set_comment("TITLE", name)?;
+            flac.set_comment("DATE", &date)?;
+            flac.set_comment("DESCRIPTION", &description)?;
+            flac.set_comment("TIME_REFERENCE", &seconds_to_samples(time_reference, sample_rate).to_string())?;
+            for (key, value) in firmware_info {
+                flac.set_comment(key, value)?;
+            }
+            audio.flac = Some(flac);
+        }
+        Ok(audio)
+    }
+
+    /// Label track and subtitles of an audio file, named like it
+    fn open_subtitles(output_dir: &Path, name: &str) -> io::Result<SubtitleWriter> {
+        let path = |extension| output_dir.join(format!("{}.{}", name, extension));
+        SubtitleWriter::create(&path("labels.txt"), &path("srt"), &path("vtt"))
+    }
+
+    fn open_segment(output_dir: &Path, session: &SessionInfo, audio_options: AudioFileOptions, segment: u32) -> io::Result<Segment> {
+        let name = Self::segment_name(session, segment);
+        let path = |extension| output_dir.join(format!("{}.{}", name, extension));
+
+        Ok(Segment {
+            audio: Self::open_audio(output_dir, session, audio_options, &name, &[], 0.0)?,
+            part_number: 1,
+            part_frames: 0,
+            time_reference: None,
+            log: BufWriter::new(File::create(path("log"))?),
+            json_log: BufWriter::new(File::create(path("jsonl"))?),
+            subtitles: Self::open_subtitles(output_dir, &name)?,
+            frames: 0,
+            firmware_info: Vec::new(),
+            name,
+        })
+    }
+
+    fn description(session: &SessionInfo, firmware_info: &[(String, String)]) -> String {
+        let mut description = format!("port={}", session.port);
+        for (key, value) in firmware_info {
+            description.push_str(&format!("; {}={}", key, value));
+        }
+        description
+    }
+
+    /// Close the current audio files and normalize the processed wav file, as no more audio goes into them.
+    /// The files are finished here rather than on drop, as the receiver exits without dropping the recorder.
+    fn finish_audio(&mut self) -> io::Result<()> {
+        if let Some(wav) = self.segment.audio.wav.take() {
+            let audio = wav.close()?;
+            if let (Some(peak_dbfs), true) = (self.audio_options.normalize_dbfs, self.audio_options.processed_wav) {
+                audio.normalize(peak_dbfs)?;
+            }
+        }
+        if let Some(mut flac) = self.segment.audio.flac.take() {
+            flac.finish()?;
+        }
+        Ok(())
+    }
+
+    fn finish_segment(&mut self) -> io::Result<()> {
+        self.finish_audio()?;
+        self.segment.log.flush()?;
+        self.segment.json_log.flush()?;
+        self.segment.subtitles.finish()
+    }
+
+    /// Finish the files of the current segment at the end of the session, after which no audio is written
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.finish_segment()
+    }
+
+    /// Close the current files and continue in a new segment after a device reset
+    pub fn start_new_segment(&mut self) -> io::Result<()> {
+        self.finish_segment()?;
+        self.segment = Self::open_segment(&self.output_dir, &self.session, self.audio_options, self.segment_number + 1)?;
+        self.segment_number += 1;
+        Ok(())
+    }
+
+    /// Add a firmware banner value such as REV_INFO or BUILD_DATE to the audio metadata
+    pub fn set_firmware_info(&mut self, key: &str, value: &str) -> io::Result<()> {
+        let firmware_info = &mut self.segment.firmware_info;
+        match firmware_info.iter_mut().find(|(existing, _)| existing == key) {
+            Some((_, existing)) => *existing = value.to_string(),
+            None => firmware_info.push((key.to_string(), value.to_string())),
+        }
+        let description = Self::description(&self.session, &self.segment.firmware_info);
+        if let Some(wav) = &mut self.segment.audio.wav {
+            wav.set_description(&description)?;
+            wav.set_info(b"ICMT", &description)?;
+        }
+        if let Some(flac) = &mut self.segment.audio.flac {
+            flac.set_comment("DESCRIPTION", &description)?;
+            flac.set_comment(key, value)?;
+        }
+        Ok(())
+    }
+
+    /// Whether audio was written to the current segment yet
+    pub fn has_audio(&self) -> bool {
+        self.segment.frames > 0
+    }
+
+    /// Set the time of the first sample in the audio files, as samples since midnight of the session start
+    pub fn set_time_reference(&mut self, first_sample: DateTime<Local>) -> io::Result<()> {
+        let midnight = self.session.start.date_naive().and_hms_opt(0, 0, 0).expect("Midnight is a valid time");
+        let seconds = (first_sample.naive_local() - midnight).to_std().unwrap_or_default().as_secs_f64();
+        self.segment.time_reference = Some(seconds);
+        if let Some(wav) = &mut self.segment.audio.wav {
+            wav.set_time_reference(seconds_to_samples(seconds, self.audio_options.wav_sample_rate()))?;
+        }
+        if let Some(flac) = &mut self.segment.audio.flac {
+            flac.set_comment("TIME_REFERENCE", &seconds_to_samples(seconds, self.audio_options.flac_sample_rate()).to_string())?;
+        }
+        Ok(())
+    }
+
+    /// Take the received audio as `sample_rate` from now on, e.g. once it is measured.
+    /// Corrects the header of the current wav file unless its audio is resampled; FLAC files get it from the next file on.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) -> io::Result<()> {
+        let options = &mut self.audio_options;
+        if options.output_sample_rate == options.sample_rate {
+            options.output_sample_rate = sample_rate;
+        }
+        options.sample_rate = sample_rate;
+        let wav_sample_rate = options.wav_sample_rate();
+        if let Some(wav) = &mut self.segment.audio.wav {
+            wav.set_sample_rate(wav_sample_rate)?;
+            if let Some(seconds) = self.segment.time_reference {
+                wav.set_time_reference(seconds_to_samples(seconds, wav_sample_rate))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of the segment currently being written, starting at 1
+    pub fn segment(&self) -> u32 {
+        self.segment_number
+    }
+
+    /// Append the payload of an audio frame, without the frame number and sync bytes.
+    /// Starts the next audio file first when the current one is full, so frames are never split.
+    pub fn write_audio(&mut self, frame: &[u8]) -> io::Result<()> {
+        if let Some(frames_per_part) = self.audio_options.frames_per_part() {
+            if self.segment.part_frames >= frames_per_part {
+                self.start_new_part()?;
+            }
+        }
+        let raw = &frame[..common::AUDIO_PAYLOAD_LENGTH];
+        let processed = self.dsp.as_mut().map(|dsp| dsp.process(raw));
+        let payload = |use_processed: bool| match &processed {
+            Some(processed) if use_processed => processed.as_slice(),
+            _ => raw,
+        };
+        if let Some(wav) = &mut self.segment.audio.wav {
+            wav.write_pcm(payload(self.audio_options.processed_wav))?;
+        }
+        if let Some(flac) = &mut self.segment.audio.flac {
+            flac.write_pcm(payload(self.audio_options.processed_flac))?;
+        }
+        self.segment.part_frames += 1;
+        self.segment.frames += 1;
+        Ok(())
+    }
+
+    /// Continue the audio of the current boot in new audio files
+    fn start_new_part(&mut self) -> io::Result<()> {
+        let frame_seconds = common::frame_seconds(self.audio_options.sample_rate);
+        let time_reference = self.segment.time_reference
+            .map(|first| first + self.segment.part_frames as f64 * frame_seconds);
+        self.finish_audio()?;
+        self.segment.subtitles.finish()?;
+        let part_number = self.segment.part_number + 1;
+        let name = Self::part_name(&self.segment.name, part_number);
+        self.segment.audio = Self::open_audio(&self.output_dir, &self.session, self.audio_options, &name,
+            &self.segment.firmware_info, time_reference.unwrap_or(0.0))?;
+        self.segment.subtitles = Self::open_subtitles(&self.output_dir, &name)?;
+        self.segment.part_number = part_number;
+        self.segment.part_frames = 0;
+        self.segment.time_reference = time_reference;
+        Ok(())
+    }
+
+    /// Position in the current audio file at the end of the audio written so far; lost frames
+    /// and frames the triggers leave out are not in the files, so they do not count
+    fn audio_seconds(&self) -> f64 {
+        self.segment.part_frames as f64 * common::frame_seconds(self.audio_options.sample_rate)
+    }
+
+    /// Add a log line to the label track and subtitles at the current audio position
+    pub fn write_log_line_label(&mut self, line: &str) -> io::Result<()> {
+        let seconds = self.audio_seconds();
+        self.segment.subtitles.add_line(seconds, line)
+    }
+
+    /// Append a line to the log file
+    pub fn write_log(&mut self, line: &str) -> io::Result<()> {
+        writeln!(self.segment.log, "{}", line)?;
+        self.segment.log.flush()
+    }
+
+    /// Add a marker at the current audio position, as a cue in the wav and on the label track,
+    /// which FLAC files can be opened with, and note it in both log files
+    pub fn write_marker(&mut self, timestamp: &str, label: &str) -> io::Result<()> {
+        if let Some(wav) = &mut self.segment.audio.wav {
+            wav.add_cue(label)?;
+        }
+        let seconds = self.audio_seconds();
+        self.segment.subtitles.add_label(seconds, &format!("MARK: {}", label))?;
+        self.write_log(&format!("{} - MARK: {}", timestamp, label))?;
+        self.write_log_record(&serde_json::json!({ "timestamp": timestamp, "marker": label }))
+    }
+
+    /// Path of a file about the whole session, `<session>_<suffix>`
+    pub fn session_file(&self, suffix: &str) -> PathBuf {
+        self.output_dir.join(format!("{}_{}", self.session.name, suffix))
+    }
+
+    /// Write a summary of the whole session as `<session>_<name>.json`, replacing the previous one
+    pub fn write_session_summary(&self, name: &str, summary: &serde_json::Value) -> io::Result<()> {
+        let json = serde_json::to_string_pretty(summary).map_err(io::Error::other)?;
+        fs::write(self.session_file(&format!("{}.json", name)), json)
+    }
+
+    /// Append a parsed log record to the JSON Lines file
+    pub fn write_log_record(&mut self, record: &serde_json::Value) -> io::Result<()> {
+        writeln!(self.segment.json_log, "{}", record)?;
+        self.segment.json_log.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Audio of the data chunk, found by walking the chunks behind the RIFF header
+    fn wav_data(wav: &[u8]) -> &[u8] {
+        let mut offset = 12;
+        loop {
+            let length = u32::from_le_bytes(wav[offset + 4..offset + 8].try_into().unwrap()) as usize;
+            if &wav[offset..offset + 4] == b"data" {
+                return &wav[offset + 8..offset + 8 + length];
+            }
+            offset += 8 + length + length % 2;
+        }
+    }
+
+    #[test]
+    fn test_segments() {
+        let output_dir = std::env::temp_dir().join("serial2wave_test_segments");
+        let _ = fs::remove_dir_all(&output_dir);
+
+        let session = SessionInfo {
+            name: "session".to_string(),
+            port: "/dev/ttyACM0".to_string(),
+            start: Local::now(),
+        };
+        let mut recorder = Recorder::new(&output_dir, session, AudioFileOptions::default()).expect("Failed to create recorder");
+        let mut frame = vec![0u8; common::PACKET_LENGTH];
+        recorder.write_log("first boot").unwrap();
+        recorder.write_log_line_label("first boot").unwrap();
+        recorder.set_firmware_info("REV_INFO", "03fa2ba-dirty:open_source").unwrap();
+        recorder.write_log_record(&serde_json::json!({ "message": "first boot" })).unwrap();
+        frame[common::AUDIO_PAYLOAD_LENGTH] = 5;
+        recorder.write_audio(&frame).unwrap();
+        frame[common::AUDIO_PAYLOAD_LENGTH] = 7;
+        recorder.write_audio(&frame).unwrap();
+        recorder.write_log_line_label("after frame 7").unwrap();
+        recorder.write_marker("t", "single_tap").unwrap();
+        recorder.start_new_segment().unwrap();
+        recorder.write_log("second boot").unwrap();
+        assert_eq!(recorder.segment(), 2);
+        drop(recorder);
+
+        let first_wav = fs::read(output_dir.join("session_boot01.wav")).unwrap();
+        let second_wav = fs::read(output_dir.join("session_boot02.wav")).unwrap();
+        assert_eq!(wav_data(&first_wav).len(), 2 * common::AUDIO_PAYLOAD_LENGTH, "Only the payload goes into the wav");
+        assert_eq!(wav_data(&second_wav).len(), 0, "Second boot has no audio yet");
+        let description = b"port=/dev/ttyACM0; REV_INFO=03fa2ba-dirty:open_source\0";
+        assert_eq!(&first_wav[44..44 + description.len()], description, "Firmware version goes into the bext description");
+        assert!(!second_wav.windows(8).any(|window| window == b"REV_INFO"), "Firmware info belongs to its boot");
+        assert_eq!(fs::read_to_string(output_dir.join("session_boot01.log")).unwrap(), "first boot\nt - MARK: single_tap\n");
+        assert_eq!(fs::read_to_string(output_dir.join("session_boot02.log")).unwrap(), "second boot\n");
+        assert_eq!(fs::read_to_string(output_dir.join("session_boot01.jsonl")).unwrap(),
+            "{\"message\":\"first boot\"}\n{\"marker\":\"single_tap\",\"timestamp\":\"t\"}\n");
+        assert_eq!(fs::read_to_string(output_dir.join("session_boot02.jsonl")).unwrap(), "");
+        assert_eq!(fs::read_to_string(output_dir.join("session_boot01.labels.txt")).unwrap(),
+            "0.000000\t0.000000\tfirst boot\n0.083333\t0.083333\tafter frame 7\n0.083333\t0.083333\tMARK: single_tap\n",
+            "Label positions follow the audio written, without the lost frame 6");
+
+        fs::remove_dir_all(&output_dir).unwrap();
+    }
+
+    #[test]
+    fn test_split_parts() {
+        let output_dir = std::env::temp_dir().join("serial2wave_test_split_parts");
+        let _ = fs::remove_dir_all(&output_dir);
+
+        let session = SessionInfo {
+            name: "session".to_string(),
+            port: "/dev/ttyACM0".to_string(),
+            start: Local::now(),
+        };
+        let audio_options = AudioFileOptions {
+            wav: true,
+            flac: true,
+            rf64: false,
+            split_seconds: Some(0.1),
+            split_bytes: Some(3 * common::AUDIO_PAYLOAD_LENGTH as u64 + 100),
+            ..Default::default()
+        };
+        assert_eq!(audio_options.frames_per_part(), Some(2), "The shorter limit wins, counted in whole frames");
+        let resampled = AudioFileOptions { sample_rate: 16_000, output_sample_rate: 48_000, split_seconds: Some(0.3), split_bytes: None, ..audio_options };
+        assert_eq!(resampled.frames_per_part(), Some(2), "Frames of 16 kHz audio last 0.125 s");
+        let resampled = AudioFileOptions { split_seconds: None, split_bytes: Some(25_000), ..resampled };
+        assert_eq!(resampled.frames_per_part(), Some(2), "A frame turns into 12000 bytes at 48 kHz");
+        let mut recorder = Recorder::new(&output_dir, session, audio_options).expect("Failed to create recorder");
+        let mut frame = vec![0u8; common::PACKET_LENGTH];
+        for frame_number in 0..5u8 {
+            frame[..common::AUDIO_PAYLOAD_LENGTH].fill(frame_number);
+            frame[common::AUDIO_PAYLOAD_LENGTH] = frame_number;
+            recorder.write_audio(&frame).unwrap();
+            if frame_number == 2 {
+                recorder.write_log_line_label("in part 2").unwrap();
+            }
+        }
+        drop(recorder);
+        assert_eq!(fs::read_to_string(output_dir.join("session_boot01_part002.labels.txt")).unwrap(),
+            "0.041667\t0.041667\tin part 2\n", "Labels are split with the audio and timed from the start of the part");
+        assert_eq!(fs::read_to_string(output_dir.join("session_boot01.labels.txt")).unwrap(), "");
+
+        let mut audio = Vec::new();
+        for name in ["session_boot01", "session_boot01_part002", "session_boot01_part003"] {
+            let wav = fs::read(output_dir.join(format!("{}.wav", name))).unwrap();
+            let data = wav_data(&wav);
+            assert_eq!(data.len() % common::AUDIO_PAYLOAD_LENGTH, 0, "Parts hold whole frames");
+            audio.extend(data.chunks(common::AUDIO_PAYLOAD_LENGTH).map(|payload| payload[0]));
+        }
+        assert_eq!(audio, [0, 1, 2, 3, 4], "No frame is lost or written twice across the split");
+        assert!(!output_dir.join("session_boot01_part004.wav").exists());
+        for name in ["session_boot01", "session_boot01_part002", "session_boot01_part003"] {
+            assert!(output_dir.join(format!("{}.flac", name)).exists(), "FLAC files are split along with the wav files");
+        }
+
+        fs::remove_dir_all(&output_dir).unwrap();
+    }
+
+    #[test]
+    fn test_finish() {
+        let output_dir = std::env::temp_dir().join("serial2wave_test_finish");
+        let _ = fs::remove_dir_all(&output_dir);
+
+        let session = SessionInfo {
+            name: "session".to_string(),
+            port: "/dev/ttyACM0".to_string(),
+            start: Local::now(),
+        };
+        let audio_options = AudioFileOptions { wav: false, flac: true, ..Default::default() };
+        let mut recorder = Recorder::new(&output_dir, session, audio_options).expect("Failed to create recorder");
+        let frame = vec![0x11u8; common::PACKET_LENGTH];
+        for _ in 0..3 {
+            recorder.write_audio(&frame).unwrap();
+        }
+        recorder.finish().unwrap();
+
+        // Read before the recorder is dropped, as the receiver exits right after finishing
+        let mut reader = claxon::FlacReader::open(output_dir.join("session_boot01.flac")).expect("Failed to open flac");
+        let written = 3 * common::AUDIO_PAYLOAD_LENGTH as u64 / 2;
+        assert_eq!(reader.streaminfo().samples, Some(written), "The last, shorter block is encoded");
+        assert_eq!(reader.samples().count() as u64, written);
+        drop(recorder);
+
+        fs::remove_dir_all(&output_dir).unwrap();
+    }
+
+    #[test]
+    fn test_processed_and_raw_audio() {
+        use crate::dsp::chain::ProcessorSettings;
+
+        let output_dir = std::env::temp_dir().join("serial2wave_test_processed_audio");
+        let _ = fs::remove_dir_all(&output_dir);
+
+        let session = SessionInfo {
+            name: "session".to_string(),
+            port: "/dev/ttyACM0".to_string(),
+            start: Local::now(),
+        };
+        let audio_options = AudioFileOptions { flac: true, processed_flac: false, normalize_dbfs: Some(-6.0), ..Default::default() };
+        let mut recorder = Recorder::new(&output_dir, session, audio_options).expect("Failed to create recorder");
+        let dsp = DspChain::new(&[ProcessorSettings::Gain { db: -6.0 }], common::SAMPLE_RATE, common::SAMPLE_RATE, common::CHANNELS, false).unwrap();
+        recorder.set_dsp(dsp);
+        let mut frame = vec![0u8; common::PACKET_LENGTH];
+        for [first, second] in [[1000i16, -2000], [500, -1000]] {
+            for (i, sample) in frame[..common::AUDIO_PAYLOAD_LENGTH].chunks_exact_mut(2).enumerate() {
+                sample.copy_from_slice(&[first, second][i % 2].to_le_bytes());
+            }
+            recorder.write_audio(&frame).unwrap();
+            recorder.start_new_segment().unwrap();
+        }
+        let samples = |name: &str| {
+            let wav = fs::read(output_dir.join(name)).unwrap();
+            let data = wav_data(&wav);
+            [0, 1].map(|index| i16::from_le_bytes(data[index * 2..][..2].try_into().unwrap()))
+        };
+        assert_eq!(samples("session_boot01.wav"), [8211, -16423], "Processed, then normalized to a -6 dBFS peak when closed");
+        assert_eq!(samples("session_boot02.wav"), [8228, -16423], "Each file gets its own gain");
+        recorder.finish().unwrap();
+        drop(recorder);
+        let mut reader = claxon::FlacReader::open(output_dir.join("session_boot01.flac")).expect("Failed to open flac");
+        let flac: Vec<i32> = reader.samples().take(2).map(|sample| sample.unwrap()).collect();
+        assert_eq!(flac, [1000, -2000], "Raw formats stay bit-exact");
+
+        fs::remove_dir_all(&output_dir).unwrap();
+    }
+}
@@ -0,0 +1,129 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use crate::receiver::console;
+
+/// Number of log lines shown at once, so the log scrolls through the subtitles
+const VISIBLE_LINES: usize = 4;
+/// How long the last subtitle stays visible
+const LAST_SUBTITLE_SECONDS: f64 = 2.0;
+
+/// Writes log lines positioned on the audio timeline as an Audacity label track
+/// and as SRT and WebVTT subtitles
+pub struct SubtitleWriter {
+    labels: BufWriter<File>,
+    srt: BufWriter<File>,
+    vtt: BufWriter<File>,
+    /// Start of the subtitle waiting for the next one to know its end
+    pending_start: Option<f64>,
+    recent_lines: VecDeque<String>,
+    count: u32,
+}
+
+/// `HH:MM:SS` followed by the separator and milliseconds
+fn format_time(seconds: f64, separator: char) -> String {
+    let millis = (seconds * 1000.0).round() as u64;
+    format!("{:02}:{:02}:{:02}{}{:03}",
+        millis / 3_600_000, millis / 60_000 % 60, millis / 1000 % 60, separator, millis % 1000)
+}
+
+impl SubtitleWriter {
+    pub fn create(labels_path: &Path, srt_path: &Path, vtt_path: &Path) -> io::Result<Self> {
+        let labels = BufWriter::new(File::create(labels_path)?);
+        let srt = BufWriter::new(File::create(srt_path)?);
+        let mut vtt = BufWriter::new(File::create(vtt_path)?);
+        writeln!(vtt, "WEBVTT")?;
+        vtt.flush()?;
+        Ok(Self {
+            labels,
+            srt,
+            vtt,
+            pending_start: None,
+            recent_lines: VecDeque::new(),
+            count: 0,
+        })
+    }
+
+    /// Add a label to the label track only, e.g. a marker
+    pub fn add_label(&mut self, seconds: f64, text: &str) -> io::Result<()> {
+        writeln!(self.labels, "{:.6}\t{:.6}\t{}", seconds, seconds, text)?;
+        self.labels.flush()
+    }
+
+    /// Add a log line at a position on the audio timeline
+    pub fn add_line(&mut self, seconds: f64, line: &str) -> io::Result<()> {
+        self.add_label(seconds, line)?;
+
+        // Lines arriving at the same position share one subtitle
+        match self.pending_start {
+            Some(start) if seconds > start => {
+                self.write_subtitle(start, seconds)?;
+                self.pending_start = Some(seconds);
+            }
+            Some(_) => {}
+            None => self.pending_start = Some(seconds),
+        }
+        if self.recent_lines.len() == VISIBLE_LINES {
+            self.recent_lines.pop_front();
+        }
+        self.recent_lines.push_back(line.to_string());
+        Ok(())
+    }
+
+    /// Write the last subtitle
+    pub fn finish(&mut self) -> io::Result<()> {
+        if let Some(start) = self.pending_start.take() {
+            self.write_subtitle(start, start + LAST_SUBTITLE_SECONDS)?;
+        }
+        Ok(())
+    }
+
+    fn write_subtitle(&mut self, start: f64, end: f64) -> io::Result<()> {
+        self.count += 1;
+        let text: Vec<&str> = self.recent_lines.iter().map(String::as_str).collect();
+        let text = text.join("\n");
+        write!(self.srt, "{}\n{} --> {}\n{}\n\n", self.count, format_time(start, ','), format_time(end, ','), text)?;
+        write!(self.vtt, "\n{} --> {}\n{}\n", format_time(start, '.'), format_time(end, '.'), text)?;
+        self.srt.flush()?;
+        self.vtt.flush()
+    }
+}
+
+impl Drop for SubtitleWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            console::report_error(format_args!("Failed to write subtitles: {}", e));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_subtitles() {
+        let dir = std::env::temp_dir().join("serial2wave_test_subtitles");
+        fs::create_dir_all(&dir).unwrap();
+        let (labels, srt, vtt) = (dir.join("labels.txt"), dir.join("log.srt"), dir.join("log.vtt"));
+
+        let mut writer = SubtitleWriter::create(&labels, &srt, &vtt).unwrap();
+        writer.add_line(0.0, "app_init").unwrap();
+        writer.add_line(0.0, "Set TWS side to 2").unwrap();
+        writer.add_line(3661.5, "app_key_single_tap event 8").unwrap();
+        drop(writer);
+
+        assert_eq!(fs::read_to_string(&labels).unwrap(),
+            "0.000000\t0.000000\tapp_init\n0.000000\t0.000000\tSet TWS side to 2\n3661.500000\t3661.500000\tapp_key_single_tap event 8\n");
+        assert_eq!(fs::read_to_string(&srt).unwrap(),
+            "1\n00:00:00,000 --> 01:01:01,500\napp_init\nSet TWS side to 2\n\n\
+             2\n01:01:01,500 --> 01:01:03,500\napp_init\nSet TWS side to 2\napp_key_single_tap event 8\n\n");
+        assert_eq!(fs::read_to_string(&vtt).unwrap(),
+            "WEBVTT\n\n00:00:00.000 --> 01:01:01.500\napp_init\nSet TWS side to 2\n\
+             \n01:01:01.500 --> 01:01:03.500\napp_init\nSet TWS side to 2\napp_key_single_tap event 8\n");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,203 @@
+use std::collections::VecDeque;
+use serde::Deserialize;
+use crate::constants::common;
+use crate::logs::pattern::Pattern;
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum TriggerAction {
+    /// Start writing audio, including the pre-roll
+    Start,
+    /// Stop writing audio after the post-roll
+    Stop,
+    /// Drop a marker at the current position
+    Mark,
+}
+
+/// A trigger as written in the config file or on the command line
+#[derive(Deserialize, Clone, Debug)]
+pub struct TriggerSettings {
+    pub action: TriggerAction,
+    pub pattern: String,
+}
+
+pub enum TriggerEvent {
+    /// Recording started; the pre-roll frames have to be written first
+    Started { pre_roll: Vec<Vec<u8>> },
+    /// Recording stops once the post-roll is written
+    Stopping,
+    Marked { label: String },
+}
+
+#[derive(PartialEq, Debug)]
+enum GateState {
+    /// Waiting for a start trigger, keeping the last frames as pre-roll
+    Armed,
+    Recording,
+    /// Stop triggered, this many frames are still written
+    PostRoll(usize),
+}
+
+struct Trigger {
+    action: TriggerAction,
+    pattern: Pattern,
+}
+
+/// Decides which audio frames get recorded, based on log lines matching triggers
+pub struct RecordingGate {
+    triggers: Vec<Trigger>,
+    state: GateState,
+    pre_roll: VecDeque<Vec<u8>>,
+    pre_roll_frames: usize,
+    post_roll_frames: usize,
+}
+
+/// Number of audio frames covering the given duration at `sample_rate`
+pub fn seconds_to_frames(seconds: f64, sample_rate: u32) -> usize {
+    (seconds / common::frame_seconds(sample_rate)).ceil() as usize
+}
+
+impl RecordingGate {
+    /// Without start triggers the gate records from the beginning
+    pub fn new(settings: &[TriggerSettings], pre_roll_frames: usize, post_roll_frames: usize) -> Result<Self, String> {
+        let mut triggers = Vec::new();
+        for trigger in settings {
+            triggers.push(Trigger {
+                action: trigger.action,
+                pattern: Pattern::parse(&trigger.pattern)?,
+            });
+        }
+        let state = if triggers.iter().any(|trigger| trigger.action == TriggerAction::Start) {
+            GateState::Armed
+        } else {
+            GateState::Recording
+        };
+        Ok(Self {
+            triggers,
+            state,
+            pre_roll: VecDeque::new(),
+            pre_roll_frames,
+            post_roll_frames,
+        })
+    }
+
+    /// Change the pre-roll and post-roll lengths, e.g. when frames turn out to last longer or shorter
+    pub fn set_roll_frames(&mut self, pre_roll_frames: usize, post_roll_frames: usize) {
+        self.pre_roll_frames = pre_roll_frames;
+        self.post_roll_frames = post_roll_frames;
+        while self.pre_roll.len() > pre_roll_frames {
+            self.pre_roll.pop_front();
+        }
+    }
+
+    /// Check a log line against the triggers
+    pub fn on_log_line(&mut self, line: &str) -> Vec<TriggerEvent> {
+        let actions: Vec<TriggerAction> = self.triggers.iter()
+            .filter(|trigger| trigger.pattern.is_match(line))
+            .map(|trigger| trigger.action)
+            .collect();
+
+        actions.into_iter().filter_map(|action| self.trigger(action, line)).collect()
+    }
+
+    /// Apply a trigger action, from a log line or a key press; marks are labeled `label`
+    pub fn trigger(&mut self, action: TriggerAction, label: &str) -> Option<TriggerEvent> {
+        match (action, &self.state) {
+            (TriggerAction::Start, GateState::Armed) => {
+                self.state = GateState::Recording;
+                Some(TriggerEvent::Started { pre_roll: self.pre_roll.drain(..).collect() })
+            }
+            (TriggerAction::Start, GateState::PostRoll(_)) => {
+                self.state = GateState::Recording;
+                None
+            }
+            (TriggerAction::Stop, GateState::Recording) => {
+                self.state = GateState::PostRoll(self.post_roll_frames);
+                Some(TriggerEvent::Stopping)
+            }
+            (TriggerAction::Mark, _) => Some(TriggerEvent::Marked { label: label.to_string() }),
+            _ => None,
+        }
+    }
+
+    /// Whether audio frames are being written, including the post-roll
+    pub fn is_recording(&self) -> bool {
+        self.state != GateState::Armed
+    }
+
+    /// Return whether an audio frame has to be written; frames that are not
+    /// written are kept as pre-roll for the next start trigger
+    pub fn on_audio_frame(&mut self, frame: &[u8]) -> bool {
+        match self.state {
+            GateState::Armed => {
+                if self.pre_roll_frames > 0 {
+                    if self.pre_roll.len() == self.pre_roll_frames {
+                        self.pre_roll.pop_front();
+                    }
+                    self.pre_roll.push_back(frame.to_vec());
+                }
+                false
+            }
+            GateState::Recording => true,
+            GateState::PostRoll(0) => {
+                self.state = GateState::Armed;
+                self.on_audio_frame(frame)
+            }
+            GateState::PostRoll(remaining) => {
+                self.state = GateState::PostRoll(remaining - 1);
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trigger(action: TriggerAction, pattern: &str) -> TriggerSettings {
+        TriggerSettings { action, pattern: pattern.to_string() }
+    }
+
+    #[test]
+    fn test_recording_gate() {
+        assert_eq!(seconds_to_frames(1.0, 48_000), 24, "4000 byte frames of 48 kHz 16 bit mono");
+        assert_eq!(seconds_to_frames(1.0, 16_000), 8);
+
+        let mut gate = RecordingGate::new(&[
+            trigger(TriggerAction::Start, "app_init"),
+            trigger(TriggerAction::Stop, "/ANC mode \\d/"),
+            trigger(TriggerAction::Mark, "single_tap"),
+        ], 2, 1).unwrap();
+
+        // Armed: frames only go into the pre-roll
+        for frame_number in 0..5u8 {
+            assert!(!gate.on_audio_frame(&[frame_number]));
+        }
+        assert!(gate.on_log_line("nv_record_extension_init,done.").is_empty());
+
+        match gate.on_log_line("app_init").as_slice() {
+            [TriggerEvent::Started { pre_roll }] => assert_eq!(pre_roll, &vec![vec![3], vec![4]], "Last 2 frames are the pre-roll"),
+            _ => panic!("Expected a start event"),
+        }
+        assert!(gate.on_audio_frame(&[5]));
+
+        match gate.on_log_line("app_key_single_tap event 8").as_slice() {
+            [TriggerEvent::Marked { label }] => assert_eq!(label, "app_key_single_tap event 8"),
+            _ => panic!("Expected a mark event"),
+        }
+
+        assert!(matches!(gate.on_log_line("ANC mode 2").as_slice(), [TriggerEvent::Stopping]));
+        assert!(gate.on_audio_frame(&[6]), "Post-roll frame is still written");
+        assert!(!gate.on_audio_frame(&[7]));
+        assert!(gate.on_log_line("ANC mode 3").is_empty(), "Stop while armed does nothing");
+
+        match gate.on_log_line("app_init").as_slice() {
+            [TriggerEvent::Started { pre_roll }] => assert_eq!(pre_roll, &vec![vec![7]]),
+            _ => panic!("Expected a start event"),
+        }
+
+        let mut gate = RecordingGate::new(&[trigger(TriggerAction::Mark, "x")], 2, 1).unwrap();
+        assert!(gate.on_audio_frame(&[0]), "Without start triggers everything is recorded");
+    }
+}
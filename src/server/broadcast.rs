@@ -0,0 +1,30 @@
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+
+/// Hands every value to all current subscribers. A subscriber that falls
+/// `capacity` values behind or goes away is dropped, so a slow client never blocks the receiver.
+pub struct Broadcast<T: Clone> {
+    subscribers: Mutex<Vec<SyncSender<T>>>,
+    capacity: usize,
+}
+
+impl<T: Clone> Broadcast<T> {
+    pub fn new(capacity: usize) -> Self {
+        Self { subscribers: Mutex::new(Vec::new()), capacity }
+    }
+
+    pub fn subscribe(&self) -> Receiver<T> {
+        let (sender, receiver) = mpsc::sync_channel(self.capacity);
+        self.subscribers.lock().expect("Failed to lock subscribers mutex").push(sender);
+        receiver
+    }
+
+    pub fn send(&self, value: T) {
+        self.subscribers.lock().expect("Failed to lock subscribers mutex")
+            .retain(|subscriber| subscriber.try_send(value.clone()).is_ok());
+    }
+
+    pub fn subscriber_count(&self) -> usize {
+        self.subscribers.lock().expect("Failed to lock subscribers mutex").len()
+    }
+}
@@ -0,0 +1,92 @@
+use std::io;
+use tiny_http::{Header, Request, Response};
+use tungstenite::handshake::derive_accept_key;
+use tungstenite::protocol::Role;
+use tungstenite::{Message, WebSocket};
+use crate::analysis::levels;
+use super::feed::LiveFeed;
+
+/// The bundled web UI, served as `/`
+pub const PAGE: &str = include_str!("dashboard.html");
+/// Waveform columns per audio frame, each the minimum and maximum of its samples
+const WAVEFORM_COLUMNS: usize = 100;
+
+/// Dashboard message with the waveform outline and levels of an audio frame
+pub fn audio_message(frame_number: u64, payload: &[u8]) -> String {
+    let samples: Vec<i16> = levels::samples(payload).collect();
+    let column_length = samples.len().div_ceil(WAVEFORM_COLUMNS).max(1);
+    let (min, max): (Vec<i16>, Vec<i16>) = samples.chunks(column_length)
+        .map(|column| (*column.iter().min().unwrap_or(&0), *column.iter().max().unwrap_or(&0)))
+        .unzip();
+    // The waveform is drawn from all channels together
+    let level = levels::channel_levels(payload, 1)[0];
+    serde_json::json!({
+        "type": "audio",
+        "frame": frame_number,
+        "min": min,
+        "max": max,
+        "peak_dbfs": level.peak_dbfs(),
+        "rms_dbfs": level.rms_dbfs(),
+    }).to_string()
+}
+
+/// Dashboard message with a log line
+pub fn log_message(line: &str) -> String {
+    serde_json::json!({ "type": "log", "line": line }).to_string()
+}
+
+/// Accept the WebSocket handshake of `/ws` and push the dashboard messages until the browser goes away
+pub fn serve_websocket(request: Request, feed: &LiveFeed) -> io::Result<()> {
+    let key = request.headers().iter()
+        .find(|header| header.field.equiv("Sec-WebSocket-Key"))
+        .map(|header| header.value.as_str().to_string());
+    let Some(key) = key else {
+        return request.respond(Response::from_string("Expected a WebSocket request").with_status_code(400));
+    };
+    let accept = Header::from_bytes(&b"Sec-WebSocket-Accept"[..], derive_accept_key(key.as_bytes()).as_bytes())
+        .expect("Accept key is valid ASCII");
+    let messages = feed.dashboard.subscribe();
+    let stream = request.upgrade("websocket", Response::empty(101).with_header(accept));
+    let mut socket = WebSocket::from_raw_socket(stream, Role::Server, None);
+    for message in messages {
+        socket.send(Message::text(message.as_ref())).map_err(io::Error::other)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use crate::server::http::HttpServer;
+
+    #[test]
+    fn test_dashboard() {
+        let payload: Vec<u8> = (0..2000).flat_map(|i| (if i == 10 { i16::MIN } else { 1000 }).to_le_bytes()).collect();
+        let message: serde_json::Value = serde_json::from_str(&audio_message(7, &payload)).unwrap();
+        assert_eq!(message["frame"], 7);
+        assert_eq!(message["min"].as_array().unwrap().len(), WAVEFORM_COLUMNS);
+        assert_eq!(message["min"][0], i16::MIN);
+        assert_eq!(message["max"][0], 1000);
+        assert_eq!(message["peak_dbfs"], 0.0, "Full scale peak");
+
+        let feed = Arc::new(LiveFeed::new("session", "/dev/ttyACM0", 48_000));
+        let address = HttpServer::start("127.0.0.1:0", Arc::clone(&feed)).expect("Failed to start server");
+        let (mut socket, _) = tungstenite::connect(format!("ws://{}/ws", address)).expect("WebSocket handshake failed");
+        let start = Instant::now();
+        while feed.status().clients < 1 {
+            assert!(start.elapsed() < Duration::from_secs(5), "Dashboard did not connect");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        feed.on_log_line("2024-12-21 12:24:34.830 - app_init");
+        feed.on_audio_frame(3, 4012, &payload, Instant::now());
+
+        let log: serde_json::Value = serde_json::from_str(socket.read().unwrap().to_text().unwrap()).unwrap();
+        assert_eq!(log["type"], "log");
+        assert_eq!(log["line"], "2024-12-21 12:24:34.830 - app_init");
+        let audio: serde_json::Value = serde_json::from_str(socket.read().unwrap().to_text().unwrap()).unwrap();
+        assert_eq!(audio["type"], "audio");
+        assert_eq!(audio["frame"], 3);
+    }
+}
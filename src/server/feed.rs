@@ -0,0 +1,233 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use serde::Serialize;
+use crate::analysis::analyzer::FrameAnalysis;
+use crate::analysis::spectrum::ToneAnalysis;
+use crate::receiver::link_stats::LinkSummary;
+use crate::timing::rate_estimator::RateEstimate;
+use super::broadcast::Broadcast;
+use super::dashboard;
+
+/// Audio frames kept for a slow client before it is dropped, about 2.7 s
+const AUDIO_BACKLOG: usize = 64;
+const LOG_BACKLOG: usize = 1024;
+const DASHBOARD_BACKLOG: usize = 1024;
+/// Window of the throughput figures
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(5);
+
+/// Counters of the receiver, served as `/status`
+#[derive(Serialize, Default, Clone)]
+pub struct ReceiverStatus {
+    pub session: String,
+    pub port: String,
+    pub uptime_seconds: f64,
+    pub segment: u32,
+    pub device_resets: u32,
+    /// Times the serial port was opened again after an error
+    pub reconnects: u64,
+    pub audio_frames: u64,
+    pub last_frame_number: Option<u64>,
+    /// Jumps in the frame counter, and the frames lost in them
+    pub frame_gaps: u64,
+    pub lost_frames: u64,
+    /// Audio frames cut short on the serial line
+    pub bad_frames: u64,
+    pub log_lines: u64,
+    pub bytes_received: u64,
+    pub bytes_per_second: f64,
+    /// Levels of the last audio frame
+    pub audio_levels: Option<FrameAnalysis>,
+    /// Strongest tone of the last spectrum
+    pub tone: Option<ToneAnalysis>,
+    pub audio_frames_per_second: f64,
+    /// Sample rate the frames arrive at
+    pub measured_sample_rate: Option<RateEstimate>,
+    /// Reads, queue and overruns of the serial link
+    pub link: Option<LinkSummary>,
+    /// Connected `/audio.wav`, `/log` and dashboard clients
+    pub clients: usize,
+}
+
+/// Running counters behind `ReceiverStatus`
+struct StatusCounters {
+    status: ReceiverStatus,
+    start: Instant,
+    /// Arrival time, size and whether it was an audio frame, within the throughput window
+    recent: VecDeque<(Instant, usize, bool)>,
+}
+
+impl StatusCounters {
+    fn record(&mut self, now: Instant, bytes: usize, audio: bool) {
+        self.status.bytes_received += bytes as u64;
+        self.recent.push_back((now, bytes, audio));
+        while self.recent.front().is_some_and(|(time, _, _)| now.duration_since(*time) > THROUGHPUT_WINDOW) {
+            self.recent.pop_front();
+        }
+    }
+
+    fn snapshot(&self, now: Instant) -> ReceiverStatus {
+        let mut status = self.status.clone();
+        status.uptime_seconds = now.duration_since(self.start).as_secs_f64();
+        let window = status.uptime_seconds.min(THROUGHPUT_WINDOW.as_secs_f64());
+        if window > 0.0 {
+            let recent = self.recent.iter().filter(|(time, _, _)| now.duration_since(*time) <= THROUGHPUT_WINDOW);
+            let (bytes, frames) = recent.fold((0, 0), |(bytes, frames), (_, size, audio)| (bytes + size, frames + *audio as usize));
+            status.bytes_per_second = bytes as f64 / window;
+            status.audio_frames_per_second = frames as f64 / window;
+        }
+        status
+    }
+}
+
+/// What the receiver publishes to the HTTP clients
+pub struct LiveFeed {
+    /// Payloads of the audio frames
+    pub audio: Broadcast<Arc<Vec<u8>>>,
+    /// Timestamped log lines without ANSI codes
+    pub log: Broadcast<Arc<str>>,
+    /// JSON messages for the dashboard WebSocket
+    pub dashboard: Broadcast<Arc<str>>,
+    /// Sample rate of the published audio, for the header of new audio clients
+    sample_rate: AtomicU32,
+    counters: Mutex<StatusCounters>,
+}
+
+impl LiveFeed {
+    pub fn new(session: &str, port: &str, sample_rate: u32) -> Self {
+        let status = ReceiverStatus { session: session.to_string(), port: port.to_string(), segment: 1, ..Default::default() };
+        Self {
+            audio: Broadcast::new(AUDIO_BACKLOG),
+            log: Broadcast::new(LOG_BACKLOG),
+            dashboard: Broadcast::new(DASHBOARD_BACKLOG),
+            sample_rate: AtomicU32::new(sample_rate),
+            counters: Mutex::new(StatusCounters { status, start: Instant::now(), recent: VecDeque::new() }),
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate.load(Ordering::Relaxed)
+    }
+
+    /// Announce the audio as `sample_rate` to clients that connect from now on
+    pub fn set_sample_rate(&self, sample_rate: u32) {
+        self.sample_rate.store(sample_rate, Ordering::Relaxed);
+    }
+
+    fn counters(&self) -> std::sync::MutexGuard<'_, StatusCounters> {
+        self.counters.lock().expect("Failed to lock status mutex")
+    }
+
+    /// Count a frame and publish its payload
+    pub fn on_audio_frame(&self, frame_number: u64, frame_length: usize, payload: &[u8], received: Instant) {
+        {
+            let mut counters = self.counters();
+            let status = &mut counters.status;
+            if let Some(last) = status.last_frame_number {
+                if frame_number > last + 1 {
+                    status.frame_gaps += 1;
+                    status.lost_frames += frame_number - last - 1;
+                }
+            }
+            status.last_frame_number = Some(frame_number);
+            status.audio_frames += 1;
+            counters.record(received, frame_length, true);
+        }
+        self.audio.send(Arc::new(payload.to_vec()));
+        if self.dashboard.subscriber_count() > 0 {
+            self.dashboard.send(Arc::from(dashboard::audio_message(frame_number, payload)));
+        }
+    }
+
+    pub fn on_frame_analysis(&self, analysis: &FrameAnalysis) {
+        self.counters().status.audio_levels = Some(analysis.clone());
+    }
+
+    pub fn on_tone(&self, tone: Option<ToneAnalysis>) {
+        self.counters().status.tone = tone;
+    }
+
+    pub fn on_rate_estimate(&self, estimate: RateEstimate) {
+        self.counters().status.measured_sample_rate = Some(estimate);
+    }
+
+    pub fn on_link_summary(&self, summary: LinkSummary) {
+        self.counters().status.link = Some(summary);
+    }
+
+    /// Count an audio frame that was cut short
+    pub fn on_bad_frame(&self, length: usize, received: Instant) {
+        let mut counters = self.counters();
+        counters.status.bad_frames += 1;
+        counters.record(received, length, false);
+    }
+
+    /// Count a chunk of log data
+    pub fn on_log_data(&self, length: usize, received: Instant) {
+        self.counters().record(received, length, false);
+    }
+
+    /// Publish a complete log line
+    pub fn on_log_line(&self, line: &str) {
+        self.counters().status.log_lines += 1;
+        self.log.send(Arc::from(line));
+        if self.dashboard.subscriber_count() > 0 {
+            self.dashboard.send(Arc::from(dashboard::log_message(line)));
+        }
+    }
+
+    pub fn on_device_reset(&self, segment: u32) {
+        let mut counters = self.counters();
+        counters.status.device_resets += 1;
+        counters.status.segment = segment;
+        // The frame counter starts over, which is not a gap
+        counters.status.last_frame_number = None;
+    }
+
+    pub fn on_reconnect(&self) {
+        self.counters().status.reconnects += 1;
+    }
+
+    pub fn status(&self) -> ReceiverStatus {
+        let mut status = self.counters().snapshot(Instant::now());
+        status.clients = self.audio.subscriber_count() + self.log.subscriber_count() + self.dashboard.subscriber_count();
+        status
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_live_feed() {
+        let feed = LiveFeed::new("session", "/dev/ttyACM0", 48_000);
+        let audio = feed.audio.subscribe();
+        let start = Instant::now();
+        feed.on_audio_frame(5, 4012, &[1, 0], start);
+        feed.on_audio_frame(6, 4012, &[2, 0], start);
+        feed.on_audio_frame(9, 4012, &[3, 0], start);
+        feed.on_log_data(100, start);
+        feed.on_bad_frame(2012, start);
+        feed.on_log_line("app_init");
+        feed.on_device_reset(2);
+        feed.on_audio_frame(0, 4012, &[4, 0], start);
+
+        let status = feed.status();
+        assert_eq!(status.audio_frames, 4);
+        assert_eq!(status.frame_gaps, 1);
+        assert_eq!(status.lost_frames, 2, "Frames 7 and 8");
+        assert_eq!(status.device_resets, 1);
+        assert_eq!(status.segment, 2);
+        assert_eq!(status.log_lines, 1);
+        assert_eq!(status.bad_frames, 1);
+        assert_eq!(status.bytes_received, 4 * 4012 + 100 + 2012);
+        assert!(status.bytes_per_second > 0.0);
+        assert_eq!(audio.try_iter().map(|payload| payload[0]).collect::<Vec<_>>(), [1, 2, 3, 4]);
+
+        drop(audio);
+        feed.on_audio_frame(1, 4012, &[5, 0], start);
+        assert_eq!(feed.status().clients, 0, "Gone clients are dropped");
+    }
+}
@@ -0,0 +1,165 @@
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread;
+use tiny_http::{Header, Method, Request, Response, Server};
+use crate::audio::wav;
+use crate::constants::common;
+use crate::receiver::console;
+use super::dashboard;
+use super::feed::LiveFeed;
+use super::metrics;
+
+/// Serves the live feed: the dashboard at `/` with its `/ws` WebSocket, `/audio.wav`, `/log`, `/status` and `/metrics`
+pub struct HttpServer;
+
+impl HttpServer {
+    /// Listen on `address`, e.g. `127.0.0.1:8080` or `0.0.0.0:8080` for the LAN, and return the bound address
+    pub fn start(address: &str, feed: Arc<LiveFeed>) -> io::Result<SocketAddr> {
+        let server = Server::http(address).map_err(io::Error::other)?;
+        let bound = server.server_addr().to_ip()
+            .ok_or_else(|| io::Error::other("HTTP server is not on an IP address"))?;
+        thread::spawn(move || {
+            for request in server.incoming_requests() {
+                let feed = Arc::clone(&feed);
+                // Streams last as long as the client listens, so every request gets its own thread
+                thread::spawn(move || {
+                    if let Err(e) = Self::respond(request, &feed) {
+                        if e.kind() != io::ErrorKind::BrokenPipe && e.kind() != io::ErrorKind::ConnectionReset {
+                            console::report_error(format_args!("HTTP client error: {}", e));
+                        }
+                    }
+                });
+            }
+        });
+        Ok(bound)
+    }
+
+    fn respond(request: Request, feed: &LiveFeed) -> io::Result<()> {
+        if *request.method() != Method::Get {
+            return request.respond(Response::empty(405));
+        }
+        match request.url() {
+            "/" => request.respond(Response::from_string(dashboard::PAGE)
+                .with_header(header("Content-Type", "text/html; charset=utf-8"))),
+            "/ws" => dashboard::serve_websocket(request, feed),
+            "/audio.wav" => Self::stream_audio(request, feed),
+            "/log" => Self::stream_log(request, feed),
+            "/status" => {
+                let body = serde_json::to_string_pretty(&feed.status()).map_err(io::Error::other)?;
+                request.respond(Response::from_string(body)
+                    .with_header(header("Content-Type", "application/json"))
+                    .with_header(header("Access-Control-Allow-Origin", "*")))
+            },
+            "/metrics" => request.respond(Response::from_string(metrics::render(&feed.status()))
+                .with_header(header("Content-Type", "text/plain; version=0.0.4; charset=utf-8"))),
+            _ => request.respond(Response::from_string("Not found").with_status_code(404)),
+        }
+    }
+
+    /// Start a chunked response; tiny_http would buffer the chunks, so streams are written directly
+    fn start_stream(request: Request, content_type: &str) -> io::Result<Box<dyn Write + Send>> {
+        let mut writer = request.into_writer();
+        write!(writer, "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nTransfer-Encoding: chunked\r\n\
+            Cache-Control: no-cache\r\nAccess-Control-Allow-Origin: *\r\nConnection: close\r\n\r\n", content_type)?;
+        writer.flush()?;
+        Ok(writer)
+    }
+
+    fn write_chunk(writer: &mut dyn Write, data: &[u8]) -> io::Result<()> {
+        write!(writer, "{:x}\r\n", data.len())?;
+        writer.write_all(data)?;
+        writer.write_all(b"\r\n")?;
+        writer.flush()
+    }
+
+    fn stream_audio(request: Request, feed: &LiveFeed) -> io::Result<()> {
+        let frames = feed.audio.subscribe();
+        let mut writer = Self::start_stream(request, "audio/wav")?;
+        Self::write_chunk(&mut writer, &wav::streaming_header(feed.sample_rate(), common::CHANNELS, common::BITS_PER_SAMPLE))?;
+        for payload in frames {
+            Self::write_chunk(&mut writer, &payload)?;
+        }
+        Ok(())
+    }
+
+    /// Log lines as server-sent events
+    fn stream_log(request: Request, feed: &LiveFeed) -> io::Result<()> {
+        let lines = feed.log.subscribe();
+        let mut writer = Self::start_stream(request, "text/event-stream")?;
+        for line in lines {
+            Self::write_chunk(&mut writer, format!("data: {}\n\n", line).as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).expect("Header is valid ASCII")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::TcpStream;
+    use std::time::{Duration, Instant};
+
+    fn get(address: SocketAddr, path: &str) -> BufReader<TcpStream> {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", path).unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        while line != "\r\n" {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+        }
+        reader
+    }
+
+    fn read_chunk(reader: &mut BufReader<TcpStream>) -> Vec<u8> {
+        let mut size = String::new();
+        reader.read_line(&mut size).unwrap();
+        let mut chunk = vec![0u8; usize::from_str_radix(size.trim(), 16).unwrap() + 2];
+        reader.read_exact(&mut chunk).unwrap();
+        chunk.truncate(chunk.len() - 2);
+        chunk
+    }
+
+    fn wait_for_clients(feed: &LiveFeed, clients: usize) {
+        let start = Instant::now();
+        while feed.status().clients < clients {
+            assert!(start.elapsed() < Duration::from_secs(5), "Client did not connect");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_http_server() {
+        let feed = Arc::new(LiveFeed::new("session", "/dev/ttyACM0", 48_000));
+        let address = HttpServer::start("127.0.0.1:0", Arc::clone(&feed)).expect("Failed to start server");
+
+        let mut audio = get(address, "/audio.wav");
+        let mut log = get(address, "/log");
+        wait_for_clients(&feed, 2);
+        feed.on_audio_frame(3, 4012, &[1, 0, 2, 0], Instant::now());
+        feed.on_log_line("2024-12-21 12:24:34.830 - app_init");
+
+        let header = read_chunk(&mut audio);
+        assert_eq!(&header[0..4], b"RIFF");
+        assert_eq!(read_chunk(&mut audio), [1, 0, 2, 0]);
+        assert_eq!(read_chunk(&mut log), b"data: 2024-12-21 12:24:34.830 - app_init\n\n");
+
+        let mut status = String::new();
+        get(address, "/status").read_to_string(&mut status).unwrap();
+        let status: serde_json::Value = serde_json::from_str(&status).unwrap();
+        assert_eq!(status["audio_frames"], 1);
+        assert_eq!(status["last_frame_number"], 3);
+        assert_eq!(status["clients"], 2);
+
+        let mut metrics = String::new();
+        get(address, "/metrics").read_to_string(&mut metrics).unwrap();
+        assert!(metrics.contains("pinebuds_audio_frames_total 1\n"), "{}", metrics);
+    }
+}
@@ -0,0 +1,95 @@
+use std::fmt::Write;
+use super::feed::ReceiverStatus;
+
+/// Prefix of every metric name
+const PREFIX: &str = "pinebuds";
+
+/// Numbers as Prometheus writes them, with its spelling of infinity
+fn number(value: f64) -> String {
+    match value {
+        f64::INFINITY => "+Inf".to_string(),
+        f64::NEG_INFINITY => "-Inf".to_string(),
+        value if value.is_nan() => "NaN".to_string(),
+        value => value.to_string(),
+    }
+}
+
+/// Escape a label value: backslash, double quote and newline
+fn label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Writes metrics in the Prometheus text format
+struct Exposition(String);
+
+impl Exposition {
+    fn metric(&mut self, name: &str, kind: &str, help: &str, value: f64) {
+        let name = format!("{}_{}", PREFIX, name);
+        writeln!(self.0, "# HELP {} {}\n# TYPE {} {}\n{} {}", name, help, name, kind, name, number(value))
+            .expect("Writing to a String does not fail");
+    }
+
+    fn counter(&mut self, name: &str, help: &str, value: u64) {
+        self.metric(name, "counter", help, value as f64);
+    }
+
+    fn gauge(&mut self, name: &str, help: &str, value: f64) {
+        self.metric(name, "gauge", help, value);
+    }
+}
+
+/// The receiver status as the `/metrics` page
+pub fn render(status: &ReceiverStatus) -> String {
+    let mut out = Exposition(String::new());
+    writeln!(out.0, "# HELP {0}_info Session and serial port of the receiver\n# TYPE {0}_info gauge\n{0}_info{{session=\"{1}\",port=\"{2}\"}} 1",
+        PREFIX, label(&status.session), label(&status.port)).expect("Writing to a String does not fail");
+    out.gauge("uptime_seconds", "Seconds since the receiver started", status.uptime_seconds);
+    out.counter("audio_frames_total", "Audio frames received", status.audio_frames);
+    out.gauge("audio_frames_per_second", "Audio frames received per second over the last 5 seconds", status.audio_frames_per_second);
+    out.counter("frame_gaps_total", "Jumps in the audio frame counter", status.frame_gaps);
+    out.counter("lost_frames_total", "Audio frames lost in the gaps", status.lost_frames);
+    out.counter("bad_frames_total", "Audio frames cut short, their sync bytes too close to the previous frame", status.bad_frames);
+    out.counter("log_lines_total", "Log lines received", status.log_lines);
+    out.counter("bytes_received_total", "Bytes of audio frames and log data received", status.bytes_received);
+    out.gauge("bytes_per_second", "Bytes received per second over the last 5 seconds", status.bytes_per_second);
+    out.counter("device_resets_total", "Device reboots detected", status.device_resets as u64);
+    out.counter("serial_reconnects_total", "Times the serial port was opened again after an error", status.reconnects);
+    out.gauge("clients", "Connected HTTP stream and dashboard clients", status.clients as f64);
+    if let Some(link) = &status.link {
+        out.gauge("parser_queue_bytes", "Bytes the parser had not processed yet when the last serial read arrived", link.queue_bytes as f64);
+        out.gauge("uart_utilization", "Share of the baud rate in use over the last 5 seconds", link.uart_utilization);
+        out.counter("serial_overruns_total", "Frame gaps that most likely come from the host falling behind", link.likely_overruns);
+    }
+    if let Some(levels) = &status.audio_levels {
+        out.gauge("audio_rms_dbfs", "RMS level of the last audio frame", levels.rms_dbfs);
+        out.gauge("audio_peak_dbfs", "Peak level of the last audio frame", levels.peak_dbfs);
+    }
+    out.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::analyzer::FrameAnalysis;
+
+    #[test]
+    fn test_render() {
+        let status = ReceiverStatus {
+            session: "session_\"a\"".to_string(),
+            port: "/dev/ttyACM0".to_string(),
+            audio_frames: 42,
+            lost_frames: 3,
+            audio_levels: Some(FrameAnalysis { frame_number: 41, peak_dbfs: -3.5, rms_dbfs: f64::NEG_INFINITY, dc_offset: 0.0, clipped_samples: 0, silent: true }),
+            ..Default::default()
+        };
+        let page = render(&status);
+        assert!(page.starts_with("# HELP pinebuds_info "));
+        assert!(page.contains("pinebuds_info{session=\"session_\\\"a\\\"\",port=\"/dev/ttyACM0\"} 1\n"));
+        assert!(page.contains("# TYPE pinebuds_audio_frames_total counter\npinebuds_audio_frames_total 42\n"));
+        assert!(page.contains("pinebuds_lost_frames_total 3\n"));
+        assert!(page.contains("pinebuds_audio_rms_dbfs -Inf\n"));
+        assert!(page.contains("pinebuds_audio_peak_dbfs -3.5\n"));
+        assert!(!page.contains("parser_queue_bytes"), "No link figures yet");
+        assert!(page.lines().all(|line| line.starts_with('#') || line.split(' ').count() == 2));
+    }
+}
@@ -0,0 +1,5 @@
+pub mod broadcast;
+pub mod dashboard;
+pub mod feed;
+pub mod http;
+pub mod metrics;
@@ -0,0 +1,174 @@
+use chrono::{DateTime, Duration, Local};
+use crate::constants::common;
+
+/// Audio and host timing of a frame
+pub struct FrameTiming {
+    /// Time on the device timeline at the end of the frame, counted from frame 0 of the boot
+    pub device_seconds: f64,
+    /// Host time at which the end of the frame was captured, corrected with the clock estimate
+    pub host_time: DateTime<Local>,
+}
+
+/// Reconstructs the device timeline from the frame counter and estimates how the
+/// device clock maps onto the host clock.
+///
+/// The device timeline is the frame counter times the audio duration of a frame.
+/// The host clock is fitted to it with a least squares regression over all frames
+/// of a boot, which averages out the delay between capture and receive time.
+pub struct DeviceClock {
+    sample_rate: u32,
+    host_origin: Option<DateTime<Local>>,
+    last_frame_number: Option<u64>,
+    count: f64,
+    mean_device: f64,
+    mean_host: f64,
+    /// Sums of squared device and host deviations and of device/host co-deviations
+    variance_device: f64,
+    variance_host: f64,
+    covariance: f64,
+}
+
+impl Default for DeviceClock {
+    fn default() -> Self {
+        Self::new(common::SAMPLE_RATE)
+    }
+}
+
+impl DeviceClock {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate,
+            host_origin: None,
+            last_frame_number: None,
+            count: 0.0,
+            mean_device: 0.0,
+            mean_host: 0.0,
+            variance_device: 0.0,
+            variance_host: 0.0,
+            covariance: 0.0,
+        }
+    }
+
+    /// Forget the estimate, e.g. when the device reboots and its counter restarts
+    pub fn reset(&mut self) {
+        *self = Self::new(self.sample_rate);
+    }
+
+    /// Take the frames as audio at `sample_rate` from now on; the estimate starts over, as its device times were off
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        *self = Self::new(sample_rate);
+    }
+
+    /// Add a frame received at `received` and return its timing
+    pub fn on_frame(&mut self, frame_number: u64, received: DateTime<Local>) -> FrameTiming {
+        let host_origin = *self.host_origin.get_or_insert(received);
+        let device_seconds = self.frame_end_seconds(frame_number);
+        let host_seconds = Self::seconds_between(host_origin, received);
+
+        // Online update of the regression sums
+        self.count += 1.0;
+        let device_deviation = device_seconds - self.mean_device;
+        self.mean_device += device_deviation / self.count;
+        let host_deviation = host_seconds - self.mean_host;
+        self.mean_host += host_deviation / self.count;
+        self.variance_device += device_deviation * (device_seconds - self.mean_device);
+        self.variance_host += host_deviation * (host_seconds - self.mean_host);
+        self.covariance += device_deviation * (host_seconds - self.mean_host);
+        self.last_frame_number = Some(frame_number);
+
+        FrameTiming {
+            device_seconds,
+            host_time: self.host_time(device_seconds).unwrap_or(received),
+        }
+    }
+
+    fn frame_end_seconds(&self, frame_number: u64) -> f64 {
+        (frame_number + 1) as f64 * common::frame_seconds(self.sample_rate)
+    }
+
+    fn seconds_between(from: DateTime<Local>, to: DateTime<Local>) -> f64 {
+        (to - from).num_microseconds().unwrap_or(i64::MAX) as f64 / 1e6
+    }
+
+    /// Device time at the end of the last frame, which is where log lines arriving now belong
+    pub fn device_seconds(&self) -> Option<f64> {
+        self.last_frame_number.map(|frame_number| self.frame_end_seconds(frame_number))
+    }
+
+    /// Host seconds per device second
+    fn slope(&self) -> f64 {
+        if self.count < 2.0 || self.variance_device == 0.0 {
+            1.0
+        } else {
+            self.covariance / self.variance_device
+        }
+    }
+
+    /// Corrected host time of a point on the device timeline
+    pub fn host_time(&self, device_seconds: f64) -> Option<DateTime<Local>> {
+        let host_origin = self.host_origin?;
+        let host_seconds = self.mean_host + self.slope() * (device_seconds - self.mean_device);
+        Some(host_origin + Duration::microseconds((host_seconds * 1e6).round() as i64))
+    }
+
+    /// Host time of device time 0, i.e. when the device started counting frames
+    pub fn offset(&self) -> Option<DateTime<Local>> {
+        self.host_time(0.0)
+    }
+
+    /// Standard deviation of the receive times around the fitted clock
+    pub fn jitter_seconds(&self) -> Option<f64> {
+        if self.count < 3.0 || self.variance_device == 0.0 {
+            return None;
+        }
+        let residual = self.variance_host - self.covariance * self.covariance / self.variance_device;
+        Some((residual.max(0.0) / (self.count - 2.0)).sqrt())
+    }
+
+    /// How much faster the device clock runs than the host clock, in parts per million
+    pub fn drift_ppm(&self) -> Option<f64> {
+        if self.count < 2.0 {
+            None
+        } else {
+            Some((1.0 / self.slope() - 1.0) * 1e6)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_device_clock() {
+        let mut clock = DeviceClock::new(common::SAMPLE_RATE);
+        assert!(clock.device_seconds().is_none());
+        assert!(clock.drift_ppm().is_none());
+
+        // Device runs 100 ppm fast and the counter started 10 frames before we listened.
+        // Frames are received 5-25 ms after their capture.
+        let frame_seconds = common::frame_seconds(common::SAMPLE_RATE);
+        let start = Local::now();
+        for frame_number in 10..2010u64 {
+            let captured = (frame_number + 1) as f64 * frame_seconds / (1.0 + 100e-6);
+            let delay = 0.005 + 0.020 * ((frame_number * 7919) % 100) as f64 / 100.0;
+            let received = start + Duration::microseconds(((captured + delay) * 1e6) as i64);
+            clock.on_frame(frame_number, received);
+        }
+
+        assert_eq!(clock.device_seconds(), Some(2010.0 * frame_seconds));
+        let drift = clock.drift_ppm().unwrap();
+        assert!((drift - 100.0).abs() < 5.0, "Drift should be about 100 ppm, got {}", drift);
+
+        // Device time 0 maps to the start plus the average 15 ms receive delay
+        let offset = DeviceClock::seconds_between(start, clock.offset().unwrap());
+        assert!((offset - 0.015).abs() < 0.002, "Offset should be about 15 ms, got {}", offset);
+
+        // Delays spread evenly over 20 ms have a standard deviation of 20 / sqrt(12) ms
+        let jitter = clock.jitter_seconds().unwrap();
+        assert!((jitter - 0.00577).abs() < 0.0005, "Jitter should be about 5.8 ms, got {}", jitter);
+
+        clock.reset();
+        assert!(clock.device_seconds().is_none());
+    }
+}
@@ -0,0 +1,2 @@
+pub mod device_clock;
+pub mod rate_estimator;
@@ -0,0 +1,103 @@
+use std::collections::VecDeque;
+use std::time::Instant;
+use serde::Serialize;
+use crate::constants::common;
+
+/// Sample rates audio codecs run at
+const STANDARD_SAMPLE_RATES: [u32; 9] = [8_000, 11_025, 16_000, 22_050, 24_000, 32_000, 44_100, 48_000, 96_000];
+/// Fewest frames, and shortest time between the first and last of them, an estimate is made from
+const MIN_FRAMES: usize = 4;
+const MIN_SPAN_SECONDS: f64 = 1.0;
+
+/// The standard sample rate closest to a measured one
+pub fn nearest_standard_rate(measured: f64) -> u32 {
+    let distance = |rate: u32| (measured / rate as f64).ln().abs();
+    STANDARD_SAMPLE_RATES.into_iter()
+        .min_by(|&a, &b| distance(a).total_cmp(&distance(b)))
+        .expect("There are standard rates")
+}
+
+/// Sample rate the audio frames arrive at
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct RateEstimate {
+    /// Samples per second on the host clock
+    pub measured: f64,
+    /// The standard rate closest to it
+    pub sample_rate: u32,
+}
+
+/// Estimates the sample rate of the payload from the frame counter and the receive times of
+/// the frames within a sliding window
+pub struct RateEstimator {
+    window_seconds: f64,
+    frames: VecDeque<(u64, Instant)>,
+}
+
+impl RateEstimator {
+    pub fn new(window_seconds: f64) -> Self {
+        Self { window_seconds, frames: VecDeque::new() }
+    }
+
+    pub fn push(&mut self, frame_number: u64, received: Instant) {
+        self.frames.push_back((frame_number, received));
+        while self.frames.front().is_some_and(|(_, time)| received.duration_since(*time).as_secs_f64() > self.window_seconds) {
+            self.frames.pop_front();
+        }
+    }
+
+    /// Forget the frames, e.g. when the device reboots and its counter restarts
+    pub fn reset(&mut self) {
+        self.frames.clear();
+    }
+
+    /// None until the window holds enough frames
+    pub fn estimate(&self) -> Option<RateEstimate> {
+        let (&(first_frame, first_time), &(_, last_time)) = (self.frames.front()?, self.frames.back()?);
+        if self.frames.len() < MIN_FRAMES || last_time.duration_since(first_time).as_secs_f64() < MIN_SPAN_SECONDS {
+            return None;
+        }
+        // A least squares fit of the counter against the receive time averages out the receive jitter
+        let points: Vec<(f64, f64)> = self.frames.iter()
+            .map(|(frame_number, time)| (time.duration_since(first_time).as_secs_f64(), (frame_number - first_frame) as f64))
+            .collect();
+        let count = points.len() as f64;
+        let mean_time = points.iter().map(|(time, _)| time).sum::<f64>() / count;
+        let mean_frame = points.iter().map(|(_, frame)| frame).sum::<f64>() / count;
+        let covariance: f64 = points.iter().map(|(time, frame)| (time - mean_time) * (frame - mean_frame)).sum();
+        let variance: f64 = points.iter().map(|(time, _)| (time - mean_time).powi(2)).sum();
+        let samples_per_frame = common::AUDIO_PAYLOAD_LENGTH as f64 / (common::CHANNELS * common::BITS_PER_SAMPLE / 8) as f64;
+        let measured = covariance / variance * samples_per_frame;
+        Some(RateEstimate { measured, sample_rate: nearest_standard_rate(measured) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_rate_estimator() {
+        assert_eq!(nearest_standard_rate(48_004.8), 48_000);
+        assert_eq!(nearest_standard_rate(15_870.0), 16_000);
+        assert_eq!(nearest_standard_rate(44_500.0), 44_100);
+
+        let mut estimator = RateEstimator::new(10.0);
+        let start = Instant::now();
+        // 16 kHz frames last 125 ms, received 5-25 ms late, and frame 30 is lost
+        for frame_number in (0..200u64).filter(|&frame_number| frame_number != 30) {
+            let delay = 0.005 + 0.020 * ((frame_number * 7919) % 100) as f64 / 100.0;
+            estimator.push(frame_number, start + Duration::from_secs_f64(frame_number as f64 * 0.125 + delay));
+            if frame_number == 5 {
+                assert!(estimator.estimate().is_none(), "Not enough frames yet");
+            }
+        }
+        assert!((79..=81).contains(&estimator.frames.len()), "The window keeps the last 10 seconds, {} frames", estimator.frames.len());
+        let estimate = estimator.estimate().unwrap();
+        assert!((estimate.measured - 16_000.0).abs() < 20.0, "Measured {}", estimate.measured);
+        assert_eq!(estimate.sample_rate, 16_000);
+
+        estimator.reset();
+        assert!(estimator.estimate().is_none());
+    }
+}
@@ -0,0 +1,233 @@
+use std::io;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Style, Stylize};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Gauge, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+use crate::analysis::levels::{self, ChannelLevels};
+use crate::constants::common;
+use crate::receiver::handler::FrameHandler;
+use crate::server::feed::{LiveFeed, ReceiverStatus};
+use super::log_pane::LogPane;
+
+/// Time between screen updates
+const REFRESH: Duration = Duration::from_millis(50);
+/// Lowest level on the meters
+const METER_FLOOR_DBFS: f64 = -60.0;
+/// Peaks above this are drawn red
+const CLIP_WARNING_DBFS: f64 = -1.0;
+/// Serial line bits per byte: start bit, 8 data bits and a stop bit
+const BITS_PER_BYTE: f64 = 10.0;
+
+/// State of the full-screen terminal UI
+struct App {
+    handler: Arc<Mutex<FrameHandler>>,
+    feed: Arc<LiveFeed>,
+    audio: Receiver<Arc<Vec<u8>>>,
+    lines: Receiver<String>,
+    baudrate: u32,
+    log: LogPane,
+    /// Highest levels of the frames that arrived since the last screen update
+    levels: Vec<ChannelLevels>,
+    /// Search text being typed after `/`
+    search_input: Option<String>,
+    search_error: Option<String>,
+    markers: u32,
+}
+
+/// Run the terminal UI on its own thread; quitting it ends the receiver
+pub fn spawn(handler: Arc<Mutex<FrameHandler>>, lines: Receiver<String>, baudrate: u32) {
+    let feed = handler.lock().expect("Failed to lock frame handler mutex")
+        .feed().expect("The terminal UI needs the live feed");
+    let audio = feed.audio.subscribe();
+    let mut app = App {
+        handler,
+        feed,
+        audio,
+        lines,
+        baudrate,
+        log: LogPane::new(),
+        levels: vec![ChannelLevels::default(); common::CHANNELS as usize],
+        search_input: None,
+        search_error: None,
+        markers: 0,
+    };
+    thread::spawn(move || {
+        let result = ratatui::try_init().and_then(|terminal| app.run(terminal));
+        ratatui::restore();
+        // Lines printed while finishing go to the restored terminal
+        app.lines = mpsc::channel().1;
+        app.handler().finish();
+        if let Err(e) = result {
+            eprintln!("Terminal UI failed: {}", e);
+            std::process::exit(1);
+        }
+        std::process::exit(0);
+    });
+}
+
+impl App {
+    fn handler(&self) -> std::sync::MutexGuard<'_, FrameHandler> {
+        self.handler.lock().expect("Failed to lock frame handler mutex")
+    }
+
+    fn run(&mut self, mut terminal: DefaultTerminal) -> io::Result<()> {
+        loop {
+            for line in self.lines.try_iter() {
+                self.log.push(line);
+            }
+            let frames: Vec<_> = self.audio.try_iter().collect();
+            if !frames.is_empty() {
+                self.levels.fill(ChannelLevels::default());
+            }
+            for payload in frames {
+                for (level, frame_level) in self.levels.iter_mut().zip(levels::channel_levels(&payload, common::CHANNELS)) {
+                    level.peak = level.peak.max(frame_level.peak);
+                    level.rms = level.rms.max(frame_level.rms);
+                }
+            }
+            let status = self.feed.status();
+            let recording = self.handler().is_recording();
+            terminal.draw(|frame| self.draw(frame, &status, recording))?;
+
+            if event::poll(REFRESH)? {
+                if let Event::Key(key) = event::read()? {
+                    // Raw mode turns Ctrl-C into a key press instead of SIGINT, so it quits like `q`
+                    let interrupt = key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+                    if key.kind == KeyEventKind::Press && (interrupt || !self.on_key(key.code)) {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Handle a key press; false quits
+    fn on_key(&mut self, key: KeyCode) -> bool {
+        if let Some(input) = &mut self.search_input {
+            match key {
+                KeyCode::Enter => self.search_input = None,
+                KeyCode::Esc => {
+                    self.search_input = None;
+                    self.search_error = None;
+                    let _ = self.log.set_search("");
+                },
+                KeyCode::Backspace => { input.pop(); },
+                KeyCode::Char(c) => input.push(c),
+                _ => {},
+            }
+            // Search as you type
+            if let Some(input) = &self.search_input {
+                self.search_error = self.log.set_search(input).err();
+            }
+            return true;
+        }
+        match key {
+            KeyCode::Char('q') => return false,
+            KeyCode::Char('r') => self.handler().toggle_recording(),
+            KeyCode::Char('m') => {
+                self.markers += 1;
+                let label = format!("Marker {}", self.markers);
+                self.handler().mark(&label);
+            },
+            KeyCode::Char('/') => self.search_input = Some(String::new()),
+            KeyCode::Esc => {
+                self.search_error = None;
+                let _ = self.log.set_search("");
+            },
+            KeyCode::Up => self.log.scroll_up(1),
+            KeyCode::Down => self.log.scroll_down(1),
+            KeyCode::PageUp => self.log.scroll_up(20),
+            KeyCode::PageDown => self.log.scroll_down(20),
+            KeyCode::End => self.log.follow(),
+            _ => {},
+        }
+        true
+    }
+
+    fn draw(&self, frame: &mut Frame, status: &ReceiverStatus, recording: bool) {
+        let meter_rows = 2 * self.levels.len() as u16;
+        let [meters, statistics, throughput, log, help] = Layout::vertical([
+            Constraint::Length(meter_rows + 2),
+            Constraint::Length(6),
+            Constraint::Length(3),
+            Constraint::Min(3),
+            Constraint::Length(1),
+        ]).areas(frame.area());
+
+        self.draw_meters(frame, meters);
+        self.draw_statistics(frame, statistics, status, recording);
+
+        let budget = self.baudrate as f64 / BITS_PER_BYTE;
+        let ratio = status.bytes_per_second / budget;
+        let link = status.link.as_ref().map_or(String::new(), |link| format!(", reads {:.0} B, blocked {:.0}%, queue {:.1} kB, overruns {}",
+            link.mean_read_bytes, link.blocked_ratio * 100.0, link.queue_bytes as f64 / 1000.0, link.likely_overruns));
+        frame.render_widget(Gauge::default()
+            .block(Block::bordered().title(format!(" Serial throughput, {} baud ", self.baudrate)))
+            .gauge_style(Style::new().fg(if ratio > 0.9 { Color::Red } else { Color::Cyan }))
+            .ratio(ratio.clamp(0.0, 1.0))
+            .label(format!("{:.1} kB/s of {:.1} kB/s, {:.0}%{}", status.bytes_per_second / 1000.0, budget / 1000.0, ratio * 100.0, link)),
+            throughput);
+
+        let title = match (&self.search_input, &self.search_error) {
+            (_, Some(error)) => format!(" Log: {} ", error),
+            (Some(input), None) => format!(" Log, search: {}_ ", input),
+            (None, None) if self.log.is_following() => " Log ".to_string(),
+            (None, None) => " Log (scrolled, End follows) ".to_string(),
+        };
+        let lines: Vec<Line> = self.log.visible(log.height.saturating_sub(2) as usize).into_iter().map(Line::from).collect();
+        frame.render_widget(Paragraph::new(lines).block(Block::bordered().title(title)), log);
+
+        frame.render_widget(Line::from(
+            " q quit  r start/stop recording  m marker  / search  Esc clear search  Up/Down/PgUp/PgDn scroll  End follow").dark_gray(),
+            help);
+    }
+
+    fn draw_meters(&self, frame: &mut Frame, area: Rect) {
+        let block = Block::bordered().title(" Levels ");
+        let inner = block.inner(area);
+        frame.render_widget(block, area);
+        let ratio = |dbfs: f64| ((dbfs - METER_FLOOR_DBFS) / -METER_FLOOR_DBFS).clamp(0.0, 1.0);
+        for (channel, level) in self.levels.iter().enumerate() {
+            let row = |offset: u16| Rect { y: inner.y + 2 * channel as u16 + offset, height: 1, ..inner };
+            let peak_color = if level.peak_dbfs() > CLIP_WARNING_DBFS { Color::Red } else { Color::Yellow };
+            frame.render_widget(Gauge::default()
+                .gauge_style(Style::new().fg(peak_color))
+                .ratio(ratio(level.peak_dbfs()))
+                .label(format!("ch{} peak {:.1} dBFS", channel + 1, level.peak_dbfs())),
+                row(0));
+            frame.render_widget(Gauge::default()
+                .gauge_style(Style::new().fg(Color::Green))
+                .ratio(ratio(level.rms_dbfs()))
+                .label(format!("ch{} rms {:.1} dBFS", channel + 1, level.rms_dbfs())),
+                row(1));
+        }
+    }
+
+    fn draw_statistics(&self, frame: &mut Frame, area: Rect, status: &ReceiverStatus, recording: bool) {
+        let recording = if recording { "RECORDING".red().bold() } else { "armed".yellow() };
+        let last_frame = status.last_frame_number.map_or("-".to_string(), |frame_number| frame_number.to_string());
+        let sample_rate = status.measured_sample_rate.map_or("-".to_string(), |estimate| format!("{:.0} Hz", estimate.measured));
+        let lines = vec![
+            Line::from(vec![
+                format!("{} on {}, segment {}, up {:.0} s  ", status.session, status.port, status.segment, status.uptime_seconds).into(),
+                recording,
+            ]),
+            Line::from(format!("Frames {} at {:.1}/s, {}, last #{}, device resets {}, reconnects {}",
+                status.audio_frames, status.audio_frames_per_second, sample_rate, last_frame, status.device_resets, status.reconnects)),
+            Line::from(format!("Gaps {}, lost frames {}, bad frames {}, log lines {}",
+                status.frame_gaps, status.lost_frames, status.bad_frames, status.log_lines)),
+            Line::from(match &status.tone {
+                Some(tone) => format!("Tone {:.1} Hz at {:.1} dBFS, THD {:.2}%, SNR {:.1} dB",
+                    tone.frequency, tone.level_dbfs, tone.thd_percent, tone.snr_db),
+                None => "Tone -".to_string(),
+            }),
+        ];
+        frame.render_widget(Paragraph::new(lines).block(Block::bordered().title(" Receiver ")), area);
+    }
+}
@@ -0,0 +1,100 @@
+use std::collections::VecDeque;
+use crate::logs::pattern::Pattern;
+
+/// Lines kept for scrolling back
+const MAX_LINES: usize = 10_000;
+
+/// Scrollback of the log pane, filtered by a search pattern
+pub struct LogPane {
+    lines: VecDeque<String>,
+    /// Matching lines scrolled up from the newest; 0 follows new lines
+    scroll: usize,
+    search: Option<Pattern>,
+}
+
+impl LogPane {
+    pub fn new() -> Self {
+        Self { lines: VecDeque::new(), scroll: 0, search: None }
+    }
+
+    fn matches(&self, line: &str) -> bool {
+        self.search.as_ref().is_none_or(|pattern| pattern.is_match(line))
+    }
+
+    pub fn push(&mut self, line: String) {
+        // Keep a scrolled-up view where it is
+        if self.scroll > 0 && self.matches(&line) {
+            self.scroll += 1;
+        }
+        self.lines.push_back(line);
+        if self.lines.len() > MAX_LINES {
+            self.lines.pop_front();
+        }
+    }
+
+    /// Show only the lines matching `search`, a substring or `/regex/`; an empty search shows everything
+    pub fn set_search(&mut self, search: &str) -> Result<(), String> {
+        self.search = None;
+        self.scroll = 0;
+        if !search.is_empty() {
+            self.search = Some(Pattern::parse(search)?);
+        }
+        Ok(())
+    }
+
+    pub fn scroll_up(&mut self, lines: usize) {
+        let matching = self.lines.iter().filter(|line| self.matches(line)).count();
+        self.scroll = (self.scroll + lines).min(matching.saturating_sub(1));
+    }
+
+    pub fn scroll_down(&mut self, lines: usize) {
+        self.scroll = self.scroll.saturating_sub(lines);
+    }
+
+    pub fn follow(&mut self) {
+        self.scroll = 0;
+    }
+
+    pub fn is_following(&self) -> bool {
+        self.scroll == 0
+    }
+
+    /// The matching lines that fit `height` rows, oldest first
+    pub fn visible(&self, height: usize) -> Vec<&str> {
+        let mut visible: Vec<&str> = self.lines.iter().rev()
+            .filter(|line| self.matches(line))
+            .skip(self.scroll)
+            .take(height)
+            .map(String::as_str)
+            .collect();
+        visible.reverse();
+        visible
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_pane() {
+        let mut pane = LogPane::new();
+        for i in 0..10 {
+            pane.push(format!("line {}", i));
+        }
+        assert_eq!(pane.visible(2), ["line 8", "line 9"], "Follows the newest lines");
+
+        pane.scroll_up(3);
+        assert_eq!(pane.visible(2), ["line 5", "line 6"]);
+        pane.push("line 10".to_string());
+        assert_eq!(pane.visible(2), ["line 5", "line 6"], "Scrolled view stays put");
+        pane.scroll_up(100);
+        assert_eq!(pane.visible(2), ["line 0"], "Scrolls up to the oldest line");
+
+        pane.set_search("/line [37]$/").unwrap();
+        assert!(pane.is_following(), "Search starts at the newest lines");
+        assert_eq!(pane.visible(5), ["line 3", "line 7"]);
+        assert!(pane.set_search("/[/").is_err());
+        assert_eq!(pane.visible(1), ["line 10"], "Invalid search shows everything");
+    }
+}
@@ -1 +1 @@
-pub mod test_utils;
\ No newline at end of file
+pub mod test_utils;